@@ -7,17 +7,21 @@ use core::ptr::{self, NonNull};
 use linked_list_allocator::Heap;
 use spin::Mutex;
 
+use arch::x86_64::interrupts::without_interrupts;
+
 /// Base location of the kheap.
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
 /// Size of the kheap.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
 /// Locked ownership of an optional kheap. Initialized on boot; is `None` before then.
+///
+/// Deferred work allocates too, so this is only ever locked with interrupts off.
 static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 
 /// Initialize the kheap. Called at boot.
 pub unsafe fn heap_init(start: usize, size: usize) {
-    *HEAP.lock() = Some(Heap::new(start, size));
+    without_interrupts(|| *HEAP.lock() = Some(Heap::new(start, size)));
 }
 
 /// Wraps whatever allocator backend we're using, and implements `alloc::allocator::Alloc`.
@@ -25,43 +29,52 @@ pub struct Allocator;
 
 unsafe impl<'a> Alloc for &'a Allocator {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.allocate_first_fit(layout)
-        } else {
-            panic!("kheap: attempting alloc w/ uninitialized heap");
-        }
+        without_interrupts(|| {
+            if let Some(ref mut heap) = *HEAP.lock() {
+                heap.allocate_first_fit(layout)
+            } else {
+                panic!("kheap: attempting alloc w/ uninitialized heap");
+            }
+        })
     }
 
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.deallocate(ptr, layout)
-        } else {
-            panic!("kheap: attempting dealloc w/ uninitialized heap");
-        }
+        without_interrupts(|| {
+            if let Some(ref mut heap) = *HEAP.lock() {
+                heap.deallocate(ptr, layout)
+            } else {
+                panic!("kheap: attempting dealloc w/ uninitialized heap");
+            }
+        })
     }
 }
 
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.allocate_first_fit(layout)
-                .ok()
-                .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
-        } else {
-            panic!("kheap: attempting alloc w/ uninitialized heap");
-        }
+        without_interrupts(|| {
+            if let Some(ref mut heap) = *HEAP.lock() {
+                heap.allocate_first_fit(layout)
+                    .ok()
+                    .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+            } else {
+                panic!("kheap: attempting alloc w/ uninitialized heap");
+            }
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.deallocate(NonNull::new_unchecked(ptr), layout)
-        } else {
-            panic!("kheap: attempting dealloc w/ uninitialized heap");
-        }
+        without_interrupts(|| {
+            if let Some(ref mut heap) = *HEAP.lock() {
+                heap.deallocate(NonNull::new_unchecked(ptr), layout)
+            } else {
+                panic!("kheap: attempting dealloc w/ uninitialized heap");
+            }
+        })
     }
 }
 
 /// OOM message
+#[cfg(not(test))]
 #[lang = "oom"]
 #[no_mangle]
 pub extern "C" fn oom(_: Layout) -> ! {
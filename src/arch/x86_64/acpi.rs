@@ -0,0 +1,205 @@
+//! Just enough ACPI to find firmware tables: locates the RSDP, and walks the RSDT/XSDT.
+//!
+//! Tables are identity-mapped (read-only) as they're found, so the references handed out here
+//! stay valid forever.
+
+use alloc::vec::Vec;
+use core::{mem, slice, str};
+use spin::Once;
+
+use arch::x86_64::memory::paging::table::EntryFlags;
+use arch::x86_64::memory::paging::FrameAllocator;
+use arch::x86_64::memory::MemoryController;
+
+/// Where the BIOS keeps the RSDP (if it's not in the EBDA).
+const BIOS_AREA_START: usize = 0xe0000;
+const BIOS_AREA_END: usize = 0x100000;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The longest table we'll map in. Anything longer is taken for a corrupt header, rather than
+/// have us map (and checksum) whatever garbage length it claims.
+const MAX_TABLE_LEN: usize = 1 << 20;
+
+/// Every table we found, in the order the RSDT/XSDT lists them.
+static TABLES: Once<Vec<&'static SdtHeader>> = Once::new();
+
+/// Root System Description Pointer, as of ACPI 2.0.
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // fields below here are only present if `revision >= 2`
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every System Description Table starts with.
+#[allow(dead_code)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// The table's four-character signature (*e.g.* `HPET`).
+    pub fn signature(&self) -> &str {
+        str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    /// Length of the table, including this header, in bytes.
+    pub fn length(&self) -> usize {
+        self.length as usize
+    }
+
+    /// The table's revision.
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// The table contents following the header.
+    pub fn data(&self) -> &[u8] {
+        let header_len = mem::size_of::<SdtHeader>();
+        let start = self as *const _ as usize + header_len;
+
+        // tables shorter than their header are never handed out, but don't trust that
+        let len = self.length().saturating_sub(header_len);
+        unsafe { slice::from_raw_parts(start as *const u8, len) }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.length()) }
+    }
+}
+
+/// Does `bytes` sum to zero (mod 256), as every ACPI structure should?
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Scans the BIOS ROM area for a valid RSDP.
+///
+/// # Notes
+/// We don't look in the EBDA: finding it would mean mapping page 0.
+fn find_rsdp() -> Option<&'static Rsdp> {
+    (BIOS_AREA_START..BIOS_AREA_END - mem::size_of::<Rsdp>())
+        .step_by(16) // the RSDP is always 16-byte aligned
+        .map(|addr| unsafe { &*(addr as *const Rsdp) })
+        .find(|rsdp| {
+            let v1_len = 20; // everything up to (and including) `rsdt_address`
+            let bytes = unsafe { slice::from_raw_parts(*rsdp as *const _ as *const u8, v1_len) };
+
+            &rsdp.signature == RSDP_SIGNATURE && checksum_ok(bytes)
+        })
+}
+
+/// Maps the table at `phys_addr` in, and returns it if its length is plausible and its checksum
+/// is good.
+fn map_table<A: FrameAllocator>(
+    memory_controller: &mut MemoryController<A>,
+    phys_addr: usize,
+) -> Option<&'static SdtHeader> {
+    let flags = EntryFlags::NO_EXECUTE;
+    let header_len = mem::size_of::<SdtHeader>();
+    memory_controller.identity_map_range(phys_addr, header_len, flags);
+
+    let header = unsafe { &*(phys_addr as *const SdtHeader) };
+    if header.length() < header_len || header.length() > MAX_TABLE_LEN {
+        warn!(
+            "acpi: {} table at {:#x} has a bad length ({}); ignoring it",
+            header.signature(),
+            phys_addr,
+            header.length()
+        );
+        return None;
+    }
+    memory_controller.identity_map_range(phys_addr, header.length(), flags);
+
+    if checksum_ok(header.bytes()) {
+        Some(header)
+    } else {
+        warn!(
+            "acpi: {} table at {:#x} has a bad checksum; ignoring it",
+            header.signature(),
+            phys_addr
+        );
+        None
+    }
+}
+
+/// Finds the RSDP, and maps in every table the RSDT (or XSDT) points to.
+///
+/// Returns `false` if there's no ACPI support to be found.
+pub fn init<A: FrameAllocator>(memory_controller: &mut MemoryController<A>) -> bool {
+    assert_first_call!("acpi::init() can only be called once!");
+
+    memory_controller.identity_map_range(
+        BIOS_AREA_START,
+        BIOS_AREA_END - BIOS_AREA_START,
+        EntryFlags::NO_EXECUTE,
+    );
+
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            warn!("acpi: no RSDP found");
+            return false;
+        }
+    };
+
+    // ACPI 2.0+ has a 64-bit XSDT; prefer it over the RSDT
+    let (root_addr, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as usize, 8)
+    } else {
+        (rsdp.rsdt_address as usize, 4)
+    };
+
+    let root = match map_table(memory_controller, root_addr) {
+        Some(root) => root,
+        None => return false,
+    };
+    info!(
+        "acpi: revision {}, {} at {:#x}",
+        rsdp.revision,
+        root.signature(),
+        root_addr
+    );
+
+    let tables = root
+        .data()
+        .chunks(entry_size)
+        .map(|entry| {
+            entry
+                .iter()
+                .rev()
+                .fold(0usize, |addr, b| (addr << 8) | *b as usize)
+        })
+        .filter_map(|addr| map_table(memory_controller, addr))
+        .inspect(|table| debug!("acpi: found {} table", table.signature()))
+        .collect();
+
+    TABLES.call_once(|| tables);
+    true
+}
+
+/// Finds the first table with the given `signature`.
+pub fn find_table(signature: &str) -> Option<&'static SdtHeader> {
+    TABLES
+        .try()
+        .and_then(|tables| tables.iter().find(|t| t.signature() == signature))
+        .map(|t| *t)
+}
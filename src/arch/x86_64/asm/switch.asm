@@ -0,0 +1,37 @@
+; Kernel context switching (see context.rs).
+;
+; A switched-out context is just its stack pointer: the callee-saved registers are pushed onto
+; its stack, and the return address is already there. Everything else is caller-saved, so the
+; compiler has already dealt with it.
+global switch_context
+global thread_trampoline
+
+section .text
+bits 64
+; switch_context(old: *mut Context, new: *const Context)
+switch_context:
+	push rbp
+	push rbx
+	push r12
+	push r13
+	push r14
+	push r15
+
+	mov [rdi], rsp          ; save the old context
+	mov rsp, [rsi]          ; and load the new one
+
+	pop r15
+	pop r14
+	pop r13
+	pop r12
+	pop rbx
+	pop rbp
+	ret
+
+; Where a new context first "returns" to, from `switch_context`. `Context::new` leaves the
+; function to call in r12, and its arguments in r13 and r14.
+thread_trampoline:
+	mov rdi, r13
+	mov rsi, r14
+	call r12
+	ud2                     ; the function mustn't return
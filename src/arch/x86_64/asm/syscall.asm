@@ -0,0 +1,49 @@
+; System call entry point, installed in LSTAR.
+;
+; `syscall` leaves the user's rip in rcx and rflags in r11, loads the kernel's cs/ss from STAR,
+; and masks rflags with SFMASK (so interrupts are off). Everything else, including rsp, is still
+; the user's.
+;
+; gs only points at the `CpuLocal` for the few instructions it takes to switch stacks: the call
+; may block and be resumed on another CPU, or a thread may go back to user mode through `iretq`
+; rather than here, so the swapped state can't be left to last until `sysret`.
+global syscall_entry
+extern syscall_dispatch
+
+section .text
+bits 64
+syscall_entry:
+	swapgs                  ; gs now points at this CPU's `CpuLocal`
+	mov [gs:8], rsp         ; stash the user's stack pointer
+	mov rsp, [gs:0]         ; and switch to the current thread's kernel stack
+
+	; build a `SyscallFrame` (see syscall.rs), last field first
+	push qword [gs:8]       ; user rsp
+	swapgs                  ; and back to the user's gs
+	push r11                ; user rflags
+	push rcx                ; user rip
+	push r9
+	push r8
+	push r10
+	push rdx
+	push rsi
+	push rdi
+	push rax                ; syscall number; overwritten with the result
+
+	mov rdi, rsp            ; &mut SyscallFrame
+	call syscall_dispatch
+
+	; restore the argument registers too, so only rax, rcx and r11 are clobbered
+	pop rax
+	pop rdi
+	pop rsi
+	pop rdx
+	pop r10
+	pop r8
+	pop r9
+	pop rcx
+	pop r11
+	pop rsp                 ; back onto the user stack
+
+	; rcx is canonical (syscall_dispatch checks), so this can't fault with the user's rsp loaded
+	o64 sysret
@@ -0,0 +1,52 @@
+; Dropping to user mode, and copying to and from user memory (see usermode.rs).
+global enter_user
+global copy_user
+global copy_user_access
+global copy_user_fixup
+
+section .text
+bits 64
+; `iretq` pops rip, cs, rflags, rsp and ss, in that order, so we build that frame on the kernel
+; stack and return through it. The user starts with interrupts on, and with every register but
+; its argument cleared, so nothing of the kernel's leaks out.
+;
+; enter_user(rip: usize, rsp: usize, arg: usize, cs: u64, ss: u64) -> !
+enter_user:
+	push r8                 ; ss
+	push rsi                ; rsp
+	push qword 0x202        ; rflags: IF, plus the always-set bit 1
+	push rcx                ; cs
+	push rdi                ; rip
+
+	mov rdi, rdx            ; the user's first argument
+	xor eax, eax
+	xor ebx, ebx
+	xor ecx, ecx
+	xor edx, edx
+	xor esi, esi
+	xor ebp, ebp
+	xor r8d, r8d
+	xor r9d, r9d
+	xor r10d, r10d
+	xor r11d, r11d
+	xor r12d, r12d
+	xor r13d, r13d
+	xor r14d, r14d
+	xor r15d, r15d
+
+	iretq
+
+; Copies `len` bytes from `src` to `dst`, one of which is user memory. If the user memory isn't
+; all mapped, the page fault handler resumes at `copy_user_fixup` instead of killing the kernel.
+; Returns how many bytes weren't copied.
+;
+; copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize
+copy_user:
+	mov rcx, rdx
+copy_user_access:
+	rep movsb               ; the only instruction which may fault
+	xor eax, eax
+	ret
+copy_user_fixup:
+	mov rax, rcx            ; what's left
+	ret
@@ -18,3 +18,22 @@ pub fn enable_wrprot() {
         Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
     }
 }
+
+/// Turn on the `syscall`/`sysret` instructions.
+pub fn enable_syscall() {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+}
+
+/// Turn on machine-check exceptions.
+pub fn enable_mce() {
+    let mut cr4: u64;
+    unsafe {
+        asm!("mov %cr4, $0" : "=r"(cr4) ::: "volatile");
+        cr4 |= 1 << 6; // CR4.MCE
+        asm!("mov $0, %cr4" :: "r"(cr4) : "memory" : "volatile");
+    }
+}
@@ -0,0 +1,64 @@
+//! Saved kernel execution contexts, and switching between them.
+//!
+//! A context is switched out by `switch_context` (in `asm/switch.asm`), which pushes the
+//! callee-saved registers onto the current stack and records the stack pointer; switching back
+//! in pops them again and returns to wherever the switch was called from.
+
+extern "C" {
+    fn switch_context(old: *mut Context, new: *const Context);
+    fn thread_trampoline();
+}
+
+/// How many registers `switch_context` saves on the stack.
+const SAVED_REGS: usize = 6;
+
+/// A saved kernel context. Everything but the stack pointer lives on the context's own stack.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Context {
+    rsp: usize,
+}
+
+impl Context {
+    /// A context to be filled in when the running flow of control is first switched away from.
+    pub const fn empty() -> Context {
+        Context { rsp: 0 }
+    }
+
+    /// A context which, when switched to, calls `func(arg0, arg1)` on the stack whose top is
+    /// `stack_top`.
+    ///
+    /// # Safety
+    /// The stack must be mapped, writable, and not used for anything else.
+    pub unsafe fn new(
+        stack_top: usize,
+        func: extern "C" fn(usize, usize) -> !,
+        arg0: usize,
+        arg1: usize,
+    ) -> Context {
+        // `thread_trampoline` calls `func` with the stack 16-byte aligned, as the ABI wants
+        let ret_addr = (stack_top & !0xf) - 24;
+        let rsp = ret_addr - SAVED_REGS * 8;
+
+        let frame = rsp as *mut usize;
+        // r15, r14, r13, r12, rbx, rbp; see `switch_context`
+        let regs = [0, arg1, arg0, func as usize, 0, 0];
+        for (i, &reg) in regs.iter().enumerate() {
+            frame.add(i).write(reg);
+        }
+        (ret_addr as *mut usize).write(thread_trampoline as usize);
+
+        Context { rsp }
+    }
+}
+
+/// Saves the current context into `old`, and resumes `new`. Returns when something switches back
+/// to `old`.
+///
+/// # Safety
+/// `new` must be a context made by [Context::new], or saved by an earlier switch (and not resumed
+/// since). Both must stay put until the switch is done. Should be called with interrupts
+/// disabled, so that nothing runs half-way through.
+pub unsafe fn switch(old: *mut Context, new: *const Context) {
+    switch_context(old, new);
+}
@@ -0,0 +1,195 @@
+//! Driver for the local APIC (Advanced Programmable Interrupt Controller), in xAPIC mode.
+//!
+//! Every CPU has its own local APIC, mapped at the same physical address; accesses from a CPU
+//! always reach that CPU's APIC.
+
+pub mod timer;
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use raw_cpuid::CpuId;
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+
+use arch::x86_64::interrupts::{INT_LAPIC_ERROR, INT_LAPIC_SPURIOUS};
+use arch::x86_64::memory::paging::table::EntryFlags;
+use arch::x86_64::memory::paging::{Frame, FrameAllocator};
+use arch::x86_64::memory::MemoryController;
+use arch::x86_64::MAX_CPUS;
+
+/// The local APIC. Initialized by [init]; is empty before then.
+pub static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+/// How many APIC ids there can be, in xAPIC mode.
+const APIC_IDS: usize = 256;
+
+lazy_static! {
+    /// The CPU index each APIC id was given when its CPU started, plus one; 0 if it hasn't.
+    /// Atomics rather than a lock, as anything (an NMI, say) may want the current CPU's.
+    static ref CPU_INDICES: Vec<AtomicU8> = (0..APIC_IDS).map(|_| AtomicU8::new(0)).collect();
+}
+/// How many CPUs have been given indices.
+static CPUS_STARTED: AtomicUsize = AtomicUsize::new(0);
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// register offsets
+const REG_ID: usize = 0x20;
+const REG_VERSION: usize = 0x30;
+const REG_TPR: usize = 0x80;
+const REG_EOI: usize = 0xb0;
+const REG_SVR: usize = 0xf0;
+const REG_ESR: usize = 0x280;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_LVT_PERF: usize = 0x340;
+const REG_LVT_ERROR: usize = 0x370;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// A memory-mapped local APIC.
+pub struct LocalApic {
+    base: usize,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, reg: usize) -> u32 {
+        ptr::read_volatile((self.base + reg) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: usize, value: u32) {
+        ptr::write_volatile((self.base + reg) as *mut u32, value)
+    }
+
+    /// The APIC id of the current CPU.
+    pub fn id(&self) -> u32 {
+        unsafe { self.read(REG_ID) >> 24 }
+    }
+
+    /// The APIC's version number.
+    pub fn version(&self) -> u32 {
+        unsafe { self.read(REG_VERSION) & 0xff }
+    }
+
+    /// Send an End Of Interrupt message to this APIC.
+    pub fn eoi(&self) {
+        unsafe { self.write(REG_EOI, 0) }
+    }
+
+    /// Software-enables this CPU's APIC, and points its error and spurious interrupts at our
+    /// vectors.
+    unsafe fn enable(&self) {
+        // accept interrupts of every priority class
+        self.write(REG_TPR, 0);
+
+        self.write(REG_LVT_ERROR, INT_LAPIC_ERROR as u32);
+        // the ESR has to be written before it's read, which also clears it
+        self.write(REG_ESR, 0);
+
+        self.write(REG_SVR, SVR_ENABLE | INT_LAPIC_SPURIOUS as u32);
+    }
+
+    /// Delivers performance-counter overflows to this CPU as NMIs.
+    ///
+    /// The CPU masks the entry each time it fires, so this has to be called again to re-arm it.
+    pub fn set_perf_counter_nmi(&self) {
+        unsafe { self.write(REG_LVT_PERF, LVT_DELIVERY_NMI) }
+    }
+
+    /// Reads (and clears) the error status register.
+    pub fn error_status(&self) -> u32 {
+        unsafe {
+            self.write(REG_ESR, 0);
+            self.read(REG_ESR)
+        }
+    }
+}
+
+/// Returns the local APIC.
+///
+/// # Panics
+/// If called before [init].
+pub fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC
+        .try()
+        .expect("apic: local APIC used before apic::init()")
+}
+
+/// Gives the CPU with APIC id `apic_id` the next free CPU index, and returns it. APIC ids needn't
+/// be dense, or small, so they can't index per-CPU state themselves.
+///
+/// # Panics
+/// If more than [MAX_CPUS] CPUs start, or the same one starts twice.
+pub fn register_cpu(apic_id: u32) -> usize {
+    let slot = &CPU_INDICES[apic_id as usize];
+    assert!(
+        slot.load(Ordering::SeqCst) == 0,
+        "apic: CPU with APIC id {} started twice",
+        apic_id
+    );
+
+    let index = CPUS_STARTED.fetch_add(1, Ordering::SeqCst);
+    assert!(
+        index < MAX_CPUS,
+        "apic: too many CPUs; APIC id {} would be CPU {}, but there can only be {}",
+        apic_id,
+        index,
+        MAX_CPUS
+    );
+
+    slot.store(index as u8 + 1, Ordering::SeqCst);
+    index
+}
+
+/// The CPU index the CPU with APIC id `apic_id` was given by [register_cpu], if it's started.
+pub fn cpu_index(apic_id: u32) -> Option<usize> {
+    match CPU_INDICES[apic_id as usize].load(Ordering::Relaxed) {
+        0 => None,
+        index => Some(index as usize - 1),
+    }
+}
+
+/// Does this CPU have a local APIC?
+pub fn is_present() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .map_or(false, |f| f.has_apic())
+}
+
+/// Maps the local APIC's registers, and enables the boot CPU's APIC.
+pub fn init<A: FrameAllocator>(memory_controller: &mut MemoryController<A>) {
+    assert_first_call!("apic::init() can only be called once!");
+
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { base_msr.read() };
+    let phys_addr = (base & APIC_BASE_ADDR_MASK) as usize;
+
+    memory_controller.identity_map_range(
+        phys_addr,
+        Frame::SIZE,
+        EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE,
+    );
+
+    let lapic = LOCAL_APIC.call_once(|| LocalApic { base: phys_addr });
+    // the boot CPU's been CPU 0 all along
+    let index = register_cpu(lapic.id());
+    assert_eq!(index, 0, "apic: boot CPU isn't the first to start");
+    unsafe {
+        base_msr.write(base | APIC_BASE_ENABLE);
+        lapic.enable();
+    }
+
+    info!(
+        "apic: local APIC {} (version {:#x}) at {:#x}",
+        lapic.id(),
+        lapic.version(),
+        phys_addr
+    );
+}
@@ -0,0 +1,190 @@
+//! The local APIC timer; each CPU's preferred tick device.
+//!
+//! The timer counts down at a (divided) bus frequency which varies between machines, so it's
+//! calibrated against the HPET (or the PIT) before use. Periodic and one-shot modes are always
+//! available; one-shot events use TSC-deadline mode instead when the CPU supports it.
+
+use core::cmp;
+use core::sync::atomic::{AtomicU64, Ordering};
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+
+use super::{
+    local_apic, LVT_MASKED, REG_LVT_TIMER, REG_TIMER_CURRENT, REG_TIMER_DIVIDE, REG_TIMER_INITIAL,
+};
+use arch::x86_64::device::pit::PIT;
+use arch::x86_64::interrupts::INT_LAPIC_TIMER;
+use arch::x86_64::tsc;
+use time::clockevent::{self, ClockEventDevice, Features};
+use time::clocksource;
+
+/// The local APIC timer, as a clock-event device.
+pub static LAPIC_TIMER: LapicTimer = LapicTimer;
+
+const LVT_MODE_ONESHOT: u32 = 0b00 << 17;
+const LVT_MODE_PERIODIC: u32 = 0b01 << 17;
+const LVT_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// Divide configuration value for dividing the bus clock by 16.
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+/// How long to measure the timer against the PIT for, in _µs_.
+const CALIBRATION_US: u32 = 10_000;
+
+/// The rate the timer counts down at, in _Hz_. Zero until calibrated.
+static TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
+
+/// Modes the local APIC timer can run in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Count down once from the initial count, then stop.
+    OneShot,
+    /// Count down from the initial count, then reload it and start again.
+    Periodic,
+    /// Fire when the TSC reaches the value written to `IA32_TSC_DEADLINE`.
+    TscDeadline,
+}
+
+impl TimerMode {
+    fn lvt_bits(self) -> u32 {
+        match self {
+            TimerMode::OneShot => LVT_MODE_ONESHOT,
+            TimerMode::Periodic => LVT_MODE_PERIODIC,
+            TimerMode::TscDeadline => LVT_MODE_TSC_DEADLINE,
+        }
+    }
+}
+
+/// The local APIC timer.
+pub struct LapicTimer;
+
+impl LapicTimer {
+    /// Does this CPU support TSC-deadline mode?
+    pub fn has_tsc_deadline() -> bool {
+        CpuId::new()
+            .get_feature_info()
+            .map_or(false, |f| f.has_tsc_deadline())
+    }
+
+    /// The rate the timer counts down at, in _Hz_.
+    pub fn frequency() -> u64 {
+        TIMER_FREQ.load(Ordering::Relaxed)
+    }
+
+    /// Switches the timer into `mode`, unmasked and firing [INT_LAPIC_TIMER].
+    unsafe fn set_mode(&self, mode: TimerMode) {
+        let lapic = local_apic();
+        lapic.write(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+        lapic.write(REG_LVT_TIMER, mode.lvt_bits() | INT_LAPIC_TIMER as u32);
+
+        if mode == TimerMode::TscDeadline {
+            // the LVT write has to be serialized before the first write to IA32_TSC_DEADLINE,
+            // or the deadline may be ignored (SDM vol. 3, 10.5.4.1)
+            asm!("mfence" :::: "volatile");
+        }
+    }
+}
+
+impl ClockEventDevice for LapicTimer {
+    fn name(&self) -> &'static str {
+        "lapic-timer"
+    }
+
+    fn features(&self) -> Features {
+        Features::PERIODIC | Features::ONESHOT | Features::PER_CPU
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn set_periodic(&self, freq: u32) {
+        let count = cmp::max(Self::frequency() / u64::from(freq), 1);
+        let count = cmp::min(count, u64::from(u32::max_value())) as u32;
+
+        unsafe {
+            self.set_mode(TimerMode::Periodic);
+            local_apic().write(REG_TIMER_INITIAL, count);
+        }
+    }
+
+    fn set_oneshot(&self, delta_ns: u64) {
+        unsafe {
+            if Self::has_tsc_deadline() {
+                let cycles = ns_to_ticks(delta_ns, tsc::frequency());
+
+                self.set_mode(TimerMode::TscDeadline);
+                Msr::new(IA32_TSC_DEADLINE).write(tsc::read() + cmp::max(cycles, 1));
+            } else {
+                let count = ns_to_ticks(delta_ns, Self::frequency());
+                let count = cmp::min(cmp::max(count, 1), u64::from(u32::max_value())) as u32;
+
+                self.set_mode(TimerMode::OneShot);
+                local_apic().write(REG_TIMER_INITIAL, count);
+            }
+        }
+    }
+
+    fn shutdown(&self) {
+        unsafe {
+            let lapic = local_apic();
+            lapic.write(REG_LVT_TIMER, LVT_MASKED | INT_LAPIC_TIMER as u32);
+            lapic.write(REG_TIMER_INITIAL, 0);
+
+            if Self::has_tsc_deadline() {
+                Msr::new(IA32_TSC_DEADLINE).write(0);
+            }
+        }
+    }
+}
+
+/// Converts a number of nanoseconds into ticks of a `freq` _Hz_ clock.
+fn ns_to_ticks(ns: u64, freq: u64) -> u64 {
+    (u128::from(ns) * u128::from(freq) / 1_000_000_000) as u64
+}
+
+/// Measures how fast the timer counts, by timing a delay against the current clock source, or
+/// against the PIT if there isn't one.
+///
+/// Must be called with interrupts disabled.
+unsafe fn calibrate() {
+    let lapic = local_apic();
+
+    lapic.write(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    lapic.write(REG_LVT_TIMER, LVT_MASKED | LVT_MODE_ONESHOT);
+    lapic.write(REG_TIMER_INITIAL, u32::max_value());
+
+    match clocksource::current() {
+        Some(source) => source.delay_us(u64::from(CALIBRATION_US)),
+        None => PIT.lock().delay_us(CALIBRATION_US),
+    }
+
+    let elapsed = u32::max_value() - lapic.read(REG_TIMER_CURRENT);
+    lapic.write(REG_TIMER_INITIAL, 0);
+
+    let per_sec = 1_000_000 / u64::from(CALIBRATION_US);
+    TIMER_FREQ.store(u64::from(elapsed) * per_sec, Ordering::Relaxed);
+}
+
+/// Calibrates the timer (the first time through), and makes it the current CPU's tick device.
+///
+/// Must be called with interrupts disabled, after [super::init] and [tsc::init].
+pub fn init() {
+    if LapicTimer::frequency() == 0 {
+        unsafe { calibrate() };
+
+        info!(
+            "apic: timer runs at {} Hz{}",
+            LapicTimer::frequency(),
+            if LapicTimer::has_tsc_deadline() {
+                " (tsc-deadline capable)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    clockevent::register(&LAPIC_TIMER);
+}
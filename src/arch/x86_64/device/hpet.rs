@@ -0,0 +1,383 @@
+//! Driver for the High Precision Event Timer.
+//!
+//! The HPET is found through the ACPI `HPET` table. It has a monotonic main counter, which we use
+//! as a clock source, and a handful of comparators which can raise an interrupt when the counter
+//! reaches a given value, either once or periodically.
+//!
+//! Without an I/O APIC, the only interrupt routing we can use is *legacy replacement*: comparator
+//! 0 takes over IRQ 0 from the PIT, and comparator 1 takes IRQ 8 from the RTC.
+
+use core::{cmp, ptr};
+use spin::Once;
+
+use arch::x86_64::acpi;
+use arch::x86_64::interrupts::irq;
+use arch::x86_64::memory::paging::table::EntryFlags;
+use arch::x86_64::memory::paging::{Frame, FrameAllocator};
+use arch::x86_64::memory::MemoryController;
+use time::clockevent::{self, ClockEventDevice, Features};
+use time::clocksource::{self, ClockSource};
+
+/// The HPET. Initialized by [init]; is empty before then, or if there isn't one.
+pub static HPET: Once<Hpet> = Once::new();
+
+/// Comparator 0 of the HPET (in legacy replacement mode, on IRQ 0), as a clock-event device.
+pub static HPET_CLOCK_EVENT: HpetClockEvent = HpetClockEvent;
+
+const FS_PER_SEC: u64 = 1_000_000_000_000_000;
+/// The longest counter period the spec allows (100ns), in femtoseconds.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+/// The fewest ticks ahead a one-shot comparator's set for, so that the counter's unlikely to have
+/// passed it by the time it's written.
+const MIN_ONESHOT_TICKS: u64 = 64;
+
+// register offsets
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0f0;
+
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CAP_LEGACY_REPLACEMENT: u64 = 1 << 15;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+
+/// A memory-mapped HPET block.
+pub struct Hpet {
+    base: usize,
+    period_fs: u64,
+    comparators: usize,
+    capabilities: u64,
+}
+
+impl Hpet {
+    unsafe fn read_reg(&self, reg: usize) -> u64 {
+        ptr::read_volatile((self.base + reg) as *const u64)
+    }
+
+    unsafe fn write_reg(&self, reg: usize, value: u64) {
+        ptr::write_volatile((self.base + reg) as *mut u64, value)
+    }
+
+    /// How long one tick of the main counter is, in femtoseconds.
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+
+    /// The rate the main counter counts at, in _Hz_.
+    pub fn frequency(&self) -> u64 {
+        FS_PER_SEC / self.period_fs
+    }
+
+    /// Reads the main counter.
+    pub fn counter(&self) -> u64 {
+        unsafe { self.read_reg(REG_MAIN_COUNTER) }
+    }
+
+    /// Is the main counter 64 bits wide (rather than 32)?
+    pub fn has_64bit_counter(&self) -> bool {
+        self.capabilities & CAP_COUNTER_64BIT != 0
+    }
+
+    /// Can comparators 0 and 1 take over the PIT's and RTC's IRQs?
+    pub fn has_legacy_replacement(&self) -> bool {
+        self.capabilities & CAP_LEGACY_REPLACEMENT != 0
+    }
+
+    /// How many comparators this HPET has.
+    pub fn comparators(&self) -> usize {
+        self.comparators
+    }
+
+    /// Returns comparator `index`, if it exists.
+    pub fn comparator(&self, index: usize) -> Option<Comparator> {
+        if index < self.comparators {
+            Some(Comparator { hpet: self, index })
+        } else {
+            None
+        }
+    }
+
+    /// Turns legacy replacement routing on or off.
+    pub fn set_legacy_replacement(&self, enabled: bool) {
+        unsafe {
+            let config = self.read_reg(REG_CONFIG);
+            self.write_reg(
+                REG_CONFIG,
+                if enabled {
+                    config | CONFIG_LEGACY_REPLACEMENT
+                } else {
+                    config & !CONFIG_LEGACY_REPLACEMENT
+                },
+            );
+        }
+    }
+
+    /// Resets the main counter to zero and starts it.
+    unsafe fn start(&self) {
+        let config = self.read_reg(REG_CONFIG) & !CONFIG_ENABLE;
+        self.write_reg(REG_CONFIG, config);
+        self.write_reg(REG_MAIN_COUNTER, 0);
+        self.write_reg(REG_CONFIG, config | CONFIG_ENABLE);
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn read(&self) -> u64 {
+        self.counter()
+    }
+
+    fn mask(&self) -> u64 {
+        if self.has_64bit_counter() {
+            u64::max_value()
+        } else {
+            u64::from(u32::max_value())
+        }
+    }
+
+    fn frequency(&self) -> u64 {
+        Hpet::frequency(self)
+    }
+}
+
+/// One of an HPET's comparators.
+pub struct Comparator<'a> {
+    hpet: &'a Hpet,
+    index: usize,
+}
+
+impl<'a> Comparator<'a> {
+    fn config_reg(&self) -> usize {
+        0x100 + 0x20 * self.index
+    }
+
+    fn comparator_reg(&self) -> usize {
+        0x108 + 0x20 * self.index
+    }
+
+    fn config(&self) -> u64 {
+        unsafe { self.hpet.read_reg(self.config_reg()) }
+    }
+
+    /// Can this comparator fire periodically?
+    pub fn is_periodic_capable(&self) -> bool {
+        self.config() & TIMER_PERIODIC_CAPABLE != 0
+    }
+
+    /// Is this comparator 64 bits wide (rather than 32)?
+    pub fn is_64bit(&self) -> bool {
+        self.config() & TIMER_64BIT_CAPABLE != 0
+    }
+
+    /// The largest value the comparator (and the counter it's matched against) can hold.
+    fn max_value(&self) -> u64 {
+        if self.is_64bit() && self.hpet.has_64bit_counter() {
+            u64::max_value()
+        } else {
+            u64::from(u32::max_value())
+        }
+    }
+
+    /// Fires the comparator's interrupt once, `ticks` main counter ticks from now (or at least
+    /// [MIN_ONESHOT_TICKS], and at most half the comparator's range).
+    ///
+    /// The comparator only fires when the counter matches it exactly, so if the counter's already
+    /// passed it by the time it's written, it's written again further ahead.
+    pub fn set_oneshot(&self, ticks: u64) {
+        let max = self.max_value();
+        let mut ticks = cmp::min(cmp::max(ticks, MIN_ONESHOT_TICKS), max / 2);
+
+        unsafe {
+            let config = self.config() & !TIMER_PERIODIC;
+            self.hpet
+                .write_reg(self.config_reg(), config | TIMER_INT_ENABLE);
+
+            loop {
+                let start = self.hpet.counter();
+                self.hpet
+                    .write_reg(self.comparator_reg(), start.wrapping_add(ticks) & max);
+
+                let elapsed = self.hpet.counter().wrapping_sub(start) & max;
+                if elapsed < ticks {
+                    break;
+                }
+                ticks = cmp::min(ticks * 2, max / 2);
+            }
+        }
+    }
+
+    /// Fires the comparator's interrupt every `ticks` main counter ticks.
+    ///
+    /// # Panics
+    /// If the comparator isn't periodic-capable.
+    pub fn set_periodic(&self, ticks: u64) {
+        assert!(
+            self.is_periodic_capable(),
+            "hpet: comparator {} can't run periodically",
+            self.index
+        );
+
+        unsafe {
+            let config = self.config();
+            self.hpet.write_reg(
+                self.config_reg(),
+                config | TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET,
+            );
+            // with VALUE_SET, the first write sets the time of the first interrupt,
+            // and the second sets the period
+            let first = self.hpet.counter().wrapping_add(ticks) & self.max_value();
+            self.hpet.write_reg(self.comparator_reg(), first);
+            self.hpet.write_reg(self.comparator_reg(), ticks);
+        }
+    }
+
+    /// Stops the comparator from raising interrupts.
+    pub fn disable(&self) {
+        unsafe {
+            let config = self.config() & !(TIMER_INT_ENABLE | TIMER_PERIODIC);
+            self.hpet.write_reg(self.config_reg(), config);
+        }
+    }
+}
+
+/// Comparator 0 of the HPET, routed to IRQ 0.
+pub struct HpetClockEvent;
+
+impl HpetClockEvent {
+    fn comparator() -> Comparator<'static> {
+        HPET.try()
+            .and_then(|hpet| hpet.comparator(0))
+            .expect("hpet: clock-event device used without an HPET")
+    }
+}
+
+impl ClockEventDevice for HpetClockEvent {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn features(&self) -> Features {
+        Features::PERIODIC | Features::ONESHOT
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+
+    fn set_periodic(&self, freq: u32) {
+        let comparator = Self::comparator();
+        comparator.hpet.set_legacy_replacement(true);
+        comparator.set_periodic(comparator.hpet.frequency() / u64::from(freq));
+
+        irq::unmask(0);
+    }
+
+    fn set_oneshot(&self, delta_ns: u64) {
+        let comparator = Self::comparator();
+        let ticks = u128::from(delta_ns) * 1_000_000 / u128::from(comparator.hpet.period_fs);
+        // the comparator clamps it further
+        let ticks = cmp::min(ticks, u128::from(u64::max_value())) as u64;
+
+        comparator.hpet.set_legacy_replacement(true);
+        comparator.set_oneshot(ticks);
+
+        irq::unmask(0);
+    }
+
+    fn shutdown(&self) {
+        let comparator = Self::comparator();
+        comparator.disable();
+        comparator.hpet.set_legacy_replacement(false);
+
+        irq::mask(0);
+    }
+}
+
+/// Finds the HPET through ACPI, maps and starts it, and registers it as a clock source (and, if
+/// it can take over the PIT's IRQ, as a clock-event device).
+///
+/// Must be called after [acpi::init]. Returns `false` if there's no usable HPET.
+pub fn init<A: FrameAllocator>(memory_controller: &mut MemoryController<A>) -> bool {
+    assert_first_call!("hpet::init() can only be called once!");
+
+    let table = match acpi::find_table("HPET") {
+        Some(table) => table.data(),
+        None => {
+            info!("hpet: no HPET table");
+            return false;
+        }
+    };
+
+    // the base address is a Generic Address Structure, starting at byte 4 of the table
+    let (address_space, phys_addr) = match (table.get(4), table.get(8..16)) {
+        (Some(&address_space), Some(address)) => {
+            let phys_addr = address
+                .iter()
+                .rev()
+                .fold(0usize, |addr, b| (addr << 8) | *b as usize);
+            (address_space, phys_addr)
+        }
+        _ => {
+            warn!("hpet: HPET table too short ({} bytes)", table.len());
+            return false;
+        }
+    };
+    if address_space != 0 {
+        warn!("hpet: not memory-mapped (address space {})", address_space);
+        return false;
+    }
+
+    memory_controller.identity_map_range(
+        phys_addr,
+        Frame::SIZE,
+        EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE,
+    );
+
+    let capabilities = unsafe { ptr::read_volatile((phys_addr + REG_CAPABILITIES) as *const u64) };
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        warn!("hpet: bogus counter period of {}fs", period_fs);
+        return false;
+    }
+
+    let hpet = HPET.call_once(|| Hpet {
+        base: phys_addr,
+        period_fs,
+        comparators: ((capabilities >> 8) & 0x1f) as usize + 1,
+        capabilities,
+    });
+
+    for comparator in (0..hpet.comparators()).filter_map(|i| hpet.comparator(i)) {
+        comparator.disable();
+    }
+    unsafe { hpet.start() };
+
+    info!(
+        "hpet: {} comparators, {}-bit counter at {} Hz, at {:#x}",
+        hpet.comparators(),
+        if hpet.has_64bit_counter() { 64 } else { 32 },
+        hpet.frequency(),
+        phys_addr
+    );
+
+    clocksource::register(hpet);
+    if hpet.has_legacy_replacement() && hpet.comparator(0).unwrap().is_periodic_capable() {
+        clockevent::register(&HPET_CLOCK_EVENT);
+    }
+
+    true
+}
@@ -2,7 +2,10 @@
 //!
 //! Basically, stuff that's mandated by PC99.
 
+pub mod apic;
+pub mod hpet;
 pub mod pic;
 pub mod pit;
+pub mod rtc;
 pub mod serial;
 pub mod vga_console;
@@ -8,11 +8,18 @@ pub static PICS: RwLock<ChainedPics> = RwLock::new(ChainedPics::new(0x20, 0x28))
 
 // command constants to send
 const PIC_OCW2_EOI: u8 = 0x20;
+const PIC_OCW3_READ_IRR: u8 = 0x0a;
+const PIC_OCW3_READ_ISR: u8 = 0x0b;
 
 const PIC_ICW1_INIT: u8 = 0x10;
 const PIC_ICW1_ICW4: u8 = 0x01;
 const PIC_ICW4_MODE_8086: u8 = 0x01;
 
+/// The primary PIC's IRQ line the secondary PIC is chained to.
+pub const CASCADE_IRQ: u8 = 2;
+/// Each PIC raises spurious interrupts on its lowest-priority line (IRQ 7 and IRQ 15).
+const SPURIOUS_LINE: u8 = 7;
+
 /// A pair of chained PICs.
 pub struct ChainedPics {
     /// The first PIC, attached directly to the CPU.
@@ -33,6 +40,7 @@ impl ChainedPics {
     ///
     /// # Notes
     /// This remaps the primary PIC to IRQs 0x20..0x28, and the secondary PIC to 0x28..0x36.
+    /// Every IRQ line is left masked.
     pub unsafe fn init(&mut self) {
         // ICW1: start initialization, signal that we want the ICW4 phase
         self.primary.cmd.write(PIC_ICW1_INIT | PIC_ICW1_ICW4);
@@ -43,16 +51,69 @@ impl ChainedPics {
         self.secondary.data.write(self.secondary.offset);
 
         // ICW3: configure PIC cascading. IRQ 2 [via PC99] is used to chain to the secondary PIC.
-        self.primary.data.write(1 << 2); // the IRQ 2 line is used for cascading
-        self.secondary.data.write(2); // the secondary PIC has an ID of 2
+        self.primary.data.write(1 << CASCADE_IRQ); // the IRQ 2 line is used for cascading
+        self.secondary.data.write(CASCADE_IRQ); // the secondary PIC has an ID of 2
 
         // ICW4: put the PICs into 8086 mode (EOIs are required)
         self.primary.data.write(PIC_ICW4_MODE_8086);
         self.secondary.data.write(PIC_ICW4_MODE_8086);
 
-        // initialization done. clear IRQ masks
-        self.primary.set_irq_mask(0);
-        self.secondary.set_irq_mask(0);
+        // initialization done. mask everything; lines get unmasked as handlers are registered
+        self.disable();
+    }
+
+    /// Masks every IRQ line on both PICs, so they never interrupt us.
+    ///
+    /// Use this when some other interrupt controller (*e.g.* an I/O APIC) takes over.
+    pub unsafe fn disable(&mut self) {
+        self.primary.set_irq_mask(0xff);
+        self.secondary.set_irq_mask(0xff);
+    }
+
+    /// Masks IRQ line `irq` (0..16), so it won't trigger interrupts.
+    pub unsafe fn mask_irq(&mut self, irq: u8) {
+        let (pic, line) = self.pic_for_irq(irq);
+        let mask = pic.get_irq_mask();
+        pic.set_irq_mask(mask | (1 << line));
+    }
+
+    /// Unmasks IRQ line `irq` (0..16), letting it trigger interrupts.
+    pub unsafe fn unmask_irq(&mut self, irq: u8) {
+        if irq >= 8 {
+            // the secondary PIC can't reach us if the cascade line is masked
+            self.unmask_irq(CASCADE_IRQ);
+        }
+
+        let (pic, line) = self.pic_for_irq(irq);
+        let mask = pic.get_irq_mask();
+        pic.set_irq_mask(mask & !(1 << line));
+    }
+
+    /// The combined IRQ mask of the chain: the primary PIC's in the low byte, and the
+    /// secondary's in the high byte.
+    pub unsafe fn irq_mask(&mut self) -> u16 {
+        u16::from(self.secondary.get_irq_mask()) << 8 | u16::from(self.primary.get_irq_mask())
+    }
+
+    /// The combined In-Service Register of the chain; bit _n_ is set if IRQ _n_ is being serviced.
+    pub unsafe fn in_service(&mut self) -> u16 {
+        u16::from(self.secondary.read_isr()) << 8 | u16::from(self.primary.read_isr())
+    }
+
+    /// The combined Interrupt Request Register of the chain; bit _n_ is set if IRQ _n_ has been
+    /// raised, but not yet delivered.
+    pub unsafe fn requested(&mut self) -> u16 {
+        u16::from(self.secondary.read_irr()) << 8 | u16::from(self.primary.read_irr())
+    }
+
+    fn pic_for_irq(&mut self, irq: u8) -> (&mut Pic, u8) {
+        assert!(irq < 16, "pic: no such IRQ line {}", irq);
+
+        if irq < 8 {
+            (&mut self.primary, irq)
+        } else {
+            (&mut self.secondary, irq - 8)
+        }
     }
 
     /// Do any of the PICs in this chain handle the given INT?
@@ -62,8 +123,33 @@ impl ChainedPics {
             .any(|p| p.handles_int(irq))
     }
 
+    /// Checks whether `int` is a spurious interrupt: one raised on IRQ 7 or IRQ 15 for a request
+    /// which went away before the CPU acknowledged it. These show up without the line's
+    /// In-Service bit set, and mustn't be acknowledged like real IRQs.
+    ///
+    /// If `int` is spurious, this does whatever acknowledgement *is* needed (the primary PIC still
+    /// considers a spurious IRQ 15 to be in service on its cascade line) and returns `true`; the
+    /// interrupt should then be ignored.
+    pub unsafe fn handle_spurious(&mut self, int: u8) -> bool {
+        if int == self.primary.offset + SPURIOUS_LINE {
+            self.primary.read_isr() & (1 << SPURIOUS_LINE) == 0
+        } else if int == self.secondary.offset + SPURIOUS_LINE {
+            let spurious = self.secondary.read_isr() & (1 << SPURIOUS_LINE) == 0;
+            if spurious {
+                self.primary.eoi();
+            }
+
+            spurious
+        } else {
+            false
+        }
+    }
+
     /// Given an interrupt, send an end-of-interrupt message to the
     /// PICs in this chain which should hear it.
+    ///
+    /// Spurious interrupts must be weeded out with [handle_spurious](#method.handle_spurious)
+    /// first; acknowledging them would end whichever real IRQ is in service instead.
     pub unsafe fn eoi(&mut self, int: u8) {
         if self.handles_int(int) {
             if self.secondary.handles_int(int) {
@@ -111,11 +197,23 @@ impl Pic {
         self.data.read()
     }
 
-    /// Get the IRQ mask of this PIC.
+    /// Set the IRQ mask of this PIC.
     ///
     /// Each bit in the IRQ mask is 0 if that IRQ is enabled, and 1 if that IRQ is masked;
     /// masked IRQs will not trigger interrupts.
     pub unsafe fn set_irq_mask(&mut self, mask: u8) {
         self.data.write(mask)
     }
+
+    /// Read the In-Service Register: which IRQs have been delivered, but not yet EOI'd.
+    pub unsafe fn read_isr(&mut self) -> u8 {
+        self.cmd.write(PIC_OCW3_READ_ISR);
+        self.cmd.read()
+    }
+
+    /// Read the Interrupt Request Register: which IRQs have been raised, but not yet delivered.
+    pub unsafe fn read_irr(&mut self) -> u8 {
+        self.cmd.write(PIC_OCW3_READ_IRR);
+        self.cmd.read()
+    }
 }
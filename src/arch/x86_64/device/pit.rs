@@ -1,15 +1,37 @@
 //! Driver for the Programmable Interrupt Timer (Intel 8253/8254).
+//!
+//! The PIT has three 16-bit down-counters, all clocked at [FREQ]: channel 0 drives IRQ 0, channel
+//! 1 used to refresh DRAM (and is left alone), and channel 2 is gated through the system control
+//! port, and drives the PC speaker.
 
+use core::cmp;
+use spin::Mutex;
 use x86_64::instructions::port::Port;
 
-pub static mut PIT: Pit = Pit {
+use arch::x86_64::interrupts::{irq, without_interrupts};
+use time::clockevent::{ClockEventDevice, Features};
+
+/// The PIT.
+///
+/// Channel 0 is reprogrammed from interrupt handlers, so only lock this with interrupts disabled.
+pub static PIT: Mutex<Pit> = Mutex::new(Pit {
     chan0: Port::new(0x40),
+    chan2: Port::new(0x42),
     mode: Port::new(0x43),
-};
+    control: Port::new(0x61),
+});
+
+/// The PIT (channel 0, on IRQ 0), as a clock-event device.
+pub static PIT_CLOCK_EVENT: PitClockEvent = PitClockEvent;
 
-const SELECT_CHAN0: u8 = 0;
+// mode/command register fields
+const ACCESS_LATCH: u8 = 0x00;
 const ACCESS_LOHI: u8 = 0x30;
-const MODE_2: u8 = 1 << 2;
+
+// bits of the system control port (0x61)
+const CONTROL_CHAN2_GATE: u8 = 1 << 0;
+const CONTROL_SPEAKER: u8 = 1 << 1;
+const CONTROL_CHAN2_OUT: u8 = 1 << 5;
 
 /// Base frequency of the PIT, in _Hz_.
 pub const FREQ: u32 = 1193182;
@@ -17,18 +39,246 @@ pub const FREQ: u32 = 1193182;
 /// The target frequency to tick at, in _Hz_.
 pub const TICK_FREQ: u32 = 20;
 
+/// The PIT channels we use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    /// Drives IRQ 0.
+    Zero,
+    /// Gated by, and readable through, the system control port; drives the PC speaker.
+    Two,
+}
+
+impl Channel {
+    fn select(self) -> u8 {
+        match self {
+            Channel::Zero => 0b00 << 6,
+            Channel::Two => 0b10 << 6,
+        }
+    }
+}
+
+/// A channel's operating mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Count down once; OUT goes high on reaching zero (mode 0).
+    InterruptOnTerminalCount,
+    /// Like mode 0, but (re)started by the gate rising (mode 1).
+    HardwareOneShot,
+    /// Pulse OUT low every `count` ticks (mode 2).
+    RateGenerator,
+    /// Toggle OUT every `count / 2` ticks (mode 3).
+    SquareWave,
+    /// Pulse OUT low once, on reaching zero (mode 4).
+    SoftwareStrobe,
+    /// Like mode 4, but started by the gate rising (mode 5).
+    HardwareStrobe,
+}
+
+impl Mode {
+    fn bits(self) -> u8 {
+        let mode = match self {
+            Mode::InterruptOnTerminalCount => 0,
+            Mode::HardwareOneShot => 1,
+            Mode::RateGenerator => 2,
+            Mode::SquareWave => 3,
+            Mode::SoftwareStrobe => 4,
+            Mode::HardwareStrobe => 5,
+        };
+
+        mode << 1
+    }
+}
+
+/// The reload value giving `freq` _Hz_, clamped to what the PIT can do (18.2 _Hz_ and up).
+fn divisor_for(freq: u32) -> u16 {
+    // a reload value of 0 means 65536; modes 2 and 3 can't count from 1
+    match FREQ / cmp::max(freq, 1) {
+        0..=2 => 2,
+        divisor if divisor >= 0x10000 => 0,
+        divisor => divisor as u16,
+    }
+}
+
+/// The frequency, in _Hz_, a reload value of `divisor` gives.
+fn frequency_of(divisor: u16) -> u32 {
+    match divisor {
+        0 => FREQ / 0x10000,
+        divisor => FREQ / u32::from(divisor),
+    }
+}
+
 pub struct Pit {
     chan0: Port<u8>,
+    chan2: Port<u8>,
     mode: Port<u8>,
+    control: Port<u8>,
 }
 
 impl Pit {
-    /// Initialize the PIT.
-    pub unsafe fn init(&mut self) {
-        const DIVISOR: u16 = (FREQ / TICK_FREQ) as u16;
+    fn data_port(&mut self, channel: Channel) -> &mut Port<u8> {
+        match channel {
+            Channel::Zero => &mut self.chan0,
+            Channel::Two => &mut self.chan2,
+        }
+    }
+
+    /// Programs `channel` to run in `mode`, counting down from `count` (0 meaning 65536).
+    pub fn configure(&mut self, channel: Channel, mode: Mode, count: u16) {
+        unsafe {
+            self.mode.write(channel.select() | ACCESS_LOHI | mode.bits());
+            let data = self.data_port(channel);
+            data.write((count & 0xff) as u8);
+            data.write((count >> 8) as u8);
+        }
+    }
+
+    /// Reads `channel`'s current count, using the latch command so the two halves match.
+    pub fn read_count(&mut self, channel: Channel) -> u16 {
+        unsafe {
+            self.mode.write(channel.select() | ACCESS_LATCH);
+            let data = self.data_port(channel);
+            let low = data.read();
+            let high = data.read();
+
+            (u16::from(high) << 8) | u16::from(low)
+        }
+    }
+
+    /// Programs channel 0 to interrupt `freq` times a second (mode 2), returning the frequency
+    /// it'll actually run at.
+    pub fn set_periodic(&mut self, freq: u32) -> u32 {
+        let divisor = divisor_for(freq);
+        self.configure(Channel::Zero, Mode::RateGenerator, divisor);
+
+        frequency_of(divisor)
+    }
+
+    /// Programs channel 0 to interrupt once, after `count` PIT ticks (mode 0).
+    pub fn set_oneshot(&mut self, count: u16) {
+        self.configure(Channel::Zero, Mode::InterruptOnTerminalCount, count);
+    }
+
+    /// Sets channel 2's gate, which starts (or, when lowered, pauses) it counting.
+    fn set_chan2_gate(&mut self, high: bool) {
+        unsafe {
+            let control = self.control.read() & !CONTROL_CHAN2_GATE;
+            self.control
+                .write(if high { control | CONTROL_CHAN2_GATE } else { control });
+        }
+    }
+
+    /// Is channel 2's OUT high?
+    pub fn chan2_out(&mut self) -> bool {
+        unsafe { self.control.read() & CONTROL_CHAN2_OUT != 0 }
+    }
+
+    /// Busy-waits for `us` microseconds (at most ~54ms), using channel 2.
+    ///
+    /// Channel 0 is left alone, so this can be used to calibrate other timers at any point.
+    /// Turns the speaker off.
+    pub fn delay_us(&mut self, us: u32) {
+        let count = u64::from(FREQ) * u64::from(us) / 1_000_000;
+        assert!(count <= 0xffff, "pit: delay of {}us is too long", us);
+
+        // hold channel 2's gate low while loading the count; speaker off
+        self.speaker_off();
+        self.configure(Channel::Two, Mode::InterruptOnTerminalCount, count as u16);
+
+        // raising the gate starts the countdown; OUT goes high when it hits zero
+        self.set_chan2_gate(true);
+        while !self.chan2_out() {}
+
+        self.set_chan2_gate(false);
+    }
+
+    /// Plays a `freq` _Hz_ tone on the PC speaker, until [speaker_off](#method.speaker_off).
+    pub fn speaker_on(&mut self, freq: u32) {
+        self.configure(Channel::Two, Mode::SquareWave, divisor_for(freq));
+
+        unsafe {
+            let control = self.control.read();
+            self.control
+                .write(control | CONTROL_CHAN2_GATE | CONTROL_SPEAKER);
+        }
+    }
+
+    /// Silences the PC speaker (and stops channel 2).
+    pub fn speaker_off(&mut self) {
+        unsafe {
+            let control = self.control.read() & !(CONTROL_CHAN2_GATE | CONTROL_SPEAKER);
+            self.control.write(control);
+        }
+    }
+}
+
+/// Channel 0 of the PIT. Every PC has one, which makes it the tick device of last resort.
+pub struct PitClockEvent;
+
+impl ClockEventDevice for PitClockEvent {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn features(&self) -> Features {
+        Features::PERIODIC | Features::ONESHOT
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn set_periodic(&self, freq: u32) {
+        let actual = without_interrupts(|| PIT.lock().set_periodic(freq));
+        if actual != freq {
+            debug!("pit: asked for {} Hz, got {} Hz", freq, actual);
+        }
+
+        irq::unmask(0);
+    }
+
+    fn set_oneshot(&self, delta_ns: u64) {
+        let count = delta_ns.saturating_mul(u64::from(FREQ)) / 1_000_000_000;
+        let count = cmp::min(cmp::max(count, 1), 0xffff) as u16;
+
+        without_interrupts(|| PIT.lock().set_oneshot(count));
+        irq::unmask(0);
+    }
+
+    fn shutdown(&self) {
+        irq::mask(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divisors() {
+        assert_eq!(divisor_for(TICK_FREQ), 59_659);
+        assert_eq!(divisor_for(19), 62_799);
+        // too slow: the longest period there is
+        assert_eq!(divisor_for(18), 0);
+        assert_eq!(divisor_for(0), 0);
+        // too fast: the shortest period modes 2 and 3 can do
+        assert_eq!(divisor_for(FREQ), 2);
+        assert_eq!(divisor_for(u32::max_value()), 2);
+    }
+
+    #[test]
+    fn frequencies() {
+        assert_eq!(frequency_of(0), 18);
+        assert_eq!(frequency_of(2), 596_591);
+        assert_eq!(frequency_of(59_659), 20);
+    }
 
-        self.mode.write(ACCESS_LOHI | SELECT_CHAN0 | MODE_2);
-        self.chan0.write((DIVISOR & 0xff) as u8);
-        self.chan0.write((DIVISOR >> 8) as u8);
+    #[test]
+    fn nearest_frequency() {
+        // the divisor gives the slowest frequency that's at least as fast as asked for
+        for freq in (19..=FREQ / 2).step_by(997) {
+            let divisor = divisor_for(freq);
+            assert!(frequency_of(divisor) >= freq, "{} Hz", freq);
+            assert!(frequency_of(divisor + 1) <= freq, "{} Hz", freq);
+        }
     }
 }
@@ -0,0 +1,359 @@
+//! Driver for the CMOS real-time clock (Motorola MC146818 and compatibles).
+//!
+//! The RTC keeps the date and time while the machine's off, and can interrupt on IRQ 8
+//! periodically (at 2 to 8192 _Hz_) or at an alarm time each day. Its registers may hold BCD or
+//! binary, and 12- or 24-hour time, depending on how the firmware set it up.
+//!
+//! The HPET's legacy replacement mode takes IRQ 8 for itself, so the RTC's interrupts don't
+//! arrive while the HPET is ticking.
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use arch::x86_64::acpi;
+use arch::x86_64::interrupts::{irq, without_interrupts};
+use deferred;
+use time::wall::{self, DateTime};
+use time::Instant;
+
+/// The RTC.
+pub static RTC: Mutex<Rtc> = Mutex::new(Rtc {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+    century_reg: 0,
+});
+
+/// The IRQ line the RTC interrupts on.
+const IRQ: u8 = 8;
+
+// registers
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+/// Writing an index with this bit set also masks NMIs, which we don't want.
+const NMI_DISABLE: u8 = 1 << 7;
+
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 = 0x0f;
+
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_ALARM_INT: u8 = 1 << 5;
+const STATUS_B_PERIODIC_INT: u8 = 1 << 6;
+
+const STATUS_C_ALARM: u8 = 1 << 5;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+
+/// In 12-hour mode, set in the hours register after noon.
+const HOURS_PM: u8 = 1 << 7;
+
+/// A function called (from deferred context) when an RTC interrupt fires.
+pub type RtcCallback = fn(usize);
+
+/// The callbacks for each of the RTC's interrupts.
+struct Callbacks {
+    periodic: Option<(RtcCallback, usize)>,
+    alarm: Option<(RtcCallback, usize)>,
+}
+
+// locked from the IRQ handler, so only ever with interrupts off
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    periodic: None,
+    alarm: None,
+});
+
+/// The raw contents of the time registers.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// The CMOS real-time clock.
+pub struct Rtc {
+    index: Port<u8>,
+    data: Port<u8>,
+    /// The CMOS register holding the century, if the firmware says there is one (else 0).
+    century_reg: u8,
+}
+
+impl Rtc {
+    unsafe fn read(&mut self, reg: u8) -> u8 {
+        self.index.write(reg & !NMI_DISABLE);
+        self.data.read()
+    }
+
+    unsafe fn write(&mut self, reg: u8, value: u8) {
+        self.index.write(reg & !NMI_DISABLE);
+        self.data.write(value);
+    }
+
+    unsafe fn is_updating(&mut self) -> bool {
+        self.read(REG_STATUS_A) & STATUS_A_UPDATING != 0
+    }
+
+    unsafe fn read_raw(&mut self) -> RawTime {
+        while self.is_updating() {}
+
+        RawTime {
+            second: self.read(REG_SECONDS),
+            minute: self.read(REG_MINUTES),
+            hour: self.read(REG_HOURS),
+            day: self.read(REG_DAY),
+            month: self.read(REG_MONTH),
+            year: self.read(REG_YEAR),
+            century: if self.century_reg != 0 {
+                self.read(self.century_reg)
+            } else {
+                0
+            },
+        }
+    }
+
+    /// Converts a register value from the RTC's format to binary.
+    unsafe fn decode(&mut self, value: u8) -> u8 {
+        if self.read(REG_STATUS_B) & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    }
+
+    /// Converts a binary value to the RTC's format.
+    unsafe fn encode(&mut self, value: u8) -> u8 {
+        if self.read(REG_STATUS_B) & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            to_bcd(value)
+        }
+    }
+
+    /// Converts an hours register value to a 24-hour hour.
+    unsafe fn decode_hour(&mut self, value: u8) -> u8 {
+        if self.read(REG_STATUS_B) & STATUS_B_24_HOUR != 0 {
+            return self.decode(value);
+        }
+
+        let hour = self.decode(value & !HOURS_PM) % 12;
+        if value & HOURS_PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    /// Converts a 24-hour hour to an hours register value.
+    unsafe fn encode_hour(&mut self, hour: u8) -> u8 {
+        if self.read(REG_STATUS_B) & STATUS_B_24_HOUR != 0 {
+            return self.encode(hour);
+        }
+
+        match hour {
+            0 => self.encode(12),
+            1..=11 => self.encode(hour),
+            12 => self.encode(12) | HOURS_PM,
+            _ => self.encode(hour - 12) | HOURS_PM,
+        }
+    }
+
+    /// Reads the current date and time.
+    pub fn read_time(&mut self) -> DateTime {
+        unsafe {
+            // the registers may change under us mid-read, so read until two reads agree
+            let mut raw = self.read_raw();
+            loop {
+                let again = self.read_raw();
+                if again == raw {
+                    break;
+                }
+                raw = again;
+            }
+
+            let year = u16::from(self.decode(raw.year));
+            let century = if self.century_reg != 0 {
+                u16::from(self.decode(raw.century))
+            } else if year < 70 {
+                20
+            } else {
+                19
+            };
+
+            DateTime {
+                year: century * 100 + year,
+                month: self.decode(raw.month),
+                day: self.decode(raw.day),
+                hour: self.decode_hour(raw.hour),
+                minute: self.decode(raw.minute),
+                second: self.decode(raw.second),
+            }
+        }
+    }
+
+    /// Turns the periodic interrupt on, at `32768 >> (rate - 1)` _Hz_.
+    ///
+    /// # Panics
+    /// If `rate` isn't between 3 (8192 _Hz_) and 15 (2 _Hz_).
+    pub fn set_periodic_rate(&mut self, rate: u8) {
+        assert!(rate >= 3 && rate <= 15, "rtc: invalid periodic rate {}", rate);
+
+        unsafe {
+            let status_a = self.read(REG_STATUS_A) & !STATUS_A_RATE_MASK;
+            self.write(REG_STATUS_A, status_a | rate);
+
+            let status_b = self.read(REG_STATUS_B);
+            self.write(REG_STATUS_B, status_b | STATUS_B_PERIODIC_INT);
+        }
+    }
+
+    /// Turns the periodic interrupt off.
+    pub fn disable_periodic(&mut self) {
+        unsafe {
+            let status_b = self.read(REG_STATUS_B);
+            self.write(REG_STATUS_B, status_b & !STATUS_B_PERIODIC_INT);
+        }
+    }
+
+    /// Turns the alarm interrupt on, firing every day at `hour:minute:second`.
+    pub fn set_alarm(&mut self, hour: u8, minute: u8, second: u8) {
+        unsafe {
+            let (hour, minute, second) = (
+                self.encode_hour(hour),
+                self.encode(minute),
+                self.encode(second),
+            );
+            self.write(REG_HOURS_ALARM, hour);
+            self.write(REG_MINUTES_ALARM, minute);
+            self.write(REG_SECONDS_ALARM, second);
+
+            let status_b = self.read(REG_STATUS_B);
+            self.write(REG_STATUS_B, status_b | STATUS_B_ALARM_INT);
+        }
+    }
+
+    /// Turns the alarm interrupt off.
+    pub fn disable_alarm(&mut self) {
+        unsafe {
+            let status_b = self.read(REG_STATUS_B);
+            self.write(REG_STATUS_B, status_b & !STATUS_B_ALARM_INT);
+        }
+    }
+
+    /// Reads (and so acknowledges) which interrupts are pending. Until this is read, the RTC
+    /// won't interrupt again.
+    unsafe fn take_interrupts(&mut self) -> u8 {
+        self.read(REG_STATUS_C)
+    }
+}
+
+/// Handles IRQ 8, running the callbacks for whichever interrupts fired.
+fn handle_irq(_irq: u8) {
+    let pending = unsafe { RTC.lock().take_interrupts() };
+    let callbacks = CALLBACKS.lock();
+
+    let sources = [
+        (STATUS_C_PERIODIC, callbacks.periodic),
+        (STATUS_C_ALARM, callbacks.alarm),
+    ];
+    for &(flag, callback) in sources.iter() {
+        if pending & flag == 0 {
+            continue;
+        }
+        if let Some((func, arg)) = callback {
+            if deferred::defer(func, arg).is_err() {
+                warn!("rtc: deferred queue full; dropping interrupt");
+            }
+        }
+    }
+}
+
+/// Calls `callback(arg)` at `32768 >> (rate - 1)` _Hz_, until [disable_periodic] is called.
+pub fn on_periodic(rate: u8, callback: RtcCallback, arg: usize) {
+    without_interrupts(|| {
+        CALLBACKS.lock().periodic = Some((callback, arg));
+        RTC.lock().set_periodic_rate(rate);
+    });
+}
+
+/// Stops the periodic interrupt.
+pub fn disable_periodic() {
+    without_interrupts(|| {
+        RTC.lock().disable_periodic();
+        CALLBACKS.lock().periodic = None;
+    });
+}
+
+/// Calls `callback(arg)` every day at `hour:minute:second` (in the RTC's time), until
+/// [disable_alarm] is called.
+pub fn on_alarm(hour: u8, minute: u8, second: u8, callback: RtcCallback, arg: usize) {
+    without_interrupts(|| {
+        CALLBACKS.lock().alarm = Some((callback, arg));
+        RTC.lock().set_alarm(hour, minute, second);
+    });
+}
+
+/// Stops the alarm interrupt.
+pub fn disable_alarm() {
+    without_interrupts(|| {
+        RTC.lock().disable_alarm();
+        CALLBACKS.lock().alarm = None;
+    });
+}
+
+/// Finds the century register, reads the time to set the wall clock, and takes IRQ 8.
+///
+/// Should be called after [acpi::init], which says where the century is kept.
+pub fn init() {
+    assert_first_call!("rtc::init() can only be called once!");
+
+    // the FADT's century field is at byte 108 of the table (72 past its header)
+    let century_reg = acpi::find_table("FACP")
+        .and_then(|fadt| fadt.data().get(72).cloned())
+        .unwrap_or(0);
+
+    let time = without_interrupts(|| {
+        let mut rtc = RTC.lock();
+        rtc.century_reg = century_reg;
+
+        unsafe {
+            // start with every interrupt off, and none pending
+            let status_b = rtc.read(REG_STATUS_B);
+            rtc.write(
+                REG_STATUS_B,
+                status_b & !(STATUS_B_ALARM_INT | STATUS_B_PERIODIC_INT),
+            );
+            rtc.take_interrupts();
+        }
+
+        rtc.read_time()
+    });
+    irq::register(IRQ, handle_irq);
+
+    if wall::set_wall_time(time, Instant::now()) {
+        info!("rtc: the time is {}", time);
+    } else {
+        warn!("rtc: ignoring the implausible time {}", time);
+    }
+}
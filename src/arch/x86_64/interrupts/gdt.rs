@@ -16,7 +16,10 @@ impl Gdt {
         }
     }
 
+    /// Adds `entry` to the table, returning a selector for it (with the entry's privilege level
+    /// as the RPL).
     pub fn add_entry(&mut self, entry: Descriptor) -> SegmentSelector {
+        let privilege = entry.privilege_level();
         let idx = match entry {
             Descriptor::UserSegment(value) => self.push(value),
             Descriptor::SystemSegment(low, high) => {
@@ -27,7 +30,7 @@ impl Gdt {
             }
         };
 
-        SegmentSelector::new(idx as u16, PrivilegeLevel::Ring0)
+        SegmentSelector::new(idx as u16, privilege)
     }
 
     pub fn load(&'static self) {
@@ -65,14 +68,37 @@ pub enum Descriptor {
 
 impl Descriptor {
     pub fn kernel_code_segment() -> Descriptor {
-        let flags = DescriptorFlags::USER_SEGMENT
-            | DescriptorFlags::PRESENT
-            | DescriptorFlags::EXECUTABLE
-            | DescriptorFlags::LONG_MODE;
+        Descriptor::UserSegment(DescriptorFlags::KERNEL_CODE.bits())
+    }
+
+    pub fn kernel_data_segment() -> Descriptor {
+        Descriptor::UserSegment(DescriptorFlags::KERNEL_DATA.bits())
+    }
+
+    pub fn user_code_segment() -> Descriptor {
+        let flags = DescriptorFlags::KERNEL_CODE | DescriptorFlags::DPL_RING_3;
+
+        Descriptor::UserSegment(flags.bits())
+    }
+
+    pub fn user_data_segment() -> Descriptor {
+        let flags = DescriptorFlags::KERNEL_DATA | DescriptorFlags::DPL_RING_3;
 
         Descriptor::UserSegment(flags.bits())
     }
 
+    /// The privilege level (DPL) of the segment this describes.
+    fn privilege_level(&self) -> PrivilegeLevel {
+        use bit_field::BitField;
+
+        match *self {
+            Descriptor::UserSegment(value) => {
+                PrivilegeLevel::from_u16(value.get_bits(45..47) as u16)
+            }
+            Descriptor::SystemSegment(..) => PrivilegeLevel::Ring0,
+        }
+    }
+
     pub fn tss_segment(tss: &'static TaskStateSegment) -> Descriptor {
         use bit_field::BitField;
 
@@ -98,10 +124,16 @@ impl Descriptor {
 
 bitflags! {
     struct DescriptorFlags: u64 {
+        const WRITABLE      = 1 << 41;
         const CONFORMING    = 1 << 42;
         const EXECUTABLE    = 1 << 43;
         const USER_SEGMENT  = 1 << 44;
+        const DPL_RING_3    = 3 << 45;
         const PRESENT       = 1 << 47;
         const LONG_MODE     = 1 << 53;
+
+        const KERNEL_CODE   = Self::USER_SEGMENT.bits | Self::PRESENT.bits | Self::EXECUTABLE.bits
+                            | Self::LONG_MODE.bits;
+        const KERNEL_DATA   = Self::USER_SEGMENT.bits | Self::PRESENT.bits | Self::WRITABLE.bits;
     }
 }
@@ -0,0 +1,122 @@
+//! Dispatches legacy (PIC) IRQs to registered handlers.
+//!
+//! Every IRQ line starts out masked, and is unmasked when a handler is registered for it.
+//!
+//! A line can instead be [forward]ed, for a driver outside the kernel to handle: the dispatcher
+//! masks it on each interrupt, and it stays masked until the driver's dealt with its device and
+//! [unmask]s it again.
+//!
+//! The [cascade](../../device/pic/constant.CASCADE_IRQ.html) line, which the secondary PIC's IRQs
+//! arrive through, is [reserved](fn.is_reserved.html): it never gets a handler.
+
+use spin::RwLock;
+
+use arch::x86_64::device::pic::{CASCADE_IRQ, PICS};
+use super::{stats, without_interrupts, IRQ_BASE};
+
+/// How many IRQ lines the chained PICs have.
+pub const IRQ_COUNT: usize = 16;
+
+/// A function handling an IRQ. Called with the IRQ line number, with interrupts disabled.
+pub type IrqHandler = fn(irq: u8);
+
+#[derive(Clone, Copy)]
+enum Handler {
+    /// Handles the IRQ in the kernel.
+    Kernel(IrqHandler),
+    /// Passes word of the IRQ on, with the line masked.
+    Forward(IrqHandler),
+}
+
+static HANDLERS: RwLock<[Option<Handler>; IRQ_COUNT]> = RwLock::new([None; IRQ_COUNT]);
+
+/// Is IRQ line `irq` one that can't be given a handler? Only the cascade line is: masking it (as
+/// unregistering a handler would) cuts off IRQs 8 to 15.
+pub fn is_reserved(irq: u8) -> bool {
+    irq == CASCADE_IRQ
+}
+
+/// Registers `handler` for IRQ line `irq`, and unmasks the line.
+///
+/// # Panics
+/// If `irq` already has a handler, or is [reserved](fn.is_reserved.html).
+pub fn register(irq: u8, handler: IrqHandler) {
+    assert!(!is_reserved(irq), "irq: IRQ {} is reserved", irq);
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        assert!(
+            handlers[irq as usize].is_none(),
+            "irq: IRQ {} already has a handler",
+            irq
+        );
+
+        handlers[irq as usize] = Some(Handler::Kernel(handler));
+        unsafe { PICS.write().unmask_irq(irq) };
+    });
+}
+
+/// Has IRQ line `irq` forwarded: on each interrupt, the line's masked, then `notify` is called to
+/// pass word on. The line's left masked until it's [unmask]ed. Returns `false` if `irq` already
+/// has a handler (or there's no such line, or it's [reserved](fn.is_reserved.html)).
+pub fn forward(irq: u8, notify: IrqHandler) -> bool {
+    if is_reserved(irq) {
+        return false;
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        match handlers.get_mut(irq as usize) {
+            Some(handler) if handler.is_none() => {
+                *handler = Some(Handler::Forward(notify));
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Masks IRQ line `irq`, and removes its handler.
+pub fn unregister(irq: u8) {
+    without_interrupts(|| {
+        unsafe { PICS.write().mask_irq(irq) };
+        HANDLERS.write()[irq as usize] = None;
+    });
+}
+
+/// Masks IRQ line `irq`, without removing its handler.
+pub fn mask(irq: u8) {
+    without_interrupts(|| unsafe { PICS.write().mask_irq(irq) });
+}
+
+/// Unmasks IRQ line `irq`.
+pub fn unmask(irq: u8) {
+    without_interrupts(|| unsafe { PICS.write().unmask_irq(irq) });
+}
+
+/// Handles IRQ `irq`: weeds out spurious interrupts, runs the line's handler, and sends the EOI.
+pub(super) fn dispatch(irq: u8) {
+    let vector = IRQ_BASE + irq as usize;
+    let int = vector as u8;
+
+    if unsafe { PICS.write().handle_spurious(int) } {
+        stats::record_spurious(vector);
+        return;
+    }
+    let _timing = stats::time(vector);
+
+    let handler = HANDLERS.read()[irq as usize];
+    match handler {
+        Some(Handler::Kernel(handler)) => handler(irq),
+        Some(Handler::Forward(notify)) => {
+            // until whoever's handling it says its device has been dealt with
+            unsafe { PICS.write().mask_irq(irq) };
+            notify(irq);
+        }
+        None => {
+            // the line should've been masked; make sure it is now
+            warn!("irq: unhandled IRQ {}; masking it", irq);
+            unsafe { PICS.write().mask_irq(irq) };
+        }
+    }
+
+    unsafe { PICS.write().eoi(int) };
+}
@@ -1,85 +1,208 @@
 //! Controls Sparkle's IDT.
 
+use core::cell::UnsafeCell;
 use spin::Once;
 use x86_64::structures::gdt::SegmentSelector;
-use x86_64::structures::idt::{ExceptionStackFrame, InterruptDescriptorTable};
+use x86_64::structures::idt::{
+    ExceptionStackFrame, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode,
+};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
-use arch::x86_64::device::pic::PICS;
+use arch::x86_64::device::apic;
+use arch::x86_64::mca;
 use arch::x86_64::memory::paging::FrameAllocator;
 use arch::x86_64::memory::MemoryController;
+use arch::x86_64::usermode;
+use deferred;
+use task::process;
+use time::clockevent;
 
 pub use x86_64::instructions::interrupts::without_interrupts;
 
 mod gdt;
+pub mod irq;
+pub mod nmi;
+pub mod stats;
 
 use self::gdt::Gdt;
 
+// Exceptions which can arrive with a bad (or no) kernel stack get a known-good stack of their own,
+// through the TSS's interrupt stack table. Every stack has an unmapped guard page below it, so
+// overflowing one faults instead of silently scribbling on its neighbour. The CPU resets to the
+// top of the stack on every entry, so these handlers mustn't nest (a debug trap inside the debug
+// handler, say, would clobber the outer handler's frame), and mustn't switch threads either: a
+// thread switched away while on one would leave its frame there for the next exception to clobber.
+//
+// Page faults aren't among them, as a user thread's fault kills it (switching away), and arrives on
+// its own kernel stack anyway. A kernel stack overflow's page fault can't be pushed, so it becomes
+// a double fault, which does get a stack of its own.
 const IST_DOUBLE_FAULT: usize = 0;
+const IST_NMI: usize = 1;
+const IST_MACHINE_CHECK: usize = 2;
+const IST_DEBUG: usize = 3;
+
+/// Size of each IST stack, in pages, indexed by IST slot.
+const IST_STACK_PAGES: [usize; 4] = [2, 2, 2, 2];
 
 const IRQ_BASE: usize = 0x20;
-static INT_TIMER_PIT: usize = IRQ_BASE + 0x0;
+
+/// Interrupt vector the local APIC timer fires on.
+pub const INT_LAPIC_TIMER: usize = 0x40;
+/// Interrupt vector local APIC errors are reported on.
+pub const INT_LAPIC_ERROR: usize = 0xfe;
+/// Interrupt vector of the local APIC's spurious interrupts.
+pub const INT_LAPIC_SPURIOUS: usize = 0xff;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.divide_by_zero.set_handler_fn(divide_by_zero_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available
+            .set_handler_fn(device_not_available_handler);
+        idt.x87_floating_point
+            .set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point
+            .set_handler_fn(simd_floating_point_handler);
+        idt.segment_not_present
+            .set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault
+            .set_handler_fn(stack_segment_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(IST_DOUBLE_FAULT as u16);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(IST_NMI as u16);
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(IST_MACHINE_CHECK as u16);
+            idt.debug
+                .set_handler_fn(debug_handler)
+                .set_stack_index(IST_DEBUG as u16);
         }
 
-        idt[INT_TIMER_PIT].set_handler_fn(timer_handler);
+        for (irq, stub) in IRQ_STUBS.iter().enumerate() {
+            idt[IRQ_BASE + irq].set_handler_fn(*stub);
+        }
+        idt[INT_LAPIC_TIMER].set_handler_fn(lapic_timer_handler);
+        idt[INT_LAPIC_ERROR].set_handler_fn(lapic_error_handler);
+        idt[INT_LAPIC_SPURIOUS].set_handler_fn(lapic_spurious_handler);
 
         idt
     };
 }
 
-static TSS: Once<TaskStateSegment> = Once::new();
+/// The TSS. The CPU reads it behind our back, and [set_privilege_stack] writes it, hence the cell.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+static TSS: Once<Tss> = Once::new();
 static GDT: Once<Gdt> = Once::new();
+static SELECTORS: Once<Selectors> = Once::new();
+
+/// Selectors for the segments in our GDT.
+///
+/// # Notes
+/// `syscall` and `sysret` find segments relative to one another, which fixes their order: kernel
+/// code, kernel data, then user data, then user code.
+#[derive(Clone, Copy, Debug)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+/// Returns the selectors for our GDT's segments.
+///
+/// # Panics
+/// If called before [init].
+pub fn selectors() -> &'static Selectors {
+    SELECTORS.try().expect("int: GDT used before interrupts::init()")
+}
+
+/// Sets the stack the CPU switches to when an interrupt arrives in ring 3 (the TSS's RSP0).
+///
+/// # Safety
+/// `top` must be the top of a mapped kernel stack, which stays valid until this is next called.
+/// Must be called with interrupts off.
+pub unsafe fn set_privilege_stack(top: usize) {
+    let tss = TSS.try().expect("int: TSS used before interrupts::init()");
+    (*tss.0.get()).privilege_stack_table[0] = VirtAddr::new(top as u64);
+}
 
 /// Initializes the interrupt subsystem, using the passed `memory_controller` to allocate interrupt stacks.
 pub fn init<A: FrameAllocator>(memory_controller: &mut MemoryController<A>) {
-    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::segmentation::{load_ds, load_es, load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
-    let double_fault_stack = memory_controller
-        .alloc_stack(1)
-        .expect("could not allocate stack for double faulting");
-
-    let tss = TSS.call_once(|| {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[IST_DOUBLE_FAULT] =
-            VirtAddr::new(double_fault_stack.top() as u64);
+    let mut tss = TaskStateSegment::new();
+    for (index, &pages) in IST_STACK_PAGES.iter().enumerate() {
+        let stack = memory_controller
+            .alloc_stack(pages)
+            .expect("int: could not allocate interrupt stack");
+        tss.interrupt_stack_table[index] = VirtAddr::new(stack.top() as u64);
+    }
 
-        tss
-    });
+    let tss = unsafe { &*TSS.call_once(|| Tss(UnsafeCell::new(tss))).0.get() };
 
-    let mut code_selector = SegmentSelector(0);
-    let mut tss_selector = SegmentSelector(0);
+    let mut selectors = Selectors {
+        kernel_code: SegmentSelector(0),
+        kernel_data: SegmentSelector(0),
+        user_data: SegmentSelector(0),
+        user_code: SegmentSelector(0),
+        tss: SegmentSelector(0),
+    };
     let gdt = GDT.call_once(|| {
         let mut gdt = Gdt::new();
 
-        code_selector = gdt.add_entry(gdt::Descriptor::kernel_code_segment());
-        tss_selector = gdt.add_entry(gdt::Descriptor::tss_segment(tss));
+        // this order is required by syscall/sysret; see `Selectors`
+        selectors.kernel_code = gdt.add_entry(gdt::Descriptor::kernel_code_segment());
+        selectors.kernel_data = gdt.add_entry(gdt::Descriptor::kernel_data_segment());
+        selectors.user_data = gdt.add_entry(gdt::Descriptor::user_data_segment());
+        selectors.user_code = gdt.add_entry(gdt::Descriptor::user_code_segment());
+        selectors.tss = gdt.add_entry(gdt::Descriptor::tss_segment(tss));
 
         gdt
     });
     gdt.load();
+    SELECTORS.call_once(|| selectors);
 
     unsafe {
-        set_cs(code_selector);
-        load_tss(tss_selector);
+        set_cs(selectors.kernel_code);
+        load_ss(selectors.kernel_data);
+        load_ds(selectors.kernel_data);
+        load_es(selectors.kernel_data);
+        load_tss(selectors.tss);
     }
 
     IDT.load();
 }
 
+/// Ends the current thread if it's been killed and the interrupt's returning to user mode, where
+/// it's holding nothing. Called last thing by handlers that can return to user mode.
+fn returning(stack_frame: &ExceptionStackFrame) {
+    if usermode::from_user(stack_frame) {
+        ::task::exit_if_killed();
+    }
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
     println!("int[3]: trap breakpoint:\n{:#?}", stack_frame);
+    returning(stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
@@ -92,8 +215,138 @@ extern "x86-interrupt" fn double_fault_handler(
     loop {}
 }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: &mut ExceptionStackFrame) {
-    unsafe {
-        PICS.write().eoi(INT_TIMER_PIT as u8);
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut ExceptionStackFrame) {
+    nmi::handle(stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut ExceptionStackFrame) {
+    mca::handle_exception(stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut ExceptionStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let address = Cr2::read().as_u64();
+    if usermode::from_user(stack_frame) {
+        process::fault(
+            format_args!("page fault at {:#x}, {:?}", address, error_code),
+            stack_frame,
+        );
+    }
+    if usermode::fixup_fault(stack_frame) {
+        return;
     }
+
+    println!(
+        "int[14]: fault: page at {:#x} ({:?}):\n{:#?}",
+        address, error_code, stack_frame
+    );
+
+    #[cfg_attr(feature = "cargo-clippy", allow(empty_loop))]
+    loop {}
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: &mut ExceptionStackFrame) {
+    println!("int[1]: trap debug:\n{:#?}", stack_frame);
+}
+
+/// Generates handlers for exceptions which are fatal only to whatever caused them: a process at
+/// fault is killed, but a fault in the kernel is a bug.
+macro_rules! fault_handlers {
+    ($($name:ident => $vector:expr, $what:expr;)*) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+                if usermode::from_user(stack_frame) {
+                    process::fault(format_args!("{}", $what), stack_frame);
+                }
+                panic!("int[{}]: fault: {}:\n{:#?}", $vector, $what, stack_frame);
+            }
+        )*
+    };
+    ($($name:ident => $vector:expr, $what:expr, error code;)*) => {
+        $(
+            extern "x86-interrupt" fn $name(
+                stack_frame: &mut ExceptionStackFrame,
+                error_code: u64,
+            ) {
+                if usermode::from_user(stack_frame) {
+                    process::fault(
+                        format_args!("{}, error code {:#x}", $what, error_code),
+                        stack_frame,
+                    );
+                }
+                panic!(
+                    "int[{}]: fault: {} ({:#x}):\n{:#?}",
+                    $vector, $what, error_code, stack_frame
+                );
+            }
+        )*
+    };
+}
+
+fault_handlers!(
+    divide_by_zero_handler => 0, "divide by zero";
+    overflow_handler => 4, "overflow";
+    bound_range_handler => 5, "bound range exceeded";
+    invalid_opcode_handler => 6, "invalid opcode";
+    device_not_available_handler => 7, "device not available";
+    x87_floating_point_handler => 16, "x87 floating point";
+    simd_floating_point_handler => 19, "SIMD floating point";
+);
+
+fault_handlers!(
+    segment_not_present_handler => 11, "segment not present", error code;
+    stack_segment_handler => 12, "stack segment", error code;
+    general_protection_handler => 13, "general protection", error code;
+    alignment_check_handler => 17, "alignment check", error code;
+);
+
+/// Generates an interrupt handler for each PIC IRQ line, which hands off to [irq::dispatch].
+macro_rules! irq_stubs {
+    ($($name:ident => $irq:expr),*) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: &mut ExceptionStackFrame) {
+                deferred::irq_enter();
+                irq::dispatch($irq);
+                deferred::irq_exit();
+                returning(stack_frame);
+            }
+        )*
+
+        const IRQ_STUBS: [HandlerFunc; irq::IRQ_COUNT] = [$($name),*];
+    };
+}
+
+irq_stubs!(
+    irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3, irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7,
+    irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11, irq12 => 12, irq13 => 13, irq14 => 14,
+    irq15 => 15
+);
+
+extern "x86-interrupt" fn lapic_timer_handler(stack_frame: &mut ExceptionStackFrame) {
+    deferred::irq_enter();
+    {
+        let _timing = stats::time(INT_LAPIC_TIMER);
+        clockevent::handle_tick();
+
+        apic::local_apic().eoi();
+    }
+    deferred::irq_exit();
+    returning(stack_frame);
+}
+
+extern "x86-interrupt" fn lapic_error_handler(_stack_frame: &mut ExceptionStackFrame) {
+    let _timing = stats::time(INT_LAPIC_ERROR);
+    let lapic = apic::local_apic();
+    warn!("int[{:#x}]: apic error: esr={:#x}", INT_LAPIC_ERROR, lapic.error_status());
+
+    lapic.eoi();
+}
+
+extern "x86-interrupt" fn lapic_spurious_handler(_stack_frame: &mut ExceptionStackFrame) {
+    // spurious interrupts aren't in service, so they mustn't be acknowledged
+    stats::record_spurious(INT_LAPIC_SPURIOUS);
 }
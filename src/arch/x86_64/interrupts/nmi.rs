@@ -0,0 +1,112 @@
+//! Non-maskable interrupts.
+//!
+//! An NMI doesn't say where it came from, so every possible source is checked: the hard-lockup
+//! [watchdog](../../watchdog/index.html)'s performance counter, and the chipset's error and
+//! watchdog bits in the system control ports. Several may be pending at once.
+//!
+//! NMIs arrive whatever the interrupt flag says, so the interrupted code may be holding any lock
+//! at all; NMI handlers have to stick to atomics, and report through [print].
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+use arch::x86_64::device::serial::COM1;
+use arch::x86_64::watchdog;
+use super::stats;
+
+const INT_NMI: usize = 2;
+
+// bits of system control port B (0x61)
+const PORT_B_SERR_DISABLE: u8 = 1 << 2;
+const PORT_B_IOCHK_DISABLE: u8 = 1 << 3;
+const PORT_B_IOCHK: u8 = 1 << 6;
+const PORT_B_SERR: u8 = 1 << 7;
+/// The writable bits of port B; the rest are status.
+const PORT_B_WRITABLE: u8 = 0x0f;
+
+// bits of system control port A (0x92)
+const PORT_A_WATCHDOG: u8 = 1 << 4;
+
+/// Where an NMI came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NmiSource {
+    /// The hard-lockup watchdog's performance counter overflowed.
+    PerfCounter,
+    /// A system error (`SERR#`), typically a memory parity error.
+    SystemError,
+    /// An I/O channel check (`IOCHK#`): some expansion card reported an error.
+    IoCheck,
+    /// The chipset's watchdog timer expired.
+    ChipsetWatchdog,
+}
+
+/// Writes to COM1, from a context where the port's lock may be held by the code we interrupted.
+///
+/// If the lock is taken, it's broken, on the assumption that its holder is stuck (or is us).
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let mut port = match COM1.try_write() {
+        Some(port) => port,
+        None => unsafe {
+            COM1.force_write_unlock();
+            COM1.write()
+        },
+    };
+    let _ = port.write_fmt(args);
+}
+
+/// Checks the system control ports for NMI sources, and clears the ones that are set.
+fn check_ports(mut report: impl FnMut(NmiSource)) {
+    let mut port_a: Port<u8> = Port::new(0x92);
+    let mut port_b: Port<u8> = Port::new(0x61);
+
+    unsafe {
+        let status = port_b.read();
+        let control = status & PORT_B_WRITABLE;
+        if status & PORT_B_SERR != 0 {
+            report(NmiSource::SystemError);
+            // toggling the disable bit clears the latch
+            port_b.write(control | PORT_B_SERR_DISABLE);
+            port_b.write(control);
+        }
+        if status & PORT_B_IOCHK != 0 {
+            report(NmiSource::IoCheck);
+            port_b.write(control | PORT_B_IOCHK_DISABLE);
+            port_b.write(control);
+        }
+
+        let status = port_a.read();
+        if status & PORT_A_WATCHDOG != 0 {
+            report(NmiSource::ChipsetWatchdog);
+            port_a.write(status & !PORT_A_WATCHDOG);
+        }
+    }
+}
+
+/// Handles an NMI which interrupted `stack_frame`.
+pub(super) fn handle(stack_frame: &ExceptionStackFrame) {
+    let _timing = stats::time(INT_NMI);
+    let mut handled = false;
+
+    if watchdog::handle_nmi(stack_frame) {
+        handled = true;
+    }
+
+    check_ports(|source| {
+        handled = true;
+        print(format_args!(
+            "int[2]: nmi: {:?} at {:#x}\n",
+            source,
+            stack_frame.instruction_pointer.as_u64()
+        ));
+    });
+
+    if !handled {
+        print(format_args!(
+            "int[2]: nmi: unknown source:\n{:#?}\n",
+            stack_frame
+        ));
+    }
+}
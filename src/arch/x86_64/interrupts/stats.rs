@@ -0,0 +1,178 @@
+//! Per-vector interrupt statistics: how often each vector fires, and how long its handler takes.
+//!
+//! Handler time is measured in TSC cycles, from entry to the dispatcher until just before
+//! returning (so it includes sending the EOI).
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arch::x86_64::device::serial::COM1;
+use arch::x86_64::tsc;
+use super::{without_interrupts, INT_LAPIC_ERROR, INT_LAPIC_SPURIOUS, INT_LAPIC_TIMER, IRQ_BASE};
+
+const VECTOR_COUNT: usize = 256;
+
+/// Counters for a single vector.
+struct Counters {
+    count: AtomicU64,
+    spurious: AtomicU64,
+    cycles: AtomicU64,
+    max_cycles: AtomicU64,
+}
+
+lazy_static! {
+    // (atomics are valid when zeroed, and `Default` isn't implemented for arrays this long)
+    static ref COUNTERS: [Counters; VECTOR_COUNT] = unsafe { mem::zeroed() };
+}
+
+/// Times a handler for `vector`, recording it when dropped.
+pub struct Timing {
+    vector: u8,
+    start: u64,
+}
+
+impl Drop for Timing {
+    fn drop(&mut self) {
+        let cycles = tsc::read().wrapping_sub(self.start);
+        let counters = &COUNTERS[self.vector as usize];
+
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        counters.cycles.fetch_add(cycles, Ordering::Relaxed);
+
+        let mut max = counters.max_cycles.load(Ordering::Relaxed);
+        while cycles > max {
+            let prev = counters
+                .max_cycles
+                .compare_and_swap(max, cycles, Ordering::Relaxed);
+            if prev == max {
+                break;
+            }
+            max = prev;
+        }
+    }
+}
+
+/// Starts timing a handler for `vector`. The interrupt is counted when the returned [Timing] is
+/// dropped, so bind it to a variable which lives until the end of the handler.
+pub fn time(vector: usize) -> Timing {
+    Timing {
+        vector: vector as u8,
+        start: tsc::read(),
+    }
+}
+
+/// Counts a spurious interrupt on `vector`.
+pub fn record_spurious(vector: usize) {
+    COUNTERS[vector].spurious.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A point-in-time copy of one vector's counters.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VectorStats {
+    /// The interrupt vector.
+    pub vector: u8,
+    /// How many times the vector's handler has run.
+    pub count: u64,
+    /// How many spurious interrupts arrived on the vector (these aren't included in `count`).
+    pub spurious: u64,
+    /// Total TSC cycles spent in the vector's handler.
+    pub cycles: u64,
+    /// The longest the vector's handler has taken, in TSC cycles.
+    pub max_cycles: u64,
+}
+
+impl VectorStats {
+    /// Average TSC cycles the vector's handler takes.
+    pub fn avg_cycles(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.cycles / self.count
+        }
+    }
+}
+
+/// A copy of the counters of every vector which has seen an interrupt.
+///
+/// Formats as a `/proc/interrupts`-style table.
+pub struct Snapshot(pub Vec<VectorStats>);
+
+/// Copies the counters of every vector which has seen an interrupt.
+///
+/// The copy isn't atomic across vectors (or across one vector's counters), as handlers keep
+/// running while it's taken.
+pub fn snapshot() -> Snapshot {
+    Snapshot(
+        COUNTERS
+            .iter()
+            .enumerate()
+            .map(|(vector, c)| VectorStats {
+                vector: vector as u8,
+                count: c.count.load(Ordering::Relaxed),
+                spurious: c.spurious.load(Ordering::Relaxed),
+                cycles: c.cycles.load(Ordering::Relaxed),
+                max_cycles: c.max_cycles.load(Ordering::Relaxed),
+            })
+            .filter(|s| s.count != 0 || s.spurious != 0)
+            .collect(),
+    )
+}
+
+/// Describes what's on `vector`.
+fn describe(vector: usize, f: &mut fmt::Formatter) -> fmt::Result {
+    const EXCEPTIONS: [&str; 21] = [
+        "divide error", "debug", "nmi", "breakpoint", "overflow", "bound range", "invalid opcode",
+        "device not available", "double fault", "coprocessor overrun", "invalid tss",
+        "segment not present", "stack fault", "general protection", "page fault", "reserved",
+        "x87 fp", "alignment check", "machine check", "simd fp", "virtualization",
+    ];
+
+    match vector {
+        v if v < EXCEPTIONS.len() => write!(f, "{}", EXCEPTIONS[v]),
+        v if v >= IRQ_BASE && v < IRQ_BASE + super::irq::IRQ_COUNT => {
+            write!(f, "pic irq {}", v - IRQ_BASE)
+        }
+        INT_LAPIC_TIMER => write!(f, "lapic timer"),
+        INT_LAPIC_ERROR => write!(f, "lapic error"),
+        INT_LAPIC_SPURIOUS => write!(f, "lapic spurious"),
+        _ => Ok(()),
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>6} {:>12} {:>9} {:>12} {:>12}  {}",
+            "vector", "count", "spurious", "avg cycles", "max cycles", "source"
+        )?;
+
+        for s in &self.0 {
+            write!(
+                f,
+                "{:>#6x} {:>12} {:>9} {:>12} {:>12}  ",
+                s.vector,
+                s.count,
+                s.spurious,
+                s.avg_cycles(),
+                s.max_cycles
+            )?;
+            describe(s.vector as usize, f)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Prints a table of interrupt statistics over serial.
+pub fn dump() {
+    use core::fmt::Write;
+
+    let snapshot = snapshot();
+    without_interrupts(|| {
+        let _ = write!(COM1.write(), "{}", snapshot);
+    });
+}
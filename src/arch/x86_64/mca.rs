@@ -0,0 +1,324 @@
+//! Machine-check architecture: reporting of hardware errors.
+//!
+//! The CPU has a number of error-reporting *banks*, each covering some piece of hardware (a cache,
+//! the memory controller, the bus...). Uncorrected errors raise a machine-check exception (#MC),
+//! which decides whether we can carry on or have to panic; corrected errors are just logged in
+//! their bank, and picked up by polling every [POLL_SECS].
+//!
+//! QEMU's monitor can inject errors to test this with, *e.g.* `mce 0 1 0xbd00000000000175 0 0 0`
+//! (an uncorrected, action-optional error in bank 1 of CPU 0).
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+use arch::x86_64::bits;
+use arch::x86_64::interrupts::nmi;
+use time::{timer, Duration};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MCG_CTL: u32 = 0x17b;
+const IA32_MC0_CTL: u32 = 0x400;
+
+// IA32_MCG_CAP bits
+const MCG_COUNT_MASK: u64 = 0xff;
+const MCG_CTL_P: u64 = 1 << 8;
+const MCG_SER_P: u64 = 1 << 24;
+
+// IA32_MCG_STATUS bits
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+
+// IA32_MCi_STATUS bits
+const STATUS_VAL: u64 = 1 << 63;
+const STATUS_OVER: u64 = 1 << 62;
+const STATUS_UC: u64 = 1 << 61;
+const STATUS_EN: u64 = 1 << 60;
+const STATUS_MISCV: u64 = 1 << 59;
+const STATUS_ADDRV: u64 = 1 << 58;
+const STATUS_PCC: u64 = 1 << 57;
+const STATUS_S: u64 = 1 << 56;
+const STATUS_AR: u64 = 1 << 55;
+
+/// How often to poll for corrected errors, in seconds.
+pub const POLL_SECS: u64 = 5;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// How many banks this CPU has.
+static BANKS: AtomicUsize = AtomicUsize::new(0);
+/// Does the CPU support software error recovery (the `S` and `AR` status bits)?
+static RECOVERY: AtomicBool = AtomicBool::new(false);
+
+fn ctl_msr(bank: usize) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank as u32)
+}
+
+fn status_msr(bank: usize) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank as u32 + 1)
+}
+
+fn addr_msr(bank: usize) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank as u32 + 2)
+}
+
+fn misc_msr(bank: usize) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank as u32 + 3)
+}
+
+/// How bad an error is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The hardware fixed it; we just log it.
+    Corrected,
+    /// The error wasn't corrected, but hasn't been consumed either (*e.g.* a bad line found by a
+    /// memory scrub), so execution can carry on.
+    Deferred,
+    /// The error can't be recovered from.
+    Fatal,
+}
+
+/// An error logged in one of the machine-check banks.
+#[derive(Clone, Copy, Debug)]
+pub struct BankError {
+    /// The bank the error was logged in.
+    pub bank: usize,
+    /// The bank's `IA32_MCi_STATUS`.
+    pub status: u64,
+    /// The bank's `IA32_MCi_ADDR`, if valid.
+    pub addr: Option<u64>,
+    /// The bank's `IA32_MCi_MISC`, if valid.
+    pub misc: Option<u64>,
+}
+
+impl BankError {
+    /// Reads bank `bank`, returning its error if it has one.
+    unsafe fn read(bank: usize) -> Option<BankError> {
+        let status = status_msr(bank).read();
+        if status & STATUS_VAL == 0 {
+            return None;
+        }
+
+        Some(BankError {
+            bank,
+            status,
+            addr: if status & STATUS_ADDRV != 0 {
+                Some(addr_msr(bank).read())
+            } else {
+                None
+            },
+            misc: if status & STATUS_MISCV != 0 {
+                Some(misc_msr(bank).read())
+            } else {
+                None
+            },
+        })
+    }
+
+    /// The architectural MCA error code.
+    pub fn error_code(&self) -> u16 {
+        self.status as u16
+    }
+
+    /// The model-specific error code.
+    pub fn model_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+
+    /// Decides how bad the error is.
+    ///
+    /// `mcg_status` is `IA32_MCG_STATUS` at the time of the exception, or `None` if the error was
+    /// found by polling.
+    pub fn severity(&self, mcg_status: Option<u64>) -> Severity {
+        let status = self.status;
+
+        if status & STATUS_UC == 0 {
+            return Severity::Corrected;
+        }
+        if status & (STATUS_PCC | STATUS_OVER) != 0 {
+            // the processor's state is corrupt, or we've lost track of an earlier error
+            return Severity::Fatal;
+        }
+        if mcg_status.map_or(false, |mcg| mcg & MCG_STATUS_RIPV == 0) {
+            // there's nowhere to safely return to
+            return Severity::Fatal;
+        }
+        if !RECOVERY.load(Ordering::Relaxed) || status & STATUS_AR != 0 {
+            // the interrupted code consumed the bad data; there's no process to kill yet, so all
+            // we can do is stop
+            return Severity::Fatal;
+        }
+
+        Severity::Deferred
+    }
+
+    /// Describes the architectural error code.
+    fn describe(&self) -> &'static str {
+        match self.error_code() {
+            0x0000 => "no error",
+            0x0001 => "unclassified",
+            0x0002 => "microcode ROM parity",
+            0x0003 => "external",
+            0x0004 => "FRC",
+            0x0005 => "internal parity",
+            0x0400 => "internal timer",
+            c if c & 0xfffc == 0x000c => "generic cache hierarchy",
+            c if c & 0xfff0 == 0x0010 => "TLB",
+            c if c & 0xff80 == 0x0080 => "memory controller",
+            c if c & 0xff00 == 0x0100 => "cache hierarchy",
+            c if c & 0xf800 == 0x0800 => "bus/interconnect",
+            c if c & 0xfc00 == 0x0400 => "internal unclassified",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const FLAGS: [(u64, &str); 7] = [
+            (STATUS_OVER, " OVER"),
+            (STATUS_UC, " UC"),
+            (STATUS_EN, " EN"),
+            (STATUS_PCC, " PCC"),
+            (STATUS_S, " S"),
+            (STATUS_AR, " AR"),
+            (STATUS_ADDRV, " ADDRV"),
+        ];
+
+        write!(
+            f,
+            "bank {}: {} error (code {:#06x}, model {:#06x}), status {:#018x}",
+            self.bank,
+            self.describe(),
+            self.error_code(),
+            self.model_code(),
+            self.status
+        )?;
+        for &(flag, name) in FLAGS.iter() {
+            if self.status & flag != 0 {
+                f.write_str(name)?;
+            }
+        }
+        if let Some(addr) = self.addr {
+            write!(f, ", addr {:#x}", addr)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {:#x}", misc)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Is machine-check reporting turned on?
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Handles a machine-check exception which interrupted `stack_frame`; panics if it's fatal.
+///
+/// Called from the #MC handler.
+pub fn handle_exception(stack_frame: &ExceptionStackFrame) {
+    let mut mcg_status = Msr::new(IA32_MCG_STATUS);
+    let mcg = unsafe { mcg_status.read() };
+    let mut worst = None;
+
+    for bank in 0..BANKS.load(Ordering::Relaxed) {
+        if let Some(error) = unsafe { BankError::read(bank) } {
+            let severity = error.severity(Some(mcg));
+            nmi::print(format_args!("mca: {:?}: {}\n", severity, error));
+            worst = worst.max(Some(severity));
+
+            unsafe { status_msr(bank).write(0) };
+        }
+    }
+
+    let worst = match worst {
+        Some(worst) => worst,
+        None => {
+            nmi::print(format_args!("mca: machine check with no error logged\n"));
+            Severity::Fatal
+        }
+    };
+    if worst == Severity::Fatal {
+        panic!(
+            "mca: unrecoverable machine check at {:#x} (rip {}valid)",
+            stack_frame.instruction_pointer.as_u64(),
+            if mcg & MCG_STATUS_EIPV != 0 { "" } else { "not " }
+        );
+    }
+
+    // clearing MCIP lets another #MC be delivered, rather than shutting the machine down
+    unsafe { mcg_status.write(0) };
+}
+
+/// Logs (and clears) any corrected errors sitting in the banks.
+pub fn poll() {
+    log_banks(false);
+}
+
+/// Logs and clears the banks' errors; uncorrected ones too, if `uncorrected`.
+fn log_banks(uncorrected: bool) {
+    for bank in 0..BANKS.load(Ordering::Relaxed) {
+        if let Some(error) = unsafe { BankError::read(bank) } {
+            // outside of init, uncorrected errors are the #MC handler's; leave them for it
+            if error.status & STATUS_UC != 0 && !uncorrected {
+                continue;
+            }
+
+            warn!("mca: {:?}: {}", error.severity(None), error);
+            unsafe { status_msr(bank).write(0) };
+        }
+    }
+}
+
+/// Enables machine-check exceptions and every error-reporting bank on the current CPU, logging
+/// any errors left over from before boot.
+///
+/// Returns `false` if the CPU doesn't support the machine-check architecture.
+pub fn init() -> bool {
+    assert_first_call!("mca::init() can only be called once!");
+
+    let supported = CpuId::new()
+        .get_feature_info()
+        .map_or(false, |f| f.has_mce() && f.has_mca());
+    if !supported {
+        info!("mca: not supported");
+        return false;
+    }
+
+    let cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+    let banks = (cap & MCG_COUNT_MASK) as usize;
+    BANKS.store(banks, Ordering::Relaxed);
+    RECOVERY.store(cap & MCG_SER_P != 0, Ordering::Relaxed);
+
+    // anything logged now happened before we booted (or caused a reset)
+    log_banks(true);
+
+    unsafe {
+        if cap & MCG_CTL_P != 0 {
+            Msr::new(IA32_MCG_CTL).write(u64::max_value());
+        }
+        for bank in 0..banks {
+            ctl_msr(bank).write(u64::max_value());
+            status_msr(bank).write(0);
+        }
+    }
+
+    bits::enable_mce();
+    timer::periodic(Duration::from_secs(POLL_SECS), |_| poll(), 0);
+    ENABLED.store(true, Ordering::Relaxed);
+
+    info!(
+        "mca: {} banks{}",
+        banks,
+        if cap & MCG_SER_P != 0 {
+            ", software error recovery"
+        } else {
+            ""
+        }
+    );
+    true
+}
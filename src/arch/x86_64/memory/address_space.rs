@@ -0,0 +1,222 @@
+//! Address spaces for user processes.
+//!
+//! Every address space shares the kernel's mappings: P4 entry 0 (the identity-mapped kernel and
+//! devices, the heap, and kernel stacks) points at the same P3 table in all of them, so kernel
+//! mappings made while one space is active show up in every other. User mappings live in
+//! [[USER_START], [USER_END]), P4 entries 1 to 255, and are private to their space. Entry 511 is,
+//! as ever, each table's recursive mapping.
+
+use core::ptr;
+
+use super::paging::table::EntryFlags;
+use super::paging::VirtualAddress;
+use super::paging::{self, Frame, FrameAllocator, InactivePageTable, Page, PhysicalAddress};
+use super::{with_controller, AreaFrameAllocator, MemoryController};
+
+/// The lowest user address.
+pub const USER_START: VirtualAddress = 0x0000_0080_0000_0000;
+/// One past the highest user address. The last page below the non-canonical hole is left out, so
+/// that no user code can end right at it: a `syscall` there would `sysret` to a non-canonical
+/// address, which faults in ring 0, on the user's stack.
+pub const USER_END: VirtualAddress = 0x0000_7fff_ffff_f000;
+
+/// Why an [AddressSpace] operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapError {
+    /// The range isn't (entirely) within [[USER_START], [USER_END]).
+    OutOfRange,
+    /// Part of the range is already mapped.
+    AlreadyMapped,
+    /// Part of the range isn't mapped.
+    NotMapped,
+    /// There are no free frames left.
+    OutOfMemory,
+}
+
+/// The pages covering the `size` bytes from `start`, if they're all user pages.
+fn user_pages(start: VirtualAddress, size: usize) -> Result<(Page, Page), MapError> {
+    let end = start.checked_add(size).ok_or(MapError::OutOfRange)?;
+    if size == 0 || start < USER_START || end > USER_END {
+        return Err(MapError::OutOfRange);
+    }
+
+    Ok((
+        Page::containing_address(start),
+        Page::containing_address(end - 1),
+    ))
+}
+
+/// A user address space: a P4 table sharing the kernel's mappings, plus whatever's been mapped
+/// into the user range.
+///
+/// Nothing in an address space is freed when it's dropped (frames can't be, yet).
+#[derive(Debug)]
+pub struct AddressSpace {
+    p4: PhysicalAddress,
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in the user range.
+    ///
+    /// # Panics
+    /// If there are no frames left for its P4 table.
+    pub fn new() -> AddressSpace {
+        with_controller(|ctrl| {
+            let frame = ctrl
+                .frame_allocator
+                .alloc_frame()
+                .expect("memory: no frames left for an address space");
+            let table =
+                InactivePageTable::new(frame, &mut ctrl.active_table, &mut ctrl.temporary_page);
+            let p4 = table.p4_address();
+
+            // share the kernel's half
+            let (kernel_frame, kernel_flags) = {
+                let entry = &ctrl.active_table.p4()[0];
+                (entry.pointed_frame().unwrap(), entry.flags())
+            };
+            {
+                let table = ctrl
+                    .temporary_page
+                    .map_table_frame(Frame::containing_address(p4), &mut ctrl.active_table);
+                table[0].set(kernel_frame, kernel_flags);
+            }
+            ctrl.temporary_page.unmap(&mut ctrl.active_table);
+
+            AddressSpace { p4 }
+        })
+    }
+
+    /// Is this the active address space?
+    pub fn is_active(&self) -> bool {
+        paging::active_p4_address() == self.p4
+    }
+
+    /// Makes this the active address space.
+    ///
+    /// # Safety
+    /// Whatever's running must not be relying on the previous space's user mappings. Should be
+    /// called with interrupts disabled.
+    pub unsafe fn activate(&self) {
+        if !self.is_active() {
+            paging::load_p4(self.p4);
+        }
+    }
+
+    /// Runs `f` on the memory controller, with this address space active (and interrupts off),
+    /// switching back afterwards.
+    fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut MemoryController<AreaFrameAllocator<'static>>) -> R,
+    {
+        with_controller(|ctrl| {
+            let old = paging::active_p4_address();
+            unsafe { self.activate() };
+
+            let result = f(ctrl);
+
+            if old != self.p4 {
+                unsafe { paging::load_p4(old) };
+            }
+            result
+        })
+    }
+
+    /// Maps fresh, zeroed memory over the `size` bytes from `start` (rounded out to whole pages),
+    /// for user code to access with `flags`.
+    pub fn map(
+        &self,
+        start: VirtualAddress,
+        size: usize,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        let (first, last) = user_pages(start, size)?;
+
+        self.with(|ctrl| {
+            if Page::range_inclusive(first, last)
+                .any(|page| ctrl.active_table.page_to_frame(page).is_some())
+            {
+                return Err(MapError::AlreadyMapped);
+            }
+
+            for page in Page::range_inclusive(first, last) {
+                let frame = ctrl
+                    .frame_allocator
+                    .alloc_frame()
+                    .ok_or(MapError::OutOfMemory)?;
+                // writable to begin with, so that we can zero it
+                ctrl.active_table.map_to(
+                    page,
+                    frame,
+                    EntryFlags::WRITABLE | EntryFlags::USER_ACCESSIBLE,
+                    &mut ctrl.frame_allocator,
+                );
+                unsafe { ptr::write_bytes(page.start_address() as *mut u8, 0, Frame::SIZE) };
+                ctrl.active_table
+                    .set_flags(page, flags | EntryFlags::USER_ACCESSIBLE);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Changes the flags user code accesses the (mapped) pages covering the `size` bytes from
+    /// `start` with.
+    pub fn protect(
+        &self,
+        start: VirtualAddress,
+        size: usize,
+        flags: EntryFlags,
+    ) -> Result<(), MapError> {
+        let (first, last) = user_pages(start, size)?;
+
+        self.with(|ctrl| {
+            if Page::range_inclusive(first, last)
+                .any(|page| ctrl.active_table.page_to_frame(page).is_none())
+            {
+                return Err(MapError::NotMapped);
+            }
+
+            for page in Page::range_inclusive(first, last) {
+                ctrl.active_table
+                    .set_flags(page, flags | EntryFlags::USER_ACCESSIBLE);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Copies `data` into this address space at `addr`, whatever the pages' protection. The
+    /// pages must already be mapped.
+    pub fn write(&self, addr: VirtualAddress, data: &[u8]) -> Result<(), MapError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        user_pages(addr, data.len())?;
+
+        self.with(|ctrl| {
+            let mut addr = addr;
+            let mut data = data;
+            while !data.is_empty() {
+                let frame = ctrl
+                    .active_table
+                    .page_to_frame(Page::containing_address(addr))
+                    .ok_or(MapError::NotMapped)?;
+                let offset = addr % Frame::SIZE;
+                let len = ::core::cmp::min(Frame::SIZE - offset, data.len());
+
+                // go through the temporary page, which is always writable by the kernel
+                let window = ctrl.temporary_page.map(frame, &mut ctrl.active_table);
+                unsafe {
+                    ptr::copy_nonoverlapping(data.as_ptr(), (window + offset) as *mut u8, len);
+                }
+                ctrl.temporary_page.unmap(&mut ctrl.active_table);
+
+                addr += len;
+                data = &data[len..];
+            }
+
+            Ok(())
+        })
+    }
+}
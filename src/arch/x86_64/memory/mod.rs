@@ -2,18 +2,36 @@
 //!
 //! Heavly inspired/lovingly ripped off from Phil Oppermann's [os.phil-opp.com](http://os.phil-opp.com/).
 
+mod address_space;
 pub(crate) mod paging;
 mod stack_allocator;
 
 use alloca;
 use arch::x86_64;
+use arch::x86_64::interrupts::without_interrupts;
+use core::cmp;
 use multiboot2::BootInformation;
+use spin::Mutex;
 
 use self::paging::frame_allocators::AreaFrameAllocator;
-use self::paging::{table, ActivePageTable, Frame, FrameAllocator, Page};
+use self::paging::table::EntryFlags;
+use self::paging::{
+    table, ActivePageTable, Frame, FrameAllocator, Page, PhysicalAddress, TemporaryPage,
+};
 
+pub use self::address_space::{AddressSpace, MapError, USER_END, USER_START};
 pub use self::stack_allocator::{Stack, StackAllocator};
 
+/// How many pages of address space to set aside for kernel stacks (guard pages included).
+const STACK_AREA_PAGES: usize = 4096;
+
+/// A page for briefly mapping frames which aren't otherwise mapped (new page tables, say): the
+/// last one of the gigabyte the heap and kernel stacks live in.
+const TEMPORARY_PAGE: usize = 0o_000_001_777_777_0000;
+
+/// The memory controller, once boot is done with it (see [install]).
+static CONTROLLER: Mutex<Option<MemoryController<AreaFrameAllocator<'static>>>> = Mutex::new(None);
+
 /// Owns the active table, the frame allocator, and the stack allocator.
 pub struct MemoryController<A>
 where
@@ -22,6 +40,7 @@ where
     active_table: ActivePageTable,
     frame_allocator: A,
     stack_allocator: StackAllocator,
+    temporary_page: TemporaryPage,
 }
 
 impl<A> MemoryController<A>
@@ -35,6 +54,71 @@ where
         self.stack_allocator
             .alloc_stack(&mut self.active_table, &mut self.frame_allocator, size)
     }
+
+    /// Frees a stack [alloc_stack](#method.alloc_stack) returned, for reuse.
+    pub fn free_stack(&mut self, stack: Stack) {
+        self.stack_allocator.free_stack(stack);
+    }
+
+    /// Identity-maps the physical range `[start, start + size)` with `flags`, for accessing
+    /// memory-mapped devices and firmware tables. Pages which are already mapped are left alone.
+    pub fn identity_map_range(&mut self, start: PhysicalAddress, size: usize, flags: EntryFlags) {
+        let start_frame = Frame::containing_address(start);
+        let end_frame = Frame::containing_address(start + size - 1);
+
+        for frame in Frame::range_inclusive(start_frame, end_frame) {
+            if self.active_table.translate(frame.start_address()).is_none() {
+                self.active_table
+                    .identity_map(frame, flags, &mut self.frame_allocator);
+            }
+        }
+    }
+}
+
+// The controller holds raw pointers (the active table, and the frame allocator's view of the
+// memory map), so isn't `Send` by default; but there's only the one address space, and the
+// controller is only ever used from behind [CONTROLLER]'s lock.
+unsafe impl<A> Send for MemoryController<A> where A: FrameAllocator {}
+
+/// Hands the boot-time memory controller over to the kernel, for [alloc_stack] and friends.
+pub fn install(memory_controller: MemoryController<AreaFrameAllocator<'static>>) {
+    assert_first_call!("memory::install() can only be called once!");
+
+    without_interrupts(|| *CONTROLLER.lock() = Some(memory_controller));
+}
+
+/// Runs `f` with the [install]ed controller.
+///
+/// # Panics
+/// If the controller hasn't been installed.
+fn with_controller<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut MemoryController<AreaFrameAllocator<'static>>) -> R,
+{
+    without_interrupts(|| {
+        f(CONTROLLER
+            .lock()
+            .as_mut()
+            .expect("memory: controller not installed"))
+    })
+}
+
+/// Allocates a `size`-page stack, behind a guard page, from the [install]ed controller. Returns
+/// `None` if there's no room left for one.
+///
+/// # Panics
+/// If the controller hasn't been installed.
+pub fn alloc_stack(size: usize) -> Option<Stack> {
+    with_controller(|controller| controller.alloc_stack(size))
+}
+
+/// Frees `stack`, which came from [alloc_stack] and which nothing's running on any more, for
+/// reuse. Its memory stays mapped, as frames can't be freed yet.
+///
+/// # Panics
+/// If the controller hasn't been installed.
+pub fn free_stack(stack: Stack) {
+    with_controller(|controller| controller.free_stack(stack))
 }
 
 /// Initializes the memory subsystem, returning a [MemoryController] owning everything we set up.
@@ -80,11 +164,26 @@ pub fn init<'a>(boot_info: &'a BootInformation) -> MemoryController<AreaFrameAll
         boot_info.end_address()
     );
 
+    // boot modules (the program to start first, say) stay where the boot loader put them
+    let modules = boot_info
+        .module_tags()
+        .filter(|m| m.end_address() > m.start_address())
+        .map(|m| (m.start_address() as usize, m.end_address() as usize - 1))
+        .fold(None, |range: Option<(usize, usize)>, (start, end)| {
+            Some(range.map_or((start, end), |(s, e)| {
+                (cmp::min(s, start), cmp::max(e, end))
+            }))
+        });
+    if let Some((start, end)) = modules {
+        debug!("modules start: {:#x}, modules end: {:#x}", start, end);
+    }
+
     let mut frame_allocator = AreaFrameAllocator::new(
         kernel_start as usize,
         kernel_end as usize,
         boot_info.start_address(),
         boot_info.end_address(),
+        modules,
         memory_map_tag.memory_areas(),
     );
 
@@ -111,15 +210,21 @@ pub fn init<'a>(boot_info: &'a BootInformation) -> MemoryController<AreaFrameAll
 
     let stack_allocator = {
         let alloc_start = heap_end_page + 1;
-        let alloc_end = alloc_start + 100;
+        let alloc_end = alloc_start + STACK_AREA_PAGES;
         let alloc_range = Page::range_inclusive(alloc_start, alloc_end);
 
         StackAllocator::new(alloc_range)
     };
 
+    let temporary_page = TemporaryPage::new(
+        Page::containing_address(TEMPORARY_PAGE),
+        &mut frame_allocator,
+    );
+
     MemoryController {
         active_table,
         frame_allocator,
         stack_allocator,
+        temporary_page,
     }
 }
@@ -18,6 +18,9 @@ pub struct AreaFrameAllocator<'a> {
     kernel_end: Frame,
     multiboot_start: Frame,
     multiboot_end: Frame,
+    /// The first and last frames of the boot modules, if there are any. They're kept for good,
+    /// as the kernel reads programs out of them.
+    modules: Option<(Frame, Frame)>,
 }
 
 impl<'a> FrameAllocator for AreaFrameAllocator<'a> {
@@ -30,6 +33,14 @@ impl<'a> FrameAllocator for AreaFrameAllocator<'a> {
             let current_area_last_frame =
                 { Frame::containing_address(area.end_address() as usize - 1) };
 
+            // Where to jump to if the frame holds a boot module
+            let after_modules = match self.modules {
+                Some((ref start, ref end)) if frame >= *start && frame <= *end => {
+                    Some(end.next_frame())
+                }
+                _ => None,
+            };
+
             // Check if the frame we're considering is OK; if it is, we'll return it,
             // if not, we'll update the frame we're looking at and try again.
             if frame > current_area_last_frame {
@@ -44,6 +55,10 @@ impl<'a> FrameAllocator for AreaFrameAllocator<'a> {
                 // The frame under consideration is used by Multiboot,
                 // so jump over the multiboot area.
                 self.next_free_frame = self.multiboot_end.next_frame();
+            } else if let Some(after_modules) = after_modules {
+                // The frame under consideration holds a boot module,
+                // so jump over the modules.
+                self.next_free_frame = after_modules;
             } else {
                 // Frame is unused!
                 self.next_free_frame = Frame::new(self.next_free_frame.index() + 1); // We'll consider the next frame next time we need to alloc
@@ -70,6 +85,7 @@ impl<'a> AreaFrameAllocator<'a> {
         kernel_end: usize,
         multiboot_start: usize,
         multiboot_end: usize,
+        modules: Option<(usize, usize)>,
         memory_areas: MemoryAreaIter<'a>,
     ) -> AreaFrameAllocator<'a> {
         let mut allocator = AreaFrameAllocator {
@@ -80,6 +96,12 @@ impl<'a> AreaFrameAllocator<'a> {
             kernel_end: Frame::containing_address(kernel_end),
             multiboot_start: Frame::containing_address(multiboot_start),
             multiboot_end: Frame::containing_address(multiboot_end),
+            modules: modules.map(|(start, end)| {
+                (
+                    Frame::containing_address(start),
+                    Frame::containing_address(end),
+                )
+            }),
         };
         allocator.next_area();
         allocator
@@ -89,9 +89,12 @@ impl Mapper {
     where
         A: FrameAllocator,
     {
-        let p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
-        let p2 = p3.next_table_create(page.p3_index(), allocator);
-        let p1 = p2.next_table_create(page.p2_index(), allocator);
+        let user = flags.contains(EntryFlags::USER_ACCESSIBLE);
+        let p3 = self
+            .p4_mut()
+            .next_table_create(page.p4_index(), user, allocator);
+        let p2 = p3.next_table_create(page.p3_index(), user, allocator);
+        let p1 = p2.next_table_create(page.p2_index(), user, allocator);
 
         assert!(
             p1[page.p1_index()].is_unused(),
@@ -120,6 +123,23 @@ impl Mapper {
         self.map_to(page, frame, flags, allocator);
     }
 
+    /// Changes the flags a mapped page is mapped with.
+    pub fn set_flags(&mut self, page: Page, flags: EntryFlags) {
+        let p1 = self
+            .p4_mut()
+            .next_table_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_mut(page.p2_index()))
+            .expect("Attempted to set the flags of a page which is not mapped!");
+        let frame = p1[page.p1_index()]
+            .pointed_frame()
+            .expect("Attempted to set the flags of a page which is not mapped!");
+        p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
+
+        use x86_64::instructions::tlb;
+        tlb::flush(::x86_64::VirtAddr::new(page.start_address() as u64));
+    }
+
     /// Unmaps a virtual page.
     #[allow(unused_variables)]
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
@@ -20,7 +20,7 @@ pub use self::frame_allocators::FrameAllocator;
 use self::mapper::Mapper;
 pub use self::page::{Page, PageIter};
 use self::table::{EntryFlags, Table};
-use self::temporary_page::TemporaryPage;
+pub use self::temporary_page::TemporaryPage;
 
 /// Helper type aliases used to make function signatures more expressive
 ///
@@ -113,12 +113,40 @@ impl ActivePageTable {
     }
 }
 
+/// Returns the physical address of the active P4 table.
+pub fn active_p4_address() -> PhysicalAddress {
+    use x86_64::registers::control::Cr3;
+
+    Cr3::read().0.start_address().as_u64() as usize
+}
+
+/// Makes the P4 table at `address` active, without keeping hold of the old one.
+///
+/// # Safety
+/// `address` must hold a P4 table which maps the kernel the same way as the active one, and is
+/// recursively mapped.
+pub unsafe fn load_p4(address: PhysicalAddress) {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PhysFrame;
+    use x86_64::PhysAddr;
+
+    Cr3::write(
+        PhysFrame::from_start_address(PhysAddr::new(address as u64)).unwrap(),
+        Cr3::read().1,
+    );
+}
+
 /// Owns an inactive P4 table.
 pub struct InactivePageTable {
     p4_frame: Frame,
 }
 
 impl InactivePageTable {
+    /// Returns the physical address of this table's P4.
+    pub fn p4_address(&self) -> PhysicalAddress {
+        self.p4_frame.start_address()
+    }
+
     pub fn new(
         frame: Frame,
         active_table: &mut ActivePageTable,
@@ -194,6 +222,23 @@ where
         for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
             mapper.identity_map(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE, allocator);
         }
+
+        // -- Identity map the boot modules (read-only), which programs are loaded from
+        for module in boot_info.module_tags() {
+            if module.end_address() <= module.start_address() {
+                continue;
+            }
+
+            let start_frame = Frame::containing_address(module.start_address() as usize);
+            let end_frame = Frame::containing_address(module.end_address() as usize - 1);
+            for frame in Frame::range_inclusive(start_frame, end_frame) {
+                mapper.identity_map(
+                    frame,
+                    EntryFlags::PRESENT | EntryFlags::NO_EXECUTE,
+                    allocator,
+                );
+            }
+        }
     });
 
     let old_table = active_table.switch(new_table);
@@ -3,6 +3,7 @@
 use super::{Frame, FrameAllocator};
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
+use elf::SegmentFlags;
 use multiboot2::ElfSection;
 
 pub trait TableLevel {}
@@ -73,9 +74,12 @@ where
             .map(|addr| unsafe { &mut *(addr as *mut _) })
     }
 
+    /// Returns the next table down at `index`, creating it if need be. If `user`, the entry is
+    /// made user-accessible too (the CPU only lets ring 3 at a page if every level allows it).
     pub fn next_table_create<A>(
         &mut self,
         index: usize,
+        user: bool,
         allocator: &mut A,
     ) -> &mut Table<L::NextLevel>
     where
@@ -93,6 +97,12 @@ where
             self.next_table_mut(index).unwrap().zero();
         }
 
+        let flags = self.entries[index].flags();
+        if user && !flags.contains(EntryFlags::USER_ACCESSIBLE) {
+            let frame = self.entries[index].pointed_frame().unwrap();
+            self.entries[index].set(frame, flags | EntryFlags::USER_ACCESSIBLE);
+        }
+
         self.next_table_mut(index).unwrap()
     }
 }
@@ -187,4 +197,19 @@ impl EntryFlags {
 
         flags
     }
+
+    /// The flags to map a loadable ELF segment with, going by its program header's `p_flags`.
+    /// Segments can't be mapped unreadable, so that permission's ignored.
+    pub fn from_elf_segment_flags(segment_flags: SegmentFlags) -> EntryFlags {
+        let mut flags = EntryFlags::PRESENT;
+
+        if segment_flags.contains(SegmentFlags::WRITABLE) {
+            flags |= EntryFlags::WRITABLE;
+        }
+        if !segment_flags.contains(SegmentFlags::EXECUTABLE) {
+            flags |= EntryFlags::NO_EXECUTE;
+        }
+
+        flags
+    }
 }
@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 use super::paging::table::EntryFlags;
 use super::paging::{ActivePageTable, Frame, FrameAllocator, Page, PageIter};
 
@@ -30,17 +32,34 @@ impl Stack {
     pub fn bottom(&self) -> usize {
         self.bottom
     }
+
+    /// How many pages the stack is.
+    fn pages(&self) -> usize {
+        (self.top - self.bottom) / Frame::SIZE
+    }
 }
 
 /// An allocator which allocates [Stack]s.
+///
+/// Frames can't be given back yet, so freed stacks stay mapped, and are handed out again to
+/// whoever next asks for a stack of the same size.
 pub struct StackAllocator {
     range: PageIter,
+    free: Vec<Stack>,
 }
 
 impl StackAllocator {
     /// Creates a new stack allocator, allocating stacks in the given `page_range`.
     pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+        StackAllocator {
+            range: page_range,
+            free: Vec::new(),
+        }
+    }
+
+    /// Frees `stack`, which nothing may be running on, for reuse.
+    pub fn free_stack(&mut self, stack: Stack) {
+        self.free.push(stack);
     }
 
     /// Allocate a stack.
@@ -60,6 +79,10 @@ impl StackAllocator {
             return None;
         }
 
+        if let Some(index) = self.free.iter().position(|stack| stack.pages() == size) {
+            return Some(self.free.swap_remove(index));
+        }
+
         let mut range = self.range.clone();
 
         // try to alloc stack, guard pages
@@ -1,15 +1,27 @@
 //! Hardware support and boot for 64-bit Intel x86 processors.
 
+pub mod acpi;
 pub mod bits;
+pub mod context;
 pub mod device;
 pub mod interrupts;
+pub mod mca;
 pub mod memory;
+pub mod syscall;
+pub mod tsc;
+pub mod usermode;
+pub mod watchdog;
 
-use self::device::{pic, pit, vga_console};
+use self::device::{apic, hpet, pic, pit, rtc, vga_console};
+use core::slice;
 use logger;
 use multiboot2;
+use time::clockevent;
 use x86_64;
 
+/// The most CPUs we're prepared to manage.
+pub const MAX_CPUS: usize = 8;
+
 /// `x86_64`-specific kernel entry point, called by the bootstrapping assembly stub.
 #[no_mangle]
 pub unsafe extern "C" fn _start(multiboot_info_pointer: usize) -> ! {
@@ -18,26 +30,84 @@ pub unsafe extern "C" fn _start(multiboot_info_pointer: usize) -> ! {
 
     logger::init().expect("Logger failed to launch!");
 
-    let boot_info = multiboot2::load(multiboot_info_pointer);
+    // kept around for good, as the frame allocator reads the memory map out of it
+    static mut BOOT_INFO: Option<multiboot2::BootInformation> = None;
+    let boot_info = BOOT_INFO.get_or_insert(multiboot2::load(multiboot_info_pointer));
 
     // initialize paging, remap kernel
-    let mut mem_ctrl = memory::init(&boot_info);
+    let mut mem_ctrl = memory::init(boot_info);
     info!("memory::init() success!");
 
     // initialize idt
     interrupts::init(&mut mem_ctrl);
     info!("int: initialized idt");
 
+    syscall::init(&mut mem_ctrl);
+    info!("syscall: enabled syscall/sysret");
+
     pic::PICS.write().init();
     info!("int: initialized pic");
 
-    pit::PIT.init();
+    // IRQ 0 is the PIT's (or, in legacy replacement mode, the HPET's) tick
+    interrupts::irq::register(0, |_| clockevent::handle_tick());
+    clockevent::register(&pit::PIT_CLOCK_EVENT);
     info!("int: initialized pit");
 
+    if acpi::init(&mut mem_ctrl) && hpet::init(&mut mem_ctrl) {
+        info!("int: initialized hpet");
+    }
+    rtc::init();
+
+    tsc::init();
+    info!("int: initialized tsc");
+
+    if apic::is_present() {
+        apic::init(&mut mem_ctrl);
+        apic::timer::init();
+        info!("int: initialized local apic timer");
+
+        if watchdog::init() {
+            info!("int: initialized hard-lockup watchdog");
+        }
+    } else {
+        warn!("int: no local apic; ticking with the pit");
+    }
+
+    ::deferred::init();
+    ::time::timer::init();
+
+    if mca::init() {
+        info!("int: enabled machine-check reporting");
+    }
+
+    memory::install(mem_ctrl);
+    ::task::init();
+    info!("task: adopted boot thread as idle thread");
+
+    // the first boot module, if there is one, is the program to start first; it's identity-mapped
+    // and never freed
+    let init = boot_info.module_tags().next().map(|module| {
+        let start = module.start_address() as usize;
+        let len = module.end_address().saturating_sub(module.start_address()) as usize;
+        info!("multiboot: init module {:?}, {} bytes", module.name(), len);
+        slice::from_raw_parts(start as *const u8, len)
+    });
+
     x86_64::instructions::interrupts::enable();
     info!("int: sti (enabled interrupts)");
 
-    ::kernel_main();
+    ::kernel_main(init);
+}
+
+/// Returns the id of the current CPU, which is less than [MAX_CPUS].
+///
+/// This is the index the CPU was given when it started (see `apic::register_cpu`), not its local
+/// APIC id; the boot CPU's is 0, including before the APIC is initialized.
+pub fn cpu_id() -> usize {
+    apic::LOCAL_APIC.try().map_or(0, |lapic| {
+        // only the boot CPU runs between the APIC being found and its getting an index
+        apic::cpu_index(lapic.id()).unwrap_or(0)
+    })
 }
 
 /// Halts the CPU.
@@ -45,3 +115,11 @@ pub unsafe extern "C" fn _start(multiboot_info_pointer: usize) -> ! {
 pub unsafe fn halt() {
     asm!("hlt" :::: "volatile");
 }
+
+/// Enables interrupts and halts the CPU, atomically: an interrupt arriving in between can't be
+/// handled before the halt, and so can't be slept through.
+#[inline(always)]
+pub unsafe fn enable_interrupts_and_halt() {
+    // `sti` only takes effect after the next instruction
+    asm!("sti; hlt" :::: "volatile");
+}
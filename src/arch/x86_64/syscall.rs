@@ -0,0 +1,158 @@
+//! The `syscall`/`sysret` entry path.
+//!
+//! `syscall` jumps to `syscall_entry` (in `asm/syscall.asm`) on the user's stack, with interrupts
+//! off. The stub swaps to the calling thread's kernel stack (see [set_kernel_stack]; until the
+//! first user thread runs, a stack of the CPU's own), saves the user's registers as a
+//! [SyscallFrame], and hands it to [syscall_dispatch], which runs the call with interrupts on;
+//! whatever that leaves in `rax` is returned to the user.
+//!
+//! # Register convention
+//! The system call number goes in `rax`, and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`,
+//! `r8` and `r9` (`rcx` and `r11` are taken by `syscall` itself). The result comes back in `rax`,
+//! and calls can return more in the argument registers; every other register except `rcx` and
+//! `r11` is preserved. See the `syscall` module for what the calls are, and how errors come back.
+
+use x86_64::registers::model_specific::Msr;
+
+use arch::x86_64::interrupts;
+use arch::x86_64::memory::paging::FrameAllocator;
+use arch::x86_64::memory::MemoryController;
+use arch::x86_64::{self, bits, MAX_CPUS};
+use syscall;
+use task::process;
+
+const IA32_STAR: u32 = 0xc000_0081;
+const IA32_LSTAR: u32 = 0xc000_0082;
+const IA32_FMASK: u32 = 0xc000_0084;
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// RFLAGS bits cleared on entry: TF, IF, and DF.
+const SYSCALL_RFLAGS_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10);
+
+/// How big each CPU's syscall stack is, in pages.
+const SYSCALL_STACK_PAGES: usize = 4;
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// A user's registers, as saved by `syscall_entry`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SyscallFrame {
+    /// The system call number on entry; the result on exit.
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// Where `sysret` returns to (saved from `rcx`).
+    pub rip: u64,
+    /// The user's RFLAGS (saved from `r11`).
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// The system call's arguments, in order.
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// Sets the argument registers the user gets back.
+    pub fn set_args(&mut self, args: &[u64; 6]) {
+        self.rdi = args[0];
+        self.rsi = args[1];
+        self.rdx = args[2];
+        self.r10 = args[3];
+        self.r8 = args[4];
+        self.r9 = args[5];
+    }
+}
+
+/// Per-CPU state `syscall_entry` finds through `gs`. The field offsets are baked into the stub.
+#[repr(C)]
+struct CpuLocal {
+    /// The stack to handle system calls on (`gs:0`).
+    kernel_rsp: usize,
+    /// Scratch space for the user's stack pointer (`gs:8`).
+    user_rsp: usize,
+}
+
+static mut CPU_LOCALS: [CpuLocal; MAX_CPUS] = [
+    CpuLocal { kernel_rsp: 0, user_rsp: 0 },
+    CpuLocal { kernel_rsp: 0, user_rsp: 0 },
+    CpuLocal { kernel_rsp: 0, user_rsp: 0 },
+    CpuLocal { kernel_rsp: 0, user_rsp: 0 },
+    CpuLocal { kernel_rsp: 0, user_rsp: 0 },
+    CpuLocal { kernel_rsp: 0, user_rsp: 0 },
+    CpuLocal { kernel_rsp: 0, user_rsp: 0 },
+    CpuLocal { kernel_rsp: 0, user_rsp: 0 },
+];
+
+/// Sets the stack the current CPU handles system calls on. The scheduler points this at each
+/// user thread's kernel stack as it switches to it.
+///
+/// # Safety
+/// `top` must be the top of a mapped, otherwise unused stack. Must be called with interrupts off.
+pub unsafe fn set_kernel_stack(top: usize) {
+    CPU_LOCALS[x86_64::cpu_id()].kernel_rsp = top;
+}
+
+/// Enables `syscall`/`sysret` on the current CPU, using the passed `memory_controller` to allocate
+/// its syscall stack.
+///
+/// Must be called after [interrupts::init], as `sysret` relies on the GDT's layout.
+pub fn init<A: FrameAllocator>(memory_controller: &mut MemoryController<A>) {
+    let selectors = interrupts::selectors();
+    let stack = memory_controller
+        .alloc_stack(SYSCALL_STACK_PAGES)
+        .expect("syscall: could not allocate stack");
+
+    // syscall loads cs from STAR[47:32] and ss from 8 past it; sysret loads ss from 8 past
+    // STAR[63:48] and cs from 16 past it, which is why the GDT is laid out the way it is
+    let star = (u64::from(selectors.kernel_code.0) << 32)
+        | (u64::from(selectors.user_data.0 - 8) << 48);
+
+    unsafe {
+        set_kernel_stack(stack.top());
+
+        bits::enable_syscall();
+        Msr::new(IA32_STAR).write(star);
+        Msr::new(IA32_LSTAR).write(syscall_entry as usize as u64);
+        Msr::new(IA32_FMASK).write(SYSCALL_RFLAGS_MASK);
+        Msr::new(IA32_KERNEL_GS_BASE)
+            .write(&CPU_LOCALS[x86_64::cpu_id()] as *const CpuLocal as u64);
+    }
+}
+
+/// Is `addr` canonical: are bits 48 to 63 copies of bit 47?
+fn is_canonical(addr: u64) -> bool {
+    let top = addr >> 47;
+    top == 0 || top == 0x1_ffff
+}
+
+/// Called by `syscall_entry` with the user's saved registers.
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    // we're on the calling thread's own stack, so the call can be interrupted, or block
+    ::x86_64::instructions::interrupts::enable();
+
+    // `sysret` to a non-canonical address faults in ring 0, but with the user's rsp already
+    // loaded; the user can't map the last page before the hole, but make sure
+    if !is_canonical(frame.rip) {
+        process::fault_at(
+            format_args!("syscall returning to a non-canonical address"),
+            frame.rip,
+        );
+    }
+    let mut args = frame.args();
+    frame.rax = syscall::dispatch(frame.rax, &mut args);
+    frame.set_args(&args);
+    ::task::exit_if_killed();
+
+    // not again until we're back in user mode, as `sysret` switches stacks first
+    ::x86_64::instructions::interrupts::disable();
+}
@@ -0,0 +1,142 @@
+//! The time-stamp counter: a per-CPU cycle counter, read with `rdtsc`.
+//!
+//! On CPUs with an *invariant* TSC, it ticks at a constant rate whatever the core's frequency or
+//! sleep state, which makes it the cheapest high-resolution clock source we have. Its rate comes
+//! from CPUID where the CPU reports it, and is measured against another clock otherwise.
+
+use core::cmp;
+use core::sync::atomic::{AtomicU64, Ordering};
+use raw_cpuid::CpuId;
+
+use arch::x86_64::device::pit::PIT;
+use time::clocksource::{self, ClockSource};
+
+/// The TSC, as a clock source.
+pub static TSC: Tsc = Tsc;
+
+/// How long each calibration run lasts, in _µs_.
+const CALIBRATION_US: u32 = 10_000;
+/// How many calibration runs to make (the quickest wins, as anything else was interrupted).
+const CALIBRATION_RUNS: usize = 3;
+
+/// The rate the TSC counts at, in _Hz_. Zero until [init].
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the current value of the time-stamp counter.
+#[inline(always)]
+pub fn read() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile");
+    }
+
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+/// The rate the TSC counts at, in _Hz_; zero before [init].
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Does the TSC tick at a constant rate, in every power state?
+pub fn is_invariant() -> bool {
+    CpuId::new()
+        .get_extended_function_info()
+        .map_or(false, |f| f.has_invariant_tsc())
+}
+
+/// The TSC's rate, if the CPU will tell us (through CPUID leaf 0x15, or failing that, the base
+/// frequency in leaf 0x16).
+fn frequency_from_cpuid() -> Option<u64> {
+    let cpuid = CpuId::new();
+
+    let from_crystal = cpuid.get_tsc_info().and_then(|info| {
+        let numerator = u64::from(info.numerator());
+        let denominator = u64::from(info.denominator());
+        let crystal_hz = u64::from(info.nominal_frequency());
+
+        if numerator == 0 || denominator == 0 || crystal_hz == 0 {
+            None
+        } else {
+            Some(crystal_hz * numerator / denominator)
+        }
+    });
+
+    from_crystal.or_else(|| {
+        cpuid
+            .get_processor_frequency_info()
+            .map(|info| u64::from(info.processor_base_frequency()) * 1_000_000)
+            .filter(|&hz| hz != 0)
+    })
+}
+
+/// Measures the TSC's rate against the current clock source, or the PIT if there isn't one.
+///
+/// Must be called with interrupts disabled.
+unsafe fn measure() -> u64 {
+    let source = clocksource::current();
+
+    let fastest = (0..CALIBRATION_RUNS)
+        .map(|_| {
+            let start = read();
+            match source {
+                Some(source) => source.delay_us(u64::from(CALIBRATION_US)),
+                None => PIT.lock().delay_us(CALIBRATION_US),
+            }
+            read() - start
+        })
+        .fold(u64::max_value(), cmp::min);
+
+    fastest * (1_000_000 / u64::from(CALIBRATION_US))
+}
+
+/// The TSC.
+pub struct Tsc;
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        // a TSC that changes speed is only any good if there's nothing else
+        if is_invariant() {
+            300
+        } else {
+            50
+        }
+    }
+
+    fn read(&self) -> u64 {
+        read()
+    }
+
+    fn frequency(&self) -> u64 {
+        frequency()
+    }
+}
+
+/// Works out the TSC's rate, and registers it as a clock source.
+///
+/// Must be called with interrupts disabled, after any better-than-PIT clock sources to calibrate
+/// against (*i.e.* the HPET) are registered.
+pub fn init() {
+    assert_first_call!("tsc::init() can only be called once!");
+
+    let (freq, how) = match frequency_from_cpuid() {
+        Some(freq) => (freq, "cpuid"),
+        None => (
+            unsafe { measure() },
+            clocksource::current().map_or("pit", |source| source.name()),
+        ),
+    };
+    FREQUENCY.store(freq, Ordering::Relaxed);
+
+    info!(
+        "tsc: {} Hz (from {}){}",
+        freq,
+        how,
+        if is_invariant() { ", invariant" } else { "" }
+    );
+    clocksource::register(&TSC);
+}
@@ -0,0 +1,104 @@
+//! Running code in ring 3.
+//!
+//! A thread gets to user mode by [enter]ing it, through `iretq`, and comes back into the kernel
+//! on the stack [set_kernel_stack] last installed: through `syscall`, or through an interrupt or
+//! exception, which the CPU switches to the TSS's RSP0 stack for.
+//!
+//! The kernel reads and writes user memory only through [copy_from_user] and [copy_to_user],
+//! which check that the memory's in the user range, and recover from faults on it.
+
+use core::ptr;
+use x86_64::structures::idt::ExceptionStackFrame;
+use x86_64::VirtAddr;
+
+use arch::x86_64::memory::paging::VirtualAddress;
+use arch::x86_64::memory::{USER_END, USER_START};
+use arch::x86_64::{interrupts, syscall};
+
+extern "C" {
+    fn enter_user(rip: usize, rsp: usize, arg: usize, cs: u64, ss: u64) -> !;
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn copy_user_access();
+    fn copy_user_fixup();
+}
+
+/// User memory couldn't be accessed: it's outside the user range, or not (all) mapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BadAddress;
+
+/// Drops to ring 3, running from `rip` on the stack whose top is `rsp`, with `arg` in `rdi`.
+/// Interrupts are on once there. Never returns; the thread only comes back into the kernel
+/// through a system call or an interrupt.
+///
+/// # Safety
+/// The active address space must map `rip` and the stack for user access, and the current
+/// thread's kernel stack must be [installed](fn.set_kernel_stack.html).
+pub unsafe fn enter(rip: usize, rsp: usize, arg: usize) -> ! {
+    let selectors = interrupts::selectors();
+    enter_user(
+        rip,
+        rsp,
+        arg,
+        u64::from(selectors.user_code.0),
+        u64::from(selectors.user_data.0),
+    )
+}
+
+/// Did the exception or interrupt behind `frame` arrive from user mode?
+pub fn from_user(frame: &ExceptionStackFrame) -> bool {
+    frame.code_segment & 3 == 3
+}
+
+/// Sets the stack the current CPU enters the kernel on from user mode, whether through a system
+/// call or an interrupt.
+///
+/// # Safety
+/// `top` must be the top of a mapped kernel stack belonging to whichever thread next runs in user
+/// mode on this CPU. Must be called with interrupts off.
+pub unsafe fn set_kernel_stack(top: usize) {
+    interrupts::set_privilege_stack(top);
+    syscall::set_kernel_stack(top);
+}
+
+/// Is the `len` bytes from `addr` all in the user range?
+pub fn is_user_range(addr: VirtualAddress, len: usize) -> bool {
+    addr >= USER_START && addr.checked_add(len).map_or(false, |end| end <= USER_END)
+}
+
+/// Copies `dst.len()` bytes from user address `src`, in the active address space, into `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: VirtualAddress) -> Result<(), BadAddress> {
+    if !is_user_range(src, dst.len()) {
+        return Err(BadAddress);
+    }
+
+    match unsafe { copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(BadAddress),
+    }
+}
+
+/// Copies `src` to user address `dst`, in the active address space.
+pub fn copy_to_user(dst: VirtualAddress, src: &[u8]) -> Result<(), BadAddress> {
+    if !is_user_range(dst, src.len()) {
+        return Err(BadAddress);
+    }
+
+    match unsafe { copy_user(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(BadAddress),
+    }
+}
+
+/// If the kernel page fault behind `frame` was [copy_from_user] or [copy_to_user] touching
+/// memory which isn't mapped, has the copy give up, and returns `true`. Called by the page fault
+/// handler; returning from it resumes the copy's recovery path.
+pub fn fixup_fault(frame: &mut ExceptionStackFrame) -> bool {
+    if frame.instruction_pointer.as_u64() != copy_user_access as usize as u64 {
+        return false;
+    }
+
+    let fixup = VirtAddr::new(copy_user_fixup as usize as u64);
+    // the frame's popped by `iretq`, which the compiler can't see
+    unsafe { ptr::write_volatile(&mut frame.instruction_pointer, fixup) };
+    true
+}
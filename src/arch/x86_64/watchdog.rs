@@ -0,0 +1,193 @@
+//! The hard-lockup watchdog.
+//!
+//! A CPU spinning with interrupts disabled stops taking ticks, and nothing else ever notices. So
+//! the watchdog has performance counter 0 count unhalted core cycles, and raise an NMI (through the
+//! local APIC) each time it overflows; if a CPU takes [THRESHOLD_SECS] worth of those in a row
+//! without its tick count moving, it's reported as locked up, along with where it was interrupted.
+//!
+//! Halted CPUs don't count cycles, so an idle CPU (whose ticks may legitimately stop) never trips
+//! it.
+
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+use arch::x86_64::device::apic;
+use arch::x86_64::interrupts::nmi;
+use arch::x86_64::{self, tsc, MAX_CPUS};
+use time::clockevent;
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// The architectural "unhalted core cycles" event.
+const EVENT_CORE_CYCLES: u64 = 0x3c;
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// Roughly how often the watchdog NMI fires on a busy CPU, in seconds.
+const PERIOD_SECS: u64 = 1;
+/// How long a CPU can go without a tick before it's considered locked up, in seconds.
+pub const THRESHOLD_SECS: u64 = 10;
+/// Writes to `IA32_PMC0` only set the low 32 bits (sign-extending bit 31), which caps the period.
+const MAX_PERIOD: u64 = (1 << 31) - 1;
+/// What to assume the core clock is if it hasn't been measured, in _Hz_.
+const DEFAULT_CPU_HZ: u64 = 1_000_000_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Does the PMU have the global control/status MSRs (version 2 and up)?
+static HAS_GLOBAL_CTRL: AtomicBool = AtomicBool::new(false);
+/// How wide the counters are, in bits.
+static COUNTER_WIDTH: AtomicU32 = AtomicU32::new(0);
+/// How many cycles between NMIs.
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+/// How many NMIs without a tick make a lockup.
+static THRESHOLD: AtomicU32 = AtomicU32::new(0);
+
+/// Per-CPU watchdog state.
+#[derive(Default)]
+struct CpuState {
+    /// The CPU's tick count at the last NMI.
+    last_ticks: AtomicU64,
+    /// How many NMIs in a row have seen the same tick count.
+    stuck: AtomicU32,
+    /// Has this lockup been reported already?
+    reported: AtomicBool,
+}
+
+lazy_static! {
+    static ref CPUS: [CpuState; MAX_CPUS] = Default::default();
+}
+
+/// Is the watchdog running?
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Loads the counter so it overflows after another period.
+unsafe fn rearm() {
+    Msr::new(IA32_PMC0).write(PERIOD.load(Ordering::Relaxed).wrapping_neg());
+    apic::local_apic().set_perf_counter_nmi();
+}
+
+/// Starts the watchdog on the current CPU.
+unsafe fn start() {
+    Msr::new(IA32_PERFEVTSEL0).write(0);
+    rearm();
+    Msr::new(IA32_PERFEVTSEL0)
+        .write(EVENT_CORE_CYCLES | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN);
+
+    if HAS_GLOBAL_CTRL.load(Ordering::Relaxed) {
+        let mut global_ctrl = Msr::new(IA32_PERF_GLOBAL_CTRL);
+        let enabled = global_ctrl.read();
+        global_ctrl.write(enabled | 1);
+    }
+}
+
+/// Did counter 0 overflow? Clears the overflow status if so.
+unsafe fn take_overflow() -> bool {
+    if HAS_GLOBAL_CTRL.load(Ordering::Relaxed) {
+        if Msr::new(IA32_PERF_GLOBAL_STATUS).read() & 1 == 0 {
+            return false;
+        }
+        Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+        true
+    } else {
+        // the counter starts out negative, so its top bit is clear only once it's wrapped
+        let top_bit = 1 << (COUNTER_WIDTH.load(Ordering::Relaxed) - 1);
+        Msr::new(IA32_PMC0).read() & top_bit == 0
+    }
+}
+
+/// Handles the NMI, if it came from the watchdog's counter; returns whether it did.
+///
+/// Called from the NMI handler.
+pub fn handle_nmi(stack_frame: &ExceptionStackFrame) -> bool {
+    if !is_enabled() || !unsafe { take_overflow() } {
+        return false;
+    }
+    unsafe { rearm() };
+
+    let cpu_id = x86_64::cpu_id();
+    let cpu = &CPUS[cpu_id];
+    let ticks = clockevent::event_count(cpu_id);
+
+    if cpu.last_ticks.swap(ticks, Ordering::Relaxed) != ticks {
+        cpu.stuck.store(0, Ordering::Relaxed);
+        cpu.reported.store(false, Ordering::Relaxed);
+        return true;
+    }
+
+    let stuck = cpu.stuck.fetch_add(1, Ordering::Relaxed) + 1;
+    if stuck >= THRESHOLD.load(Ordering::Relaxed) && !cpu.reported.swap(true, Ordering::Relaxed) {
+        let interrupts_on = stack_frame.cpu_flags & (1 << 9) != 0;
+        nmi::print(format_args!(
+            "watchdog: hard lockup on cpu {}: no ticks for {} NMIs (stuck at tick {}), \
+             interrupts {}\n{:#?}\n",
+            cpu_id,
+            stuck,
+            ticks,
+            if interrupts_on { "on" } else { "off" },
+            stack_frame
+        ));
+    }
+
+    true
+}
+
+/// Starts the watchdog on the boot CPU, if it has usable performance counters.
+///
+/// Must be called after [apic::init] and [tsc::init].
+/// Returns `false` if there's no watchdog.
+pub fn init() -> bool {
+    assert_first_call!("watchdog::init() can only be called once!");
+
+    let pmu = CpuId::new()
+        .get_performance_monitoring_info()
+        .filter(|pmu| {
+            pmu.version_id() > 0
+                && pmu.number_of_counters() > 0
+                && !pmu.is_core_cyc_ev_unavailable()
+        });
+    let pmu = match pmu {
+        Some(pmu) => pmu,
+        None => {
+            info!("watchdog: no usable performance counters");
+            return false;
+        }
+    };
+    if apic::LOCAL_APIC.try().is_none() {
+        info!("watchdog: no local apic to deliver NMIs");
+        return false;
+    }
+
+    // assume the core runs at about the TSC's rate
+    let cpu_hz = match tsc::frequency() {
+        0 => DEFAULT_CPU_HZ,
+        hz => hz,
+    };
+    let period = cmp::min(cpu_hz * PERIOD_SECS, MAX_PERIOD);
+    let threshold = cmp::max(THRESHOLD_SECS * cpu_hz / period, 1);
+
+    HAS_GLOBAL_CTRL.store(pmu.version_id() >= 2, Ordering::Relaxed);
+    COUNTER_WIDTH.store(u32::from(pmu.counter_bit_width()), Ordering::Relaxed);
+    PERIOD.store(period, Ordering::Relaxed);
+    THRESHOLD.store(threshold as u32, Ordering::Relaxed);
+    lazy_static::initialize(&CPUS);
+
+    ENABLED.store(true, Ordering::Relaxed);
+    unsafe { start() };
+
+    info!(
+        "watchdog: NMI every {} cycles; lockup after {} without a tick",
+        period, threshold
+    );
+    true
+}
@@ -0,0 +1,329 @@
+//! Capabilities: unforgeable references to kernel objects, and what may be done with them.
+//!
+//! Each process has a capability space ([CSpace slots](constant.CSPACE_SLOTS.html) of them),
+//! which user code names capabilities by the indices of. A capability refers to a kernel
+//! [Object], carries [Rights] limiting what may be done with it, and may be badged, so that
+//! whoever receives through it can tell who sent.
+//!
+//! # Derivation
+//! Every capability but the originals the kernel hands out was derived from another: [copy]ed
+//! (with the same rights and badge), or [mint]ed (with fewer rights, or a badge). The capability
+//! derivation tree records which came from which, so that [revoke] can delete everything derived
+//! from a capability, however far it's been passed on. [Moving](fn.move_cap.html) a capability
+//! keeps its place in the tree; [delete]ing one hands its children to its parent.
+
+mod space;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex as SpinMutex;
+
+use self::space::CapTable;
+use arch::x86_64::interrupts::without_interrupts;
+use ipc::{Endpoint, Notification};
+use irq::IrqHandler;
+use task::process::ProcessId;
+
+pub use self::space::CSPACE_SLOTS;
+
+bitflags! {
+    /// What a capability allows. Which rights matter depends on the object.
+    pub struct Rights: u32 {
+        /// Receiving, or reading.
+        const READ =  1 << 0;
+        /// Sending, or writing.
+        const WRITE = 1 << 1;
+        /// Passing capabilities along with messages.
+        const GRANT = 1 << 2;
+    }
+}
+
+/// A kernel object a capability can refer to.
+#[derive(Clone, Debug)]
+pub enum Object {
+    Endpoint(Arc<Endpoint>),
+    Notification(Arc<Notification>),
+    /// The right to claim IRQ lines.
+    IrqControl,
+    Irq(Arc<IrqHandler>),
+}
+
+impl Object {
+    /// Which kind of object this is.
+    pub fn kind(&self) -> ObjectKind {
+        match *self {
+            Object::Endpoint(_) => ObjectKind::Endpoint,
+            Object::Notification(_) => ObjectKind::Notification,
+            Object::IrqControl => ObjectKind::IrqControl,
+            Object::Irq(_) => ObjectKind::Irq,
+        }
+    }
+
+    /// Notes that a capability to the object has been put in a slot.
+    fn cap_created(&self) {
+        if let Object::Endpoint(ref endpoint) = *self {
+            endpoint.cap_created();
+        }
+    }
+
+    /// Notes that a capability to the object has been deleted. Must be called without the table
+    /// locked, as it may destroy the object, waking threads.
+    fn cap_deleted(&self) {
+        if let Object::Endpoint(ref endpoint) = *self {
+            endpoint.cap_deleted();
+        }
+    }
+}
+
+/// The kinds of [Object]. The numbers are part of the system call ABI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObjectKind {
+    Endpoint = 1,
+    Notification = 2,
+    IrqControl = 3,
+    Irq = 4,
+}
+
+/// A capability: an object, and what may be done with it.
+#[derive(Clone, Debug)]
+pub struct Capability {
+    object: Object,
+    rights: Rights,
+    badge: u64,
+}
+
+impl Capability {
+    /// An original, unbadged capability to `object`.
+    pub fn new(object: Object, rights: Rights) -> Capability {
+        Capability {
+            object,
+            rights,
+            badge: 0,
+        }
+    }
+
+    /// The object the capability refers to.
+    pub fn object(&self) -> &Object {
+        &self.object
+    }
+
+    /// What the capability allows.
+    pub fn rights(&self) -> Rights {
+        self.rights
+    }
+
+    /// The capability's badge; 0 if it hasn't been given one.
+    pub fn badge(&self) -> u64 {
+        self.badge
+    }
+
+    /// Checks that the capability has (at least) `rights`.
+    pub fn require(&self, rights: Rights) -> Result<(), CapError> {
+        if self.rights.contains(rights) {
+            Ok(())
+        } else {
+            Err(CapError::InsufficientRights)
+        }
+    }
+
+    /// A copy with only whichever of `rights` this has, badged with `badge` unless that's 0. Only
+    /// unbadged capabilities can be given a badge.
+    fn derive(&self, rights: Rights, badge: u64) -> Result<Capability, CapError> {
+        let mut cap = self.clone();
+        cap.rights &= rights;
+        if badge != 0 {
+            if cap.badge != 0 {
+                return Err(CapError::AlreadyBadged);
+            }
+            cap.badge = badge;
+        }
+
+        Ok(cap)
+    }
+}
+
+/// Names a slot in a process's capability space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SlotRef {
+    pub space: ProcessId,
+    pub index: usize,
+}
+
+impl SlotRef {
+    pub fn new(space: ProcessId, index: usize) -> SlotRef {
+        SlotRef { space, index }
+    }
+}
+
+impl fmt::Display for SlotRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.space, self.index)
+    }
+}
+
+/// Why a capability operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapError {
+    /// The process has no capability space (it's gone, say).
+    NoSuchSpace,
+    /// The slot index is out of range.
+    InvalidSlot,
+    /// The slot's empty.
+    Empty,
+    /// The destination slot's already in use.
+    Occupied,
+    /// The capability doesn't have the rights the operation needs.
+    InsufficientRights,
+    /// A badge was asked for, but the capability already has one.
+    AlreadyBadged,
+}
+
+lazy_static! {
+    // only ever locked with interrupts off, so that a thread holding it can't be preempted by one
+    // spinning on it
+    static ref CAPS: SpinMutex<CapTable> = SpinMutex::new(CapTable::new());
+}
+
+/// Runs `f` on the capability table.
+fn with_table<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut CapTable) -> R,
+{
+    without_interrupts(|| f(&mut CAPS.lock()))
+}
+
+/// Lets the objects `caps` (just taken out of the table) referred to know they're gone.
+fn deleted<I>(caps: I)
+where
+    I: IntoIterator<Item = Capability>,
+{
+    for cap in caps {
+        cap.object.cap_deleted();
+    }
+}
+
+/// Gives process `pid` an empty capability space.
+///
+/// # Panics
+/// If it already has one.
+pub fn create_space(pid: ProcessId) {
+    with_table(|table| table.create_space(pid));
+}
+
+/// Deletes every capability in process `pid`'s space, and the space itself. Anything derived
+/// from them lives on, handed up the tree.
+pub fn destroy_space(pid: ProcessId) {
+    deleted(with_table(|table| table.destroy_space(pid)));
+}
+
+/// Puts an original capability (one derived from nothing) in `slot`. This is how the kernel
+/// hands out capabilities to objects it's made.
+pub fn insert(slot: SlotRef, cap: Capability) -> Result<(), CapError> {
+    with_table(|table| table.insert(slot, cap, None))
+}
+
+/// The capability in `slot`.
+pub fn lookup(slot: SlotRef) -> Result<Capability, CapError> {
+    with_table(|table| table.get(slot).map(|entry| entry.cap.clone()))
+}
+
+/// Copies the capability in `src` to the empty slot `dst` (in any space), as a child of `src`.
+pub fn copy(src: SlotRef, dst: SlotRef) -> Result<(), CapError> {
+    with_table(|table| {
+        let cap = table.get(src)?.cap.clone();
+        table.insert(dst, cap, Some(src))
+    })
+}
+
+/// Like [copy], but the new capability only has whichever of `rights` the original has, and,
+/// unless `badge` is 0, is badged with `badge`. Only unbadged capabilities can be given a badge.
+pub fn mint(src: SlotRef, dst: SlotRef, rights: Rights, badge: u64) -> Result<(), CapError> {
+    with_table(|table| {
+        let cap = table.get(src)?.cap.derive(rights, badge)?;
+        table.insert(dst, cap, Some(src))
+    })
+}
+
+/// Moves the capability in `src` to the empty slot `dst`, keeping its place in the derivation
+/// tree.
+pub fn move_cap(src: SlotRef, dst: SlotRef) -> Result<(), CapError> {
+    with_table(|table| table.relocate(src, dst))
+}
+
+/// Deletes the capability in `slot`. Capabilities derived from it are kept, becoming children of
+/// its parent.
+pub fn delete(slot: SlotRef) -> Result<(), CapError> {
+    deleted(Some(with_table(|table| table.remove(slot))?));
+    Ok(())
+}
+
+/// Deletes every capability derived from the one in `slot` (however indirectly), but not the
+/// capability itself.
+pub fn revoke(slot: SlotRef) -> Result<(), CapError> {
+    let revoked = with_table(|table| table.revoke(slot))?;
+    if !revoked.is_empty() {
+        debug!(
+            "cap: revoked {} capabilities derived from {}",
+            revoked.len(),
+            slot
+        );
+    }
+
+    deleted(revoked);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cap(rights: Rights) -> Capability {
+        Capability::new(Object::IrqControl, rights)
+    }
+
+    #[test]
+    fn require() {
+        let cap = cap(Rights::READ | Rights::GRANT);
+        assert_eq!(cap.require(Rights::empty()), Ok(()));
+        assert_eq!(cap.require(Rights::READ), Ok(()));
+        assert_eq!(cap.require(Rights::READ | Rights::GRANT), Ok(()));
+        assert_eq!(
+            cap.require(Rights::WRITE),
+            Err(CapError::InsufficientRights)
+        );
+        assert_eq!(
+            cap.require(Rights::READ | Rights::WRITE),
+            Err(CapError::InsufficientRights)
+        );
+    }
+
+    #[test]
+    fn derive_only_drops_rights() {
+        let original = cap(Rights::READ | Rights::WRITE);
+
+        let copy = original.derive(Rights::all(), 0).unwrap();
+        assert_eq!(copy.rights(), Rights::READ | Rights::WRITE);
+
+        let reader = original.derive(Rights::READ | Rights::GRANT, 0).unwrap();
+        assert_eq!(reader.rights(), Rights::READ);
+        let reader = reader.derive(Rights::all(), 0).unwrap();
+        assert_eq!(reader.rights(), Rights::READ);
+    }
+
+    #[test]
+    fn derive_badges_once() {
+        let original = cap(Rights::all());
+
+        let badged = original.derive(Rights::all(), 42).unwrap();
+        assert_eq!(badged.badge(), 42);
+        assert_eq!(original.badge(), 0);
+
+        // copying keeps the badge, but it can't be changed
+        assert_eq!(badged.derive(Rights::WRITE, 0).unwrap().badge(), 42);
+        assert_eq!(
+            badged.derive(Rights::all(), 7).err(),
+            Some(CapError::AlreadyBadged)
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Capability spaces, and the derivation tree linking their slots.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::{CapError, Capability, SlotRef};
+use task::process::ProcessId;
+
+/// How many slots each capability space has.
+pub const CSPACE_SLOTS: usize = 256;
+
+/// An occupied slot: its capability, and its place in the derivation tree.
+pub(super) struct Entry {
+    pub(super) cap: Capability,
+    /// The capability this one was derived from; `None` for originals.
+    parent: Option<SlotRef>,
+    /// The capabilities derived from this one.
+    children: Vec<SlotRef>,
+}
+
+/// Every process's capability space.
+pub(super) struct CapTable {
+    spaces: BTreeMap<ProcessId, Vec<Option<Entry>>>,
+}
+
+impl CapTable {
+    pub(super) fn new() -> CapTable {
+        CapTable {
+            spaces: BTreeMap::new(),
+        }
+    }
+
+    pub(super) fn create_space(&mut self, pid: ProcessId) {
+        let slots = (0..CSPACE_SLOTS).map(|_| None).collect();
+        let old = self.spaces.insert(pid, slots);
+        assert!(old.is_none(), "cap: process {} already has a space", pid);
+    }
+
+    /// Empties process `pid`'s space and removes it. Returns the capabilities that were in it.
+    pub(super) fn destroy_space(&mut self, pid: ProcessId) -> Vec<Capability> {
+        let caps = (0..CSPACE_SLOTS)
+            .filter_map(|index| self.remove(SlotRef::new(pid, index)).ok())
+            .collect();
+        self.spaces.remove(&pid);
+        caps
+    }
+
+    fn slot(&mut self, slot: SlotRef) -> Result<&mut Option<Entry>, CapError> {
+        self.spaces
+            .get_mut(&slot.space)
+            .ok_or(CapError::NoSuchSpace)?
+            .get_mut(slot.index)
+            .ok_or(CapError::InvalidSlot)
+    }
+
+    pub(super) fn get(&mut self, slot: SlotRef) -> Result<&mut Entry, CapError> {
+        self.slot(slot)?.as_mut().ok_or(CapError::Empty)
+    }
+
+    /// Puts `cap` in the empty slot `slot`, as a child of `parent` (if it's given).
+    pub(super) fn insert(
+        &mut self,
+        slot: SlotRef,
+        cap: Capability,
+        parent: Option<SlotRef>,
+    ) -> Result<(), CapError> {
+        if self.slot(slot)?.is_some() {
+            return Err(CapError::Occupied);
+        }
+        if let Some(parent) = parent {
+            self.get(parent)?.children.push(slot);
+        }
+
+        cap.object().cap_created();
+        *self.slot(slot)? = Some(Entry {
+            cap,
+            parent,
+            children: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Points whichever slots link to `old` at `new` instead.
+    fn relink(
+        &mut self,
+        old: SlotRef,
+        new: SlotRef,
+        parent: Option<SlotRef>,
+        children: &[SlotRef],
+    ) {
+        if let Some(parent) = parent {
+            if let Ok(parent) = self.get(parent) {
+                for child in parent.children.iter_mut().filter(|c| **c == old) {
+                    *child = new;
+                }
+            }
+        }
+        for &child in children {
+            if let Ok(child) = self.get(child) {
+                child.parent = Some(new);
+            }
+        }
+    }
+
+    /// Moves the capability in `src` to the empty slot `dst`.
+    pub(super) fn relocate(&mut self, src: SlotRef, dst: SlotRef) -> Result<(), CapError> {
+        self.get(src)?;
+        if src == dst {
+            return Ok(());
+        }
+        if self.slot(dst)?.is_some() {
+            return Err(CapError::Occupied);
+        }
+
+        let entry = self.slot(src)?.take().expect("cap: slot emptied under us");
+        self.relink(src, dst, entry.parent, &entry.children);
+        *self.slot(dst)? = Some(entry);
+        Ok(())
+    }
+
+    /// Empties `slot`, handing the capabilities derived from it to its parent. Returns the
+    /// capability that was there.
+    pub(super) fn remove(&mut self, slot: SlotRef) -> Result<Capability, CapError> {
+        let entry = self.slot(slot)?.take().ok_or(CapError::Empty)?;
+
+        if let Some(parent) = entry.parent {
+            if let Ok(parent) = self.get(parent) {
+                parent.children.retain(|&c| c != slot);
+                parent.children.extend_from_slice(&entry.children);
+            }
+        }
+        for &child in entry.children.iter() {
+            if let Ok(child) = self.get(child) {
+                child.parent = entry.parent;
+            }
+        }
+
+        Ok(entry.cap)
+    }
+
+    /// Empties every slot holding a capability derived from the one in `slot`. Returns the
+    /// capabilities that were there.
+    pub(super) fn revoke(&mut self, slot: SlotRef) -> Result<Vec<Capability>, CapError> {
+        let mut pending = ::core::mem::replace(&mut self.get(slot)?.children, Vec::new());
+
+        let mut revoked = Vec::new();
+        while let Some(descendant) = pending.pop() {
+            // the whole subtree goes, so there's nothing to relink
+            if let Ok(slot) = self.slot(descendant) {
+                if let Some(entry) = slot.take() {
+                    pending.extend_from_slice(&entry.children);
+                    revoked.push(entry.cap);
+                }
+            }
+        }
+
+        Ok(revoked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Object, Rights};
+    use super::*;
+
+    fn slot(space: u64, index: usize) -> SlotRef {
+        SlotRef::new(ProcessId::from_u64(space), index)
+    }
+
+    fn cap() -> Capability {
+        Capability::new(Object::IrqControl, Rights::all())
+    }
+
+    /// Spaces 1 and 2, with an original in 1:0, a child of it in 1:1, and a grandchild in 2:0.
+    fn table() -> CapTable {
+        let mut table = CapTable::new();
+        table.create_space(ProcessId::from_u64(1));
+        table.create_space(ProcessId::from_u64(2));
+        table.insert(slot(1, 0), cap(), None).unwrap();
+        table.insert(slot(1, 1), cap(), Some(slot(1, 0))).unwrap();
+        table.insert(slot(2, 0), cap(), Some(slot(1, 1))).unwrap();
+        table
+    }
+
+    fn occupied(table: &mut CapTable, slot: SlotRef) -> bool {
+        table.get(slot).is_ok()
+    }
+
+    #[test]
+    fn slots() {
+        let mut table = table();
+        assert_eq!(
+            table.insert(slot(1, 0), cap(), None).err(),
+            Some(CapError::Occupied)
+        );
+        assert_eq!(
+            table.insert(slot(1, CSPACE_SLOTS), cap(), None).err(),
+            Some(CapError::InvalidSlot)
+        );
+        assert_eq!(
+            table.insert(slot(3, 0), cap(), None).err(),
+            Some(CapError::NoSuchSpace)
+        );
+        assert_eq!(table.get(slot(1, 2)).err(), Some(CapError::Empty));
+        assert_eq!(table.remove(slot(1, 2)).err(), Some(CapError::Empty));
+    }
+
+    #[test]
+    fn revoke_takes_every_descendant() {
+        let mut table = table();
+        table.insert(slot(2, 1), cap(), Some(slot(1, 0))).unwrap();
+
+        assert_eq!(table.revoke(slot(1, 0)).map(|caps| caps.len()), Ok(3));
+        assert!(occupied(&mut table, slot(1, 0)));
+        assert!(!occupied(&mut table, slot(1, 1)));
+        assert!(!occupied(&mut table, slot(2, 0)));
+        assert!(!occupied(&mut table, slot(2, 1)));
+
+        // and there's nothing left to revoke
+        assert_eq!(table.revoke(slot(1, 0)).map(|caps| caps.len()), Ok(0));
+    }
+
+    #[test]
+    fn revoke_spares_ancestors_and_siblings() {
+        let mut table = table();
+        table.insert(slot(2, 1), cap(), Some(slot(1, 0))).unwrap();
+
+        assert_eq!(table.revoke(slot(1, 1)).map(|caps| caps.len()), Ok(1));
+        assert!(occupied(&mut table, slot(1, 0)));
+        assert!(occupied(&mut table, slot(1, 1)));
+        assert!(!occupied(&mut table, slot(2, 0)));
+        assert!(occupied(&mut table, slot(2, 1)));
+    }
+
+    #[test]
+    fn remove_hands_children_up() {
+        let mut table = table();
+        table.remove(slot(1, 1)).unwrap();
+        assert!(occupied(&mut table, slot(2, 0)));
+
+        // the grandchild's now the original's child, so goes with it
+        assert_eq!(table.revoke(slot(1, 0)).map(|caps| caps.len()), Ok(1));
+        assert!(!occupied(&mut table, slot(2, 0)));
+    }
+
+    #[test]
+    fn relocate_keeps_the_tree() {
+        let mut table = table();
+        assert_eq!(
+            table.relocate(slot(1, 1), slot(2, 0)),
+            Err(CapError::Occupied)
+        );
+        table.relocate(slot(1, 1), slot(2, 5)).unwrap();
+        assert!(!occupied(&mut table, slot(1, 1)));
+
+        assert_eq!(table.revoke(slot(2, 5)).map(|caps| caps.len()), Ok(1));
+        assert!(!occupied(&mut table, slot(2, 0)));
+        assert_eq!(table.revoke(slot(1, 0)).map(|caps| caps.len()), Ok(1));
+        assert!(!occupied(&mut table, slot(2, 5)));
+    }
+
+    #[test]
+    fn destroyed_space_hands_children_up() {
+        let mut table = table();
+        assert_eq!(table.destroy_space(ProcessId::from_u64(1)).len(), 2);
+        assert_eq!(table.get(slot(1, 0)).err(), Some(CapError::NoSuchSpace));
+
+        // the grandchild lives on, an original now
+        assert!(occupied(&mut table, slot(2, 0)));
+        assert_eq!(table.revoke(slot(2, 0)).map(|caps| caps.len()), Ok(0));
+    }
+}
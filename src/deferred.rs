@@ -0,0 +1,244 @@
+//! Deferred interrupt work ("bottom halves").
+//!
+//! Interrupt handlers run with interrupts disabled, so they should do as little as possible: read
+//! a device register, acknowledge the interrupt, and [defer] the rest. Deferred work is queued on
+//! a per-CPU, lock-free queue, and drained when the outermost interrupt handler on that CPU
+//! returns, with interrupts re-enabled; the idle loop drains it too.
+//!
+//! Once the work's done, the outermost handler gives the scheduler a chance to [preempt] the
+//! interrupted thread (unless that was itself running deferred work).
+//!
+//! [preempt]: ../task/fn.preempt.html
+//!
+//! # Ordering guarantees
+//! * Work runs on the CPU which queued it, in the order it was queued.
+//! * Each item runs exactly once, with interrupts enabled, and never before the handler which
+//!   queued it has sent its EOI.
+//! * Items on the same CPU never run concurrently with each other. Items on different CPUs may,
+//!   and there's no ordering between them.
+//! * An item may queue more work; it'll run in the same pass, after everything already queued.
+//! * There's no ordering relative to the code the interrupt interrupted; work may run before or
+//!   after it resumes.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use arch::x86_64::{self, MAX_CPUS};
+
+/// How many items each CPU's queue can hold. Must be a power of two.
+const QUEUE_LEN: usize = 256;
+
+/// A deferred work item: a function, and an argument to call it with.
+#[derive(Clone, Copy)]
+pub struct Work {
+    func: fn(usize),
+    arg: usize,
+}
+
+impl Work {
+    /// Creates a work item which calls `func(arg)`.
+    pub fn new(func: fn(usize), arg: usize) -> Work {
+        Work { func, arg }
+    }
+
+    fn run(self) {
+        (self.func)(self.arg)
+    }
+}
+
+/// Returned by [defer] when the current CPU's queue is full.
+#[derive(Debug)]
+pub struct QueueFull;
+
+struct Slot {
+    /// Which lap of the ring this slot is on, offset by whether it's full (see [WorkQueue]).
+    seq: AtomicUsize,
+    work: UnsafeCell<Option<Work>>,
+}
+
+/// A bounded, lock-free multi-producer multi-consumer queue (after Dmitry Vyukov's).
+///
+/// Each slot's sequence number says whose turn it is: a slot at position `pos` is ready to be
+/// written when `seq == pos`, and ready to be read when `seq == pos + 1`.
+struct WorkQueue {
+    slots: Box<[Slot]>,
+    enqueue_pos: AtomicUsize,
+    dequeue_pos: AtomicUsize,
+}
+
+unsafe impl Sync for WorkQueue {}
+
+impl WorkQueue {
+    fn new() -> WorkQueue {
+        WorkQueue {
+            slots: (0..QUEUE_LEN)
+                .map(|i| Slot {
+                    seq: AtomicUsize::new(i),
+                    work: UnsafeCell::new(None),
+                })
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+            enqueue_pos: AtomicUsize::new(0),
+            dequeue_pos: AtomicUsize::new(0),
+        }
+    }
+
+    /// Is the queue (momentarily) empty?
+    fn is_empty(&self) -> bool {
+        self.dequeue_pos.load(Ordering::Relaxed) == self.enqueue_pos.load(Ordering::Relaxed)
+    }
+
+    fn push(&self, work: Work) -> Result<(), Work> {
+        let mut pos = self.enqueue_pos.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[pos & (QUEUE_LEN - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+
+            if seq == pos {
+                match self.enqueue_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { *slot.work.get() = Some(work) };
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if (seq.wrapping_sub(pos) as isize) < 0 {
+                // the slot still holds an item from the previous lap
+                return Err(work);
+            } else {
+                pos = self.enqueue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<Work> {
+        let mut pos = self.dequeue_pos.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[pos & (QUEUE_LEN - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let ready = pos.wrapping_add(1);
+
+            if seq == ready {
+                match self.dequeue_pos.compare_exchange_weak(
+                    pos,
+                    ready,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let work = unsafe { (*slot.work.get()).take() };
+                        slot.seq
+                            .store(pos.wrapping_add(QUEUE_LEN), Ordering::Release);
+                        return work;
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if (seq.wrapping_sub(ready) as isize) < 0 {
+                // nothing's been written here yet
+                return None;
+            } else {
+                pos = self.dequeue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Per-CPU deferred work state.
+struct CpuState {
+    queue: WorkQueue,
+    /// How many interrupt handlers deep this CPU is.
+    irq_depth: AtomicUsize,
+    /// Is this CPU currently running deferred work?
+    draining: AtomicBool,
+}
+
+lazy_static! {
+    static ref CPUS: Vec<CpuState> = (0..MAX_CPUS)
+        .map(|_| CpuState {
+            queue: WorkQueue::new(),
+            irq_depth: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+        })
+        .collect();
+}
+
+/// Allocates every CPU's queue. Called at boot, before interrupts are enabled, so that the first
+/// interrupt handler to use a queue doesn't end up allocating.
+pub fn init() {
+    lazy_static::initialize(&CPUS);
+}
+
+/// Queues `func(arg)` to run on the current CPU once interrupts are back on.
+pub fn defer(func: fn(usize), arg: usize) -> Result<(), QueueFull> {
+    CPUS[x86_64::cpu_id()]
+        .queue
+        .push(Work::new(func, arg))
+        .map_err(|_| QueueFull)
+}
+
+/// Does the current CPU have work waiting to run?
+pub fn has_pending() -> bool {
+    !CPUS[x86_64::cpu_id()].queue.is_empty()
+}
+
+/// Runs all of the current CPU's pending work. Does nothing if called from an interrupt handler,
+/// or from deferred work.
+///
+/// Must be called with interrupts enabled.
+pub fn run_pending() {
+    let cpu = &CPUS[x86_64::cpu_id()];
+
+    if cpu.irq_depth.load(Ordering::Relaxed) != 0 || cpu.draining.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    while let Some(work) = cpu.queue.pop() {
+        work.run();
+    }
+
+    cpu.draining.store(false, Ordering::Release);
+}
+
+/// Notes that an interrupt handler has started on the current CPU.
+///
+/// Called on entry to interrupt handlers which may defer work.
+pub fn irq_enter() {
+    CPUS[x86_64::cpu_id()]
+        .irq_depth
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Notes that an interrupt handler is finishing on the current CPU; if it's the outermost one,
+/// runs any pending work, with interrupts briefly re-enabled, then lets the scheduler preempt the
+/// interrupted thread.
+///
+/// Must be called as the very last thing in the handler, after the interrupt has been
+/// acknowledged.
+pub fn irq_exit() {
+    let cpu = &CPUS[x86_64::cpu_id()];
+
+    if cpu.irq_depth.fetch_sub(1, Ordering::Relaxed) != 1 {
+        return;
+    }
+
+    if !cpu.queue.is_empty() {
+        ::x86_64::instructions::interrupts::enable();
+        run_pending();
+        ::x86_64::instructions::interrupts::disable();
+    }
+
+    // `draining` is per-CPU, so switching threads half-way through a drain would stall the queue
+    if !cpu.draining.load(Ordering::Relaxed) {
+        ::task::preempt();
+    }
+}
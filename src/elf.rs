@@ -0,0 +1,479 @@
+//! Parsing ELF64 executables.
+//!
+//! Only what's needed to load a statically-linked x86_64 executable is understood: the file
+//! header, and the program headers. Everything is read out of the image with bounds checks, so a
+//! malformed (or truncated) file is an [ElfError], never a panic.
+
+use core::fmt;
+
+/// Why an image isn't a loadable executable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image ends before a header (or a segment's data) does.
+    Truncated,
+    /// The image doesn't start with the ELF magic number.
+    BadMagic,
+    /// The image isn't 64-bit, little-endian, version 1 ELF.
+    Unsupported,
+    /// The image isn't an executable (`ET_EXEC`), or isn't for x86_64.
+    WrongType,
+    /// The program header table's entries are too small, or it has none.
+    BadProgramHeaders,
+    /// Program header `n` describes a segment which can't be loaded: its memory is smaller than
+    /// its file data, or it runs off the end of the address space.
+    BadSegment(usize),
+    /// Loadable segment `n` overlaps (or comes before) the one before it.
+    Overlapping(usize),
+    /// The image has no loadable segments.
+    NoSegments,
+    /// The entry point isn't in an executable segment.
+    BadEntry,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ElfError::Truncated => write!(f, "truncated image"),
+            ElfError::BadMagic => write!(f, "not an ELF image"),
+            ElfError::Unsupported => write!(f, "not a 64-bit little-endian ELF image"),
+            ElfError::WrongType => write!(f, "not an x86_64 executable"),
+            ElfError::BadProgramHeaders => write!(f, "bad program header table"),
+            ElfError::BadSegment(n) => write!(f, "bad segment (program header {})", n),
+            ElfError::Overlapping(n) => write!(f, "overlapping segment (program header {})", n),
+            ElfError::NoSegments => write!(f, "nothing to load"),
+            ElfError::BadEntry => write!(f, "entry point outside executable segments"),
+        }
+    }
+}
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+/// How big the file header is.
+const EHDR_SIZE: usize = 64;
+/// How big a program header is (at least; `e_phentsize` may be bigger).
+const PHDR_SIZE: usize = 56;
+
+/// Program header type of a loadable segment.
+pub const PT_LOAD: u32 = 1;
+/// Program header type of the program header table's own entry.
+pub const PT_PHDR: u32 = 6;
+
+bitflags! {
+    /// Segment permissions (a program header's `p_flags`).
+    pub struct SegmentFlags: u32 {
+        const EXECUTABLE = 1 << 0;
+        const WRITABLE =   1 << 1;
+        const READABLE =   1 << 2;
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let end = offset.checked_add(2).ok_or(ElfError::Truncated)?;
+    let bytes = data.get(offset..end).ok_or(ElfError::Truncated)?;
+    Ok(u16::from(bytes[0]) | u16::from(bytes[1]) << 8)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    Ok(u32::from(read_u16(data, offset)?) | u32::from(read_u16(data, offset + 2)?) << 16)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    Ok(u64::from(read_u32(data, offset)?) | u64::from(read_u32(data, offset + 4)?) << 32)
+}
+
+/// A program header.
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramHeader {
+    /// What kind of segment this is; see (*e.g.*) [PT_LOAD].
+    pub kind: u32,
+    pub flags: SegmentFlags,
+    /// Where the segment's data starts in the image.
+    pub offset: u64,
+    /// Where the segment goes in memory.
+    pub vaddr: u64,
+    /// How much of the segment's data is in the image.
+    pub file_size: u64,
+    /// How big the segment is in memory. Anything past `file_size` is zeroed.
+    pub mem_size: u64,
+}
+
+impl ProgramHeader {
+    /// One past the segment's last byte in memory.
+    pub fn vaddr_end(&self) -> u64 {
+        self.vaddr + self.mem_size
+    }
+
+    /// Does the segment cover address `addr`?
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr < self.vaddr_end()
+    }
+}
+
+/// A validated ELF64 executable.
+#[derive(Clone, Copy, Debug)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    ph_offset: usize,
+    ph_entry_size: usize,
+    ph_count: usize,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Parses and validates the executable in `data`.
+    ///
+    /// Checks that every program header is in the image, and that the loadable segments' data
+    /// is; that the loadable segments are in order, and don't overlap; and that the entry point
+    /// is in one of them, which is executable.
+    pub fn parse(data: &'a [u8]) -> Result<ElfFile<'a>, ElfError> {
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+            return Err(ElfError::Unsupported);
+        }
+        if read_u16(data, 16)? != ET_EXEC || read_u16(data, 18)? != EM_X86_64 {
+            return Err(ElfError::WrongType);
+        }
+
+        let file = ElfFile {
+            data,
+            entry: read_u64(data, 24)?,
+            ph_offset: read_u64(data, 32)? as usize,
+            ph_entry_size: read_u16(data, 54)? as usize,
+            ph_count: read_u16(data, 56)? as usize,
+        };
+
+        if file.ph_count == 0 || file.ph_entry_size < PHDR_SIZE {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let table_end = file
+            .ph_entry_size
+            .checked_mul(file.ph_count)
+            .and_then(|size| size.checked_add(file.ph_offset))
+            .ok_or(ElfError::BadProgramHeaders)?;
+        if table_end > data.len() {
+            return Err(ElfError::Truncated);
+        }
+
+        file.validate_segments()?;
+        Ok(file)
+    }
+
+    fn validate_segments(&self) -> Result<(), ElfError> {
+        let mut last_end = 0;
+        let mut any = false;
+        let mut entry_ok = false;
+
+        for n in 0..self.ph_count {
+            let header = self.program_header(n)?;
+            if header.kind != PT_LOAD {
+                continue;
+            }
+
+            let bad = header.file_size > header.mem_size
+                || header.vaddr.checked_add(header.mem_size).is_none()
+                || header
+                    .offset
+                    .checked_add(header.file_size)
+                    .map_or(true, |end| end > self.data.len() as u64);
+            if bad {
+                return Err(ElfError::BadSegment(n));
+            }
+            if header.mem_size == 0 {
+                continue;
+            }
+            if header.vaddr < last_end {
+                return Err(ElfError::Overlapping(n));
+            }
+            last_end = header.vaddr_end();
+            any = true;
+
+            if header.contains(self.entry) && header.flags.contains(SegmentFlags::EXECUTABLE) {
+                entry_ok = true;
+            }
+        }
+
+        if !any {
+            Err(ElfError::NoSegments)
+        } else if !entry_ok {
+            Err(ElfError::BadEntry)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reads program header `n`.
+    fn program_header(&self, n: usize) -> Result<ProgramHeader, ElfError> {
+        let base = self.ph_offset + n * self.ph_entry_size;
+        Ok(ProgramHeader {
+            kind: read_u32(self.data, base)?,
+            flags: SegmentFlags::from_bits_truncate(read_u32(self.data, base + 4)?),
+            offset: read_u64(self.data, base + 8)?,
+            vaddr: read_u64(self.data, base + 16)?,
+            file_size: read_u64(self.data, base + 32)?,
+            mem_size: read_u64(self.data, base + 40)?,
+        })
+    }
+
+    /// The address execution starts at.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// The program headers, in order.
+    pub fn program_headers(&self) -> ProgramHeaders<'a> {
+        ProgramHeaders {
+            file: *self,
+            next: 0,
+        }
+    }
+
+    /// The loadable segments, in order (and so by address).
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD && header.mem_size > 0)
+    }
+
+    /// Loadable segment `header`'s data in the image.
+    pub fn segment_data(&self, header: &ProgramHeader) -> &'a [u8] {
+        // checked by `parse`
+        let start = header.offset as usize;
+        &self.data[start..start + header.file_size as usize]
+    }
+
+    /// Where the program header table is in the image, and how many entries it has, and how
+    /// big they are.
+    pub fn program_header_table(&self) -> (usize, usize, usize) {
+        (self.ph_offset, self.ph_count, self.ph_entry_size)
+    }
+}
+
+/// Iterates over an [ElfFile]'s program headers.
+pub struct ProgramHeaders<'a> {
+    file: ElfFile<'a>,
+    next: usize,
+}
+
+impl<'a> Iterator for ProgramHeaders<'a> {
+    type Item = ProgramHeader;
+
+    fn next(&mut self) -> Option<ProgramHeader> {
+        if self.next >= self.file.ph_count {
+            return None;
+        }
+        // every header's been bounds-checked by `parse`
+        let header = self.file.program_header(self.next).ok();
+        self.next += 1;
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A program header, as (type, flags, offset, vaddr, file size, memory size).
+    type Segment = (u32, u32, u64, u64, u64, u64);
+
+    const RX: u32 = 0b101;
+    const RW: u32 = 0b110;
+    const ENTRY: u64 = 0x40_0010;
+
+    /// A text segment, and a data segment with some bss.
+    const SEGMENTS: [Segment; 2] = [
+        (PT_LOAD, RX, 0x1000, 0x40_0000, 0x100, 0x200),
+        (PT_LOAD, RW, 0x1100, 0x40_1000, 0x10, 0x1000),
+    ];
+
+    fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+        put(image, offset, &[value as u8, (value >> 8) as u8]);
+    }
+
+    fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+        put_u16(image, offset, value as u16);
+        put_u16(image, offset + 2, (value >> 16) as u16);
+    }
+
+    fn put_u64(image: &mut [u8], offset: usize, value: u64) {
+        put_u32(image, offset, value as u32);
+        put_u32(image, offset + 4, (value >> 32) as u32);
+    }
+
+    /// An 8 KiB x86_64 executable starting at `entry`, with the program header table right after
+    /// the file header.
+    fn image(entry: u64, segments: &[Segment]) -> Vec<u8> {
+        let mut image = vec![0; 0x2000];
+        put(&mut image, 0, &ELF_MAGIC);
+        put(&mut image, 4, &[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+        put_u16(&mut image, 16, ET_EXEC);
+        put_u16(&mut image, 18, EM_X86_64);
+        put_u64(&mut image, 24, entry);
+        put_u64(&mut image, 32, EHDR_SIZE as u64);
+        put_u16(&mut image, 54, PHDR_SIZE as u16);
+        put_u16(&mut image, 56, segments.len() as u16);
+
+        for (n, &(kind, flags, offset, vaddr, file_size, mem_size)) in segments.iter().enumerate() {
+            let base = EHDR_SIZE + n * PHDR_SIZE;
+            put_u32(&mut image, base, kind);
+            put_u32(&mut image, base + 4, flags);
+            put_u64(&mut image, base + 8, offset);
+            put_u64(&mut image, base + 16, vaddr);
+            put_u64(&mut image, base + 32, file_size);
+            put_u64(&mut image, base + 40, mem_size);
+        }
+        image
+    }
+
+    fn parse(entry: u64, segments: &[Segment]) -> Result<(), ElfError> {
+        ElfFile::parse(&image(entry, segments)).map(|_| ())
+    }
+
+    #[test]
+    fn executable() {
+        let image = image(ENTRY, &SEGMENTS);
+        let file = ElfFile::parse(&image).unwrap();
+        assert_eq!(file.entry(), ENTRY);
+        assert_eq!(file.program_header_table(), (EHDR_SIZE, 2, PHDR_SIZE));
+
+        let segments: Vec<_> = file.segments().collect();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].vaddr, 0x40_0000);
+        assert_eq!(
+            segments[0].flags,
+            SegmentFlags::READABLE | SegmentFlags::EXECUTABLE
+        );
+        assert_eq!(segments[1].vaddr_end(), 0x40_2000);
+        assert_eq!(
+            segments[1].flags,
+            SegmentFlags::READABLE | SegmentFlags::WRITABLE
+        );
+
+        let data = file.segment_data(&segments[1]);
+        assert_eq!(data.as_ptr(), image[0x1100..].as_ptr());
+        assert_eq!(data.len(), 0x10);
+    }
+
+    #[test]
+    fn only_loadable_segments() {
+        let segments = [
+            (PT_PHDR, 0b100, EHDR_SIZE as u64, 0x40_0040, 112, 112),
+            SEGMENTS[0],
+            // empty ones are skipped, wherever they are
+            (PT_LOAD, RW, 0, 0x1000, 0, 0),
+            SEGMENTS[1],
+        ];
+        let image = image(ENTRY, &segments);
+        let file = ElfFile::parse(&image).unwrap();
+
+        assert_eq!(file.program_headers().count(), 4);
+        let loaded: Vec<_> = file.segments().map(|header| header.vaddr).collect();
+        assert_eq!(loaded, [0x40_0000, 0x40_1000]);
+    }
+
+    #[test]
+    fn not_executables() {
+        let mut bad = image(ENTRY, &SEGMENTS);
+        bad[0] = 0;
+        assert_eq!(ElfFile::parse(&bad).err(), Some(ElfError::BadMagic));
+
+        let mut bad = image(ENTRY, &SEGMENTS);
+        bad[4] = 1; // 32-bit
+        assert_eq!(ElfFile::parse(&bad).err(), Some(ElfError::Unsupported));
+
+        let mut bad = image(ENTRY, &SEGMENTS);
+        bad[16] = 3; // a shared object
+        assert_eq!(ElfFile::parse(&bad).err(), Some(ElfError::WrongType));
+
+        let mut bad = image(ENTRY, &SEGMENTS);
+        bad[18] = 3; // i386
+        assert_eq!(ElfFile::parse(&bad).err(), Some(ElfError::WrongType));
+    }
+
+    #[test]
+    fn truncated() {
+        let image = image(ENTRY, &SEGMENTS);
+        assert_eq!(ElfFile::parse(&[]).err(), Some(ElfError::Truncated));
+        assert_eq!(
+            ElfFile::parse(&image[..EHDR_SIZE - 1]).err(),
+            Some(ElfError::Truncated)
+        );
+        // the program header table's cut short
+        assert_eq!(
+            ElfFile::parse(&image[..EHDR_SIZE + 2 * PHDR_SIZE - 1]).err(),
+            Some(ElfError::Truncated)
+        );
+        // as is the data segment's data
+        assert_eq!(
+            ElfFile::parse(&image[..0x110f]).err(),
+            Some(ElfError::BadSegment(1))
+        );
+    }
+
+    #[test]
+    fn bad_program_headers() {
+        assert_eq!(parse(ENTRY, &[]), Err(ElfError::BadProgramHeaders));
+
+        let mut bad = image(ENTRY, &SEGMENTS);
+        put_u16(&mut bad, 54, PHDR_SIZE as u16 - 1);
+        assert_eq!(
+            ElfFile::parse(&bad).err(),
+            Some(ElfError::BadProgramHeaders)
+        );
+
+        let mut bad = image(ENTRY, &SEGMENTS);
+        put_u64(&mut bad, 32, u64::max_value());
+        assert_eq!(
+            ElfFile::parse(&bad).err(),
+            Some(ElfError::BadProgramHeaders)
+        );
+    }
+
+    #[test]
+    fn bad_segments() {
+        // more in the file than in memory
+        let segments = [SEGMENTS[0], (PT_LOAD, RW, 0x1100, 0x40_1000, 0x10, 0x8)];
+        assert_eq!(parse(ENTRY, &segments), Err(ElfError::BadSegment(1)));
+
+        // off the end of the address space
+        let segments = [SEGMENTS[0], (PT_LOAD, RW, 0, u64::max_value(), 0, 2)];
+        assert_eq!(parse(ENTRY, &segments), Err(ElfError::BadSegment(1)));
+
+        // data off the end of the image
+        let segments = [(PT_LOAD, RX, u64::max_value(), 0x40_0000, 1, 0x200)];
+        assert_eq!(parse(ENTRY, &segments), Err(ElfError::BadSegment(0)));
+    }
+
+    #[test]
+    fn overlapping_segments() {
+        let segments = [SEGMENTS[0], (PT_LOAD, RW, 0x1100, 0x40_01ff, 0x10, 0x1000)];
+        assert_eq!(parse(ENTRY, &segments), Err(ElfError::Overlapping(1)));
+
+        // out of order
+        let segments = [SEGMENTS[1], SEGMENTS[0]];
+        assert_eq!(parse(ENTRY, &segments), Err(ElfError::Overlapping(1)));
+    }
+
+    #[test]
+    fn nothing_to_run() {
+        let segments = [(PT_LOAD, RX, 0, 0x40_0000, 0, 0)];
+        assert_eq!(parse(ENTRY, &segments), Err(ElfError::NoSegments));
+
+        // in the data segment
+        assert_eq!(parse(0x40_1000, &SEGMENTS), Err(ElfError::BadEntry));
+        // in neither
+        assert_eq!(parse(0x40_0200, &SEGMENTS), Err(ElfError::BadEntry));
+    }
+}
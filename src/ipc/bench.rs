@@ -0,0 +1,81 @@
+//! A benchmark of [Endpoint] message passing, between two kernel threads. Built with the
+//! `ipc-bench` feature, and started at boot.
+//!
+//! It measures round trips (`call`, answered by `reply_recv`) and one-way `send`s, logging how
+//! long each takes on average and how many went through a second.
+
+use arch::x86_64::tsc;
+use task::{self, Priority};
+use time::{Duration, Instant};
+
+use super::{Endpoint, Message};
+
+/// How many of each operation to time.
+const ITERATIONS: u64 = 10_000;
+
+/// Tells the server to stop.
+const LABEL_STOP: u32 = 1;
+
+lazy_static! {
+    static ref ENDPOINT: Endpoint = Endpoint::new();
+}
+
+/// Starts the benchmark's threads.
+pub fn start() {
+    let server = task::spawn("ipc-bench-server", Priority::DEFAULT, server, 0);
+    if server.is_none() || task::spawn("ipc-bench", Priority::DEFAULT, client, 0).is_none() {
+        warn!("ipc-bench: no room for the benchmark's threads");
+    }
+}
+
+/// Echoes every call back to the caller, until told to stop.
+fn server(_: usize) {
+    // kernel threads are never killed
+    let mut received = ENDPOINT.recv(None).unwrap();
+    while received.msg.label != LABEL_STOP {
+        received = ENDPOINT.reply_recv(received.msg, None).unwrap();
+    }
+    super::reply(Message::default());
+}
+
+fn client(_: usize) {
+    let start = (Instant::now(), tsc::read());
+    for i in 0..ITERATIONS {
+        ENDPOINT
+            .call(0, Message::new(0, [i, 0, 0, 0]), None)
+            .unwrap();
+    }
+    report("call/reply_recv round trip", start);
+
+    let start = (Instant::now(), tsc::read());
+    for i in 0..ITERATIONS {
+        ENDPOINT.send(0, Message::new(0, [i, 0, 0, 0])).unwrap();
+    }
+    report("send", start);
+
+    ENDPOINT
+        .call(0, Message::new(LABEL_STOP, [0; 4]), None)
+        .unwrap();
+}
+
+/// Logs how long [ITERATIONS] of `what` took, since `start`.
+fn report(what: &str, start: (Instant, u64)) {
+    let elapsed = start.0.elapsed();
+    let cycles = tsc::read().wrapping_sub(start.1);
+
+    let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+    let per_op = Duration::from_nanos(nanos / ITERATIONS);
+    let per_sec = if nanos == 0 {
+        0
+    } else {
+        ITERATIONS * 1_000_000_000 / nanos
+    };
+    info!(
+        "ipc-bench: {} x{}: {:?} each ({} cycles), {} a second",
+        what,
+        ITERATIONS,
+        per_op,
+        cycles / ITERATIONS,
+        per_sec
+    );
+}
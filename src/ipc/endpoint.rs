@@ -0,0 +1,427 @@
+//! Endpoints, and the rendezvous between their senders and receivers.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{fmt, mem};
+use spin::Mutex as SpinMutex;
+
+use super::notification;
+use super::{IpcError, Message, Received};
+use arch::x86_64::interrupts::without_interrupts;
+use cap::{self, SlotRef};
+use task::{self, ThreadId};
+
+/// How far a blocked thread's message (or the one it's waiting for) has got.
+pub(super) enum Handoff {
+    /// Nothing's happened yet.
+    Pending,
+    /// Something's been found for the thread (a message, or a notification), and is on its way;
+    /// or, for a sender, a receiver's found it.
+    Claimed,
+    /// The thread was killed before anything was found for it, and gave up waiting.
+    Cancelled,
+    /// The endpoint was destroyed before anything was found for the thread.
+    Destroyed,
+    /// The thread's call was received by the given thread, which is yet to reply.
+    Accepted(ThreadId),
+    /// The thread's message was received.
+    Sent,
+    /// The message (or reply) the thread was waiting for.
+    Done(Received),
+}
+
+/// A thread waiting to hear how its message went, or for one to arrive.
+#[derive(Clone)]
+pub(super) struct Waiter {
+    pub(super) thread: ThreadId,
+    /// Where a capability coming with whatever arrives should go.
+    cap_slot: Option<SlotRef>,
+    handoff: Arc<SpinMutex<Handoff>>,
+}
+
+impl Waiter {
+    /// The current thread, which will receive a capability into `cap_slot`.
+    fn current(cap_slot: Option<SlotRef>) -> Waiter {
+        Waiter {
+            thread: task::current().expect("ipc: used before task::init()"),
+            cap_slot,
+            handoff: Arc::new(SpinMutex::new(Handoff::Pending)),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        task::priority(self.thread).is_some()
+    }
+
+    /// Reserves the waiter for whoever's found something for it, if nobody else has (and it
+    /// hasn't given up waiting).
+    pub(super) fn claim(&self) -> bool {
+        without_interrupts(|| {
+            let mut handoff = self.handoff.lock();
+            match *handoff {
+                Handoff::Pending => {
+                    *handoff = Handoff::Claimed;
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Tells the waiter how things went, and wakes it up.
+    pub(super) fn finish(&self, handoff: Handoff) -> bool {
+        without_interrupts(|| *self.handoff.lock() = handoff);
+        task::wake(self.thread)
+    }
+
+    /// Puts back a waiter that was claimed, but whose rendezvous fell through.
+    fn unclaim(&self) {
+        without_interrupts(|| *self.handoff.lock() = Handoff::Pending);
+        // it may have been killed, and have gone back to sleep seeing it was claimed
+        task::wake(self.thread);
+    }
+
+    /// Blocks until the handoff's done (lending priority to whoever's accepted it), and returns
+    /// whatever arrived.
+    ///
+    /// If the thread's killed, it gives up, unless something's already on its way: it mustn't
+    /// leave whoever's claimed it with nowhere to put it.
+    fn wait(&self) -> Result<Option<Received>, IpcError> {
+        loop {
+            let killed = task::killed();
+            let state = without_interrupts(|| {
+                let mut handoff = self.handoff.lock();
+                match *handoff {
+                    Handoff::Pending if killed => {
+                        *handoff = Handoff::Cancelled;
+                        Ok(Err(IpcError::Interrupted))
+                    }
+                    Handoff::Accepted(_) if killed => Ok(Err(IpcError::Interrupted)),
+                    Handoff::Pending | Handoff::Claimed => Err(None),
+                    Handoff::Accepted(server) => Err(Some(server)),
+                    Handoff::Cancelled => Ok(Err(IpcError::Interrupted)),
+                    Handoff::Destroyed => Ok(Err(IpcError::Destroyed)),
+                    Handoff::Sent => Ok(Ok(None)),
+                    Handoff::Done(received) => Ok(Ok(Some(received))),
+                }
+            });
+
+            match state {
+                Ok(result) => return result,
+                Err(Some(server)) => task::block_on(server),
+                Err(None) => task::block(),
+            }
+        }
+    }
+}
+
+/// A message waiting to be received.
+struct Sender {
+    waiter: Waiter,
+    badge: u64,
+    msg: Message,
+    /// Whether the sender's waiting for a reply.
+    call: bool,
+}
+
+struct Queues {
+    senders: VecDeque<Sender>,
+    receivers: VecDeque<Waiter>,
+    /// Whether the endpoint's last capability has been deleted, so nobody will ever meet anyone
+    /// here again.
+    destroyed: bool,
+}
+
+/// A rendezvous point for synchronous message passing. See the [module docs](index.html).
+pub struct Endpoint {
+    // only ever locked with interrupts off; may be held while taking the scheduler's lock
+    queues: SpinMutex<Queues>,
+    /// How many capabilities refer to the endpoint.
+    caps: AtomicUsize,
+}
+
+lazy_static! {
+    /// Who each thread that's received a call owes a reply to. Only ever locked with interrupts
+    /// off.
+    static ref REPLIES: SpinMutex<BTreeMap<ThreadId, Waiter>> = SpinMutex::new(BTreeMap::new());
+}
+
+/// Copies the capability `msg` carries (if there is one, and somewhere to put it) to `cap_slot`,
+/// and returns the message as it arrives.
+fn transfer(badge: u64, msg: Message, cap_slot: Option<SlotRef>) -> Received {
+    let cap = match (msg.cap, cap_slot) {
+        (Some(src), Some(dst)) => match cap::copy(src, dst) {
+            Ok(()) => Some(dst),
+            Err(err) => {
+                debug!("ipc: dropped capability {} sent to {}: {:?}", src, dst, err);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    Received {
+        badge,
+        msg: Message { cap, ..msg },
+        notification: false,
+    }
+}
+
+/// Fails `waiter`, the current thread, because the endpoint it was about to wait on is destroyed.
+/// Unless something's already been found for it, it'll find out as soon as it waits.
+fn refuse(waiter: &Waiter) {
+    if waiter.claim() {
+        without_interrupts(|| *waiter.handoff.lock() = Handoff::Destroyed);
+    }
+}
+
+/// What a caller gets if its call's abandoned.
+fn empty_reply() -> Handoff {
+    Handoff::Done(Received {
+        badge: 0,
+        msg: Message::default(),
+        notification: false,
+    })
+}
+
+/// Makes `server`, which has received `caller`'s call, the one to reply to it. A call `server`
+/// hadn't replied to yet is abandoned. `wake` the caller if it's already blocked, so that it
+/// blocks again lending `server` its priority.
+fn accept(server: ThreadId, caller: Waiter, wake: bool) {
+    without_interrupts(|| *caller.handoff.lock() = Handoff::Accepted(server));
+    if wake {
+        task::wake(caller.thread);
+    }
+
+    if let Some(abandoned) = without_interrupts(|| REPLIES.lock().insert(server, caller)) {
+        abandoned.finish(empty_reply());
+    }
+}
+
+impl Endpoint {
+    /// A new endpoint, with nobody waiting on it.
+    pub fn new() -> Endpoint {
+        Endpoint {
+            queues: SpinMutex::new(Queues {
+                senders: VecDeque::new(),
+                receivers: VecDeque::new(),
+                destroyed: false,
+            }),
+            caps: AtomicUsize::new(0),
+        }
+    }
+
+    /// Notes that a capability to the endpoint has been made.
+    pub fn cap_created(&self) {
+        self.caps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Notes that a capability to the endpoint has been deleted. Once the last one has gone, the
+    /// endpoint's destroyed: everyone waiting to send or receive on it (or who tries to later)
+    /// gets [IpcError::Destroyed](enum.IpcError.html#variant.Destroyed).
+    pub fn cap_deleted(&self) {
+        if self.caps.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.destroy();
+        }
+    }
+
+    fn destroy(&self) {
+        let (senders, receivers) = without_interrupts(|| {
+            let mut queues = self.queues.lock();
+            queues.destroyed = true;
+            (
+                mem::replace(&mut queues.senders, VecDeque::new()),
+                mem::replace(&mut queues.receivers, VecDeque::new()),
+            )
+        });
+
+        // skipping any that have given up waiting, or been claimed by a notification
+        let waiters = senders.into_iter().map(|sender| sender.waiter);
+        for waiter in waiters.chain(receivers).filter(Waiter::claim) {
+            waiter.finish(Handoff::Destroyed);
+        }
+    }
+
+    /// Pairs `sender` with a waiting receiver, or (if there isn't one) queues it.
+    fn rendezvous_send(&self, sender: Sender) -> Option<(Sender, Waiter)> {
+        without_interrupts(|| {
+            let mut queues = self.queues.lock();
+            if queues.destroyed {
+                refuse(&sender.waiter);
+                return None;
+            }
+
+            while let Some(receiver) = queues.receivers.pop_front() {
+                // one woken by a notification is still queued
+                if receiver.is_alive() && receiver.claim() {
+                    return Some((sender, receiver));
+                }
+            }
+
+            queues.senders.push_back(sender);
+            None
+        })
+    }
+
+    /// Pairs `receiver` with a waiting sender, or (if there isn't one, or a notification got
+    /// there first) queues it.
+    fn rendezvous_recv(&self, receiver: Waiter) -> Option<(Sender, Waiter)> {
+        without_interrupts(|| {
+            let mut queues = self.queues.lock();
+            if queues.destroyed {
+                refuse(&receiver);
+                return None;
+            }
+
+            while let Some(sender) = queues.senders.pop_front() {
+                // one that's given up waiting is still queued
+                if !sender.waiter.is_alive() || !sender.waiter.claim() {
+                    continue;
+                }
+                if receiver.claim() {
+                    return Some((sender, receiver));
+                }
+
+                sender.waiter.unclaim();
+                queues.senders.push_front(sender);
+                break;
+            }
+
+            queues.receivers.push_back(receiver);
+            None
+        })
+    }
+
+    /// Sends `msg` through a capability badged `badge`, blocking until it's received.
+    ///
+    /// If the current thread's killed (or the endpoint's destroyed) before the message is
+    /// received, it's never sent.
+    pub fn send(&self, badge: u64, msg: Message) -> Result<(), IpcError> {
+        let waiter = Waiter::current(None);
+        let sender = Sender {
+            waiter: waiter.clone(),
+            badge,
+            msg,
+            call: false,
+        };
+
+        match self.rendezvous_send(sender) {
+            Some((_, receiver)) => {
+                receiver.finish(Handoff::Done(transfer(badge, msg, receiver.cap_slot)));
+                // the receiver may well be more important than us
+                task::preempt();
+                Ok(())
+            }
+            None => waiter.wait().map(|_| ()),
+        }
+    }
+
+    /// Sends `msg` through a capability badged `badge`, and blocks until it's received and
+    /// replied to. Returns the reply, whose capability (if it has one) goes to `cap_slot`.
+    ///
+    /// If the receiver never replies (because it ends first, or receives another call before
+    /// replying), the reply is empty. If the current thread's killed, it stops waiting, as it
+    /// does if the endpoint's destroyed before the call's received.
+    pub fn call(
+        &self,
+        badge: u64,
+        msg: Message,
+        cap_slot: Option<SlotRef>,
+    ) -> Result<Received, IpcError> {
+        let waiter = Waiter::current(cap_slot);
+        let caller = Sender {
+            waiter: waiter.clone(),
+            badge,
+            msg,
+            call: true,
+        };
+
+        if let Some((caller, receiver)) = self.rendezvous_send(caller) {
+            accept(receiver.thread, caller.waiter, false);
+            receiver.finish(Handoff::Done(transfer(badge, msg, receiver.cap_slot)));
+        }
+
+        waiter
+            .wait()
+            .map(|reply| reply.expect("ipc: call finished without a reply"))
+    }
+
+    /// Blocks until a message arrives, and returns it. A capability coming with it goes to
+    /// `cap_slot`; without one, it's dropped.
+    ///
+    /// If the message is a call, the current thread is the one to [reply] to it. If the thread
+    /// has a [Notification] bound to it, that being signalled ends the wait too, as do the thread
+    /// being killed and the endpoint being destroyed.
+    ///
+    /// [reply]: fn.reply.html
+    /// [Notification]: struct.Notification.html
+    pub fn recv(&self, cap_slot: Option<SlotRef>) -> Result<Received, IpcError> {
+        let waiter = Waiter::current(cap_slot);
+        if let Some(notification) = notification::bound_to(waiter.thread) {
+            if let Some(received) = notification.arm(&waiter) {
+                return Ok(received);
+            }
+        }
+
+        match self.rendezvous_recv(waiter.clone()) {
+            Some((sender, _)) => {
+                let received = transfer(sender.badge, sender.msg, cap_slot);
+                if sender.call {
+                    accept(waiter.thread, sender.waiter, true);
+                } else {
+                    sender.waiter.finish(Handoff::Sent);
+                }
+                Ok(received)
+            }
+            None => waiter
+                .wait()
+                .map(|received| received.expect("ipc: receive finished without a message")),
+        }
+    }
+
+    /// [Reply]s to the current thread's last caller (if it owes one a reply) with `msg`, then
+    /// waits for the next message, like [recv](#method.recv).
+    ///
+    /// [Reply]: fn.reply.html
+    pub fn reply_recv(
+        &self,
+        msg: Message,
+        cap_slot: Option<SlotRef>,
+    ) -> Result<Received, IpcError> {
+        reply(msg);
+        self.recv(cap_slot)
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Endpoint {
+        Endpoint::new()
+    }
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Endpoint({:p})", self)
+    }
+}
+
+/// Replies to the call the current thread last received with `msg`, waking its caller. Returns
+/// `false` if there's no call to reply to (or the caller's gone).
+pub fn reply(msg: Message) -> bool {
+    let me = task::current().expect("ipc: used before task::init()");
+    match without_interrupts(|| REPLIES.lock().remove(&me)) {
+        Some(caller) => {
+            let received = transfer(0, msg, caller.cap_slot);
+            caller.finish(Handoff::Done(received))
+        }
+        None => false,
+    }
+}
+
+/// Gives the call thread `id` was yet to reply to (if any) an empty reply.
+pub(super) fn abandon_reply(id: ThreadId) {
+    if let Some(caller) = without_interrupts(|| REPLIES.lock().remove(&id)) {
+        caller.finish(empty_reply());
+    }
+}
@@ -0,0 +1,99 @@
+//! Inter-process communication.
+//!
+//! Threads talk through [Endpoint]s: rendezvous points where a sender and a receiver meet, each
+//! blocking until the other turns up. A message is small enough to be passed in registers: a
+//! label and [MSG_WORDS] words, and optionally a capability, which the receiver gets a copy of.
+//!
+//! Whoever receives a message also learns the badge of the capability it was sent through, so a
+//! server which hands each client a differently badged copy of its endpoint capability can tell
+//! them apart.
+//!
+//! An endpoint lasts as long as capabilities to it do. Once the last is deleted, whoever's still
+//! waiting to send or receive on it gives up, [destroyed](enum.IpcError.html#variant.Destroyed).
+//!
+//! # Calls
+//! [call](struct.Endpoint.html#method.call) sends a message and waits for the receiver to
+//! [reply]; the caller lends the receiver its priority in the meantime. A server typically loops
+//! on [reply_recv](struct.Endpoint.html#method.reply_recv), replying to its last caller and
+//! waiting for the next in one go.
+//!
+//! # Notifications
+//! For signalling that mustn't block (forwarding interrupts, say), there are [Notification]s: a
+//! word of bits that signalling ORs into, and waiting takes. A thread with a notification
+//! [bound](fn.bind.html) to it hears of it being signalled while it's waiting on an endpoint, so
+//! a server can wait for requests and events at once.
+
+#[cfg(feature = "ipc-bench")]
+pub mod bench;
+mod endpoint;
+mod notification;
+
+use cap::SlotRef;
+use task::ThreadId;
+
+pub use self::endpoint::{reply, Endpoint};
+pub use self::notification::{bind, unbind, Notification};
+
+/// How many words a message carries, besides its label.
+pub const MSG_WORDS: usize = 4;
+
+/// A message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Message {
+    /// What the message is about; its meaning is up to the sender and receiver.
+    pub label: u32,
+    pub words: [u64; MSG_WORDS],
+    /// A capability going along with the message: the slot it's sent from, or (once received)
+    /// the slot it was copied to.
+    pub cap: Option<SlotRef>,
+}
+
+impl Message {
+    /// A message with no capability.
+    pub fn new(label: u32, words: [u64; MSG_WORDS]) -> Message {
+        Message {
+            label,
+            words,
+            cap: None,
+        }
+    }
+}
+
+/// A message as it arrives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Received {
+    /// The badge of the capability the message was sent through; 0 for replies. For a
+    /// notification, the word it was signalled with.
+    pub badge: u64,
+    pub msg: Message,
+    /// Whether this is the receiving thread's bound notification being signalled, rather than a
+    /// message; if it is, `msg` is empty.
+    pub notification: bool,
+}
+
+/// A wait cut short because the waiting thread was killed. It ends on its way back to user mode,
+/// so there's no use carrying on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interrupted;
+
+/// Why sending or receiving on an [Endpoint] failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpcError {
+    /// The waiting thread was killed; see [Interrupted].
+    Interrupted,
+    /// The endpoint was destroyed (its last capability deleted) before the message got through.
+    Destroyed,
+}
+
+impl From<Interrupted> for IpcError {
+    fn from(_: Interrupted) -> IpcError {
+        IpcError::Interrupted
+    }
+}
+
+/// Cleans up after thread `id`, which has ended: a call it was yet to reply to gets an empty
+/// reply, and whatever notification was bound to it is unbound.
+pub fn thread_exited(id: ThreadId) {
+    endpoint::abandon_reply(id);
+    notification::unbind(id);
+}
@@ -0,0 +1,180 @@
+//! Notifications: words of bits which can be signalled without blocking.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::{fmt, mem};
+use spin::Mutex as SpinMutex;
+
+use super::endpoint::{Handoff, Waiter};
+use super::{Interrupted, Message, Received};
+use arch::x86_64::interrupts::without_interrupts;
+use task::{self, ThreadId};
+
+struct State {
+    /// The bits signalled since the notification was last waited on.
+    word: u64,
+    /// Threads blocked in [wait](struct.Notification.html#method.wait).
+    waiters: VecDeque<ThreadId>,
+    /// The thread the notification's bound to, if any.
+    bound: Option<ThreadId>,
+    /// The bound thread, if it's waiting on an endpoint.
+    bound_receiver: Option<Waiter>,
+}
+
+/// A word of bits, which signalling sets and waiting takes (and clears).
+///
+/// Signalling never blocks, so it can be done from anywhere threads can be woken from: deferred
+/// work, say. A notification can also be bound to a thread, which then hears of it being signalled
+/// while it's waiting for a message on an endpoint.
+pub struct Notification {
+    // only ever locked with interrupts off
+    state: SpinMutex<State>,
+}
+
+lazy_static! {
+    /// The notification bound to each thread that has one. Only ever locked with interrupts off,
+    /// and before any notification's own lock.
+    static ref BINDINGS: SpinMutex<BTreeMap<ThreadId, Arc<Notification>>> =
+        SpinMutex::new(BTreeMap::new());
+}
+
+impl Notification {
+    /// A new notification, with no bits set.
+    pub fn new() -> Notification {
+        Notification {
+            state: SpinMutex::new(State {
+                word: 0,
+                waiters: VecDeque::new(),
+                bound: None,
+                bound_receiver: None,
+            }),
+        }
+    }
+
+    /// Sets `bits` in the word. If the bound thread's waiting for a message, it gets the word;
+    /// otherwise, a (live) thread [wait](#method.wait)ing for it is woken.
+    pub fn signal(&self, bits: u64) {
+        let (receiver, mut waiter) = without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.word |= bits;
+
+            if let Some(receiver) = state.bound_receiver.take() {
+                // it may have got a message since
+                if receiver.claim() {
+                    let word = mem::replace(&mut state.word, 0);
+                    return (Some((receiver, word)), None);
+                }
+            }
+            (None, state.waiters.pop_front())
+        });
+
+        if let Some((receiver, word)) = receiver {
+            receiver.finish(Handoff::Done(Received {
+                badge: word,
+                msg: Message::default(),
+                notification: true,
+            }));
+        }
+        // one that's ended since it started waiting is still queued
+        while let Some(thread) = waiter {
+            if task::wake(thread) {
+                break;
+            }
+            waiter = without_interrupts(|| self.state.lock().waiters.pop_front());
+        }
+    }
+
+    /// Takes the word, clearing it, without blocking: 0 if nothing's been signalled.
+    pub fn poll(&self) -> u64 {
+        without_interrupts(|| mem::replace(&mut self.state.lock().word, 0))
+    }
+
+    /// Blocks until a bit's been signalled, then takes the word, clearing it. Gives up if the
+    /// current thread's killed.
+    pub fn wait(&self) -> Result<u64, Interrupted> {
+        let me = task::current().expect("ipc: used before task::init()");
+        loop {
+            let killed = task::killed();
+            let word = without_interrupts(|| {
+                let mut state = self.state.lock();
+                if killed {
+                    state.waiters.retain(|&w| w != me);
+                } else if state.word == 0 && !state.waiters.contains(&me) {
+                    state.waiters.push_back(me);
+                }
+                mem::replace(&mut state.word, 0)
+            });
+            if word != 0 {
+                return Ok(word);
+            }
+            if killed {
+                return Err(Interrupted);
+            }
+
+            task::block();
+        }
+    }
+
+    /// Takes the word if any bits are set; otherwise, has `receiver` (the bound thread, about to
+    /// wait on an endpoint) hear of the next signal.
+    pub(super) fn arm(&self, receiver: &Waiter) -> Option<Received> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.word == 0 {
+                state.bound_receiver = Some(receiver.clone());
+                return None;
+            }
+
+            Some(Received {
+                badge: mem::replace(&mut state.word, 0),
+                msg: Message::default(),
+                notification: true,
+            })
+        })
+    }
+}
+
+impl Default for Notification {
+    fn default() -> Notification {
+        Notification::new()
+    }
+}
+
+impl fmt::Debug for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Notification({:p})", self)
+    }
+}
+
+/// Binds `notification` to thread `thread`. Returns `false` if either's already bound.
+pub fn bind(notification: &Arc<Notification>, thread: ThreadId) -> bool {
+    without_interrupts(|| {
+        let mut bindings = BINDINGS.lock();
+        let mut state = notification.state.lock();
+        if state.bound.is_some() || bindings.contains_key(&thread) {
+            return false;
+        }
+
+        state.bound = Some(thread);
+        bindings.insert(thread, Arc::clone(notification));
+        true
+    })
+}
+
+/// Unbinds whatever notification's bound to thread `thread`. Returns `false` if there isn't one.
+pub fn unbind(thread: ThreadId) -> bool {
+    without_interrupts(|| match BINDINGS.lock().remove(&thread) {
+        Some(notification) => {
+            let mut state = notification.state.lock();
+            state.bound = None;
+            state.bound_receiver = None;
+            true
+        }
+        None => false,
+    })
+}
+
+/// The notification bound to thread `thread`, if there is one.
+pub(super) fn bound_to(thread: ThreadId) -> Option<Arc<Notification>> {
+    without_interrupts(|| BINDINGS.lock().get(&thread).cloned())
+}
@@ -0,0 +1,117 @@
+//! IRQ handler objects, through which drivers outside the kernel get their devices' interrupts.
+//!
+//! Whoever holds the IRQ control capability (which the first process the kernel starts gets, in
+//! slot [CONTROL_SLOT]; see [grant_control]) can [claim] an IRQ line, getting an [IrqHandler] for
+//! it, and bind that to a [Notification]. Each time the IRQ arrives, the kernel masks the line and
+//! signals the notification; the line stays masked until the driver's dealt with its device and
+//! [acknowledged](struct.IrqHandler.html#method.ack) the IRQ. A freshly claimed line is masked
+//! until it's first acknowledged, so that the driver can bind it first.
+//!
+//! Only the legacy (PIC) lines can be claimed, and not the cascade line (IRQ 2), which the
+//! secondary PIC's lines depend on: there's no MSI support to hand vectors out from yet.
+//!
+//! [Notification]: ../ipc/struct.Notification.html
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use spin::Mutex as SpinMutex;
+
+use arch::x86_64::interrupts::irq as line;
+use arch::x86_64::interrupts::without_interrupts;
+use cap::{self, CapError, Capability, Object, Rights, SlotRef};
+use deferred;
+use ipc::Notification;
+
+pub use arch::x86_64::interrupts::irq::{is_reserved, IRQ_COUNT};
+
+/// A claimed IRQ line. The line's given up when this is dropped (along with the last capability
+/// to it).
+#[derive(Debug)]
+pub struct IrqHandler {
+    irq: u8,
+}
+
+/// The notification an IRQ line's bound to, and the bits it's signalled with.
+struct Binding {
+    notification: Arc<Notification>,
+    bits: u64,
+}
+
+lazy_static! {
+    // only ever locked with interrupts off
+    static ref BINDINGS: SpinMutex<BTreeMap<u8, Binding>> = SpinMutex::new(BTreeMap::new());
+}
+
+/// Where in the first process's capability space the kernel puts the IRQ control capability.
+pub const CONTROL_SLOT: usize = 0;
+
+/// Puts an IRQ control capability, which can [claim] IRQ lines, in `slot`. For the kernel to give
+/// whichever process manages drivers.
+pub fn grant_control(slot: SlotRef) -> Result<(), CapError> {
+    cap::insert(slot, Capability::new(Object::IrqControl, Rights::all()))
+}
+
+/// Claims IRQ line `irq` for handling outside the kernel. Returns `None` if there's no such line,
+/// it's [reserved](fn.is_reserved.html), or the kernel (or someone else) is handling it already.
+pub fn claim(irq: u8) -> Option<IrqHandler> {
+    if irq as usize >= IRQ_COUNT || !line::forward(irq, forward) {
+        return None;
+    }
+
+    debug!("irq: IRQ {} claimed", irq);
+    Some(IrqHandler { irq })
+}
+
+impl IrqHandler {
+    /// The line's number.
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    /// Has the IRQ signal `bits` on `notification`, instead of whatever it was bound to before.
+    pub fn bind(&self, notification: Arc<Notification>, bits: u64) {
+        let binding = Binding { notification, bits };
+        without_interrupts(|| BINDINGS.lock().insert(self.irq, binding));
+    }
+
+    /// Stops the IRQ signalling anything. Until it's bound again, its interrupts are dropped.
+    pub fn unbind(&self) {
+        without_interrupts(|| BINDINGS.lock().remove(&self.irq));
+    }
+
+    /// Acknowledges the IRQ, unmasking the line so that it can arrive again.
+    pub fn ack(&self) {
+        line::unmask(self.irq);
+    }
+}
+
+impl Drop for IrqHandler {
+    fn drop(&mut self) {
+        line::unregister(self.irq);
+        self.unbind();
+        debug!("irq: IRQ {} released", self.irq);
+    }
+}
+
+/// Called by the dispatcher, with the line masked, when a claimed IRQ arrives.
+fn forward(irq: u8) {
+    if deferred::defer(signal, irq as usize).is_err() {
+        // let it come again, rather than leave the driver waiting forever
+        warn!("irq: deferred queue full; dropping IRQ {}", irq);
+        line::unmask(irq);
+    }
+}
+
+/// Signals whatever IRQ `irq`'s bound to.
+fn signal(irq: usize) {
+    let binding = without_interrupts(|| {
+        BINDINGS
+            .lock()
+            .get(&(irq as u8))
+            .map(|b| (Arc::clone(&b.notification), b.bits))
+    });
+
+    if let Some((notification, bits)) = binding {
+        notification.signal(bits);
+    }
+}
@@ -9,7 +9,8 @@
     abi_x86_interrupt,
     panic_info_message
 )]
-#![no_std]
+// unit tests run on the host, with std
+#![cfg_attr(not(test), no_std)]
 #![cfg_attr(feature = "cargo-clippy", allow(large_digit_groups))]
 
 #[macro_use]
@@ -25,6 +26,7 @@ extern crate log;
 extern crate multiboot2;
 #[macro_use]
 extern crate once;
+extern crate raw_cpuid;
 extern crate rlibc;
 extern crate spin;
 extern crate volatile;
@@ -36,40 +38,81 @@ extern crate x86_64;
 pub mod macros;
 pub mod alloca;
 pub mod arch;
+pub mod cap;
 mod consts;
+pub mod deferred;
+pub mod elf;
+pub mod ipc;
+pub mod irq;
 mod logger;
+#[cfg(not(test))]
 pub mod panic;
+pub mod syscall;
+pub mod task;
+pub mod time;
 
+#[cfg(not(test))]
 use alloca::Allocator;
 
 /// Our globally-visible allocator. Plugs into whatever allocator we set up in [`alloca`].
 //
 /// [`alloca`]: alloca/index.html
+#[cfg(not(test))]
 #[global_allocator]
 static GLOBAL_ALLOC: Allocator = Allocator {};
 
 /// Kernel main function. Called by the architecture-specific entry point,
-/// after initialization is finished.
-pub fn kernel_main() -> ! {
+/// after initialization is finished, with the executable to start first (if the boot loader
+/// was given one).
+pub fn kernel_main(init: Option<&'static [u8]>) -> ! {
     info!("arch-init: done, entering kernel_main");
 
-    // spin
-    unsafe {
-        loop {
-            arch::x86_64::halt();
+    match init {
+        Some(image) => start_init(image),
+        None => warn!("init: no init module; there's nothing to run"),
+    }
+
+    #[cfg(feature = "ipc-bench")]
+    ipc::bench::start();
+
+    // this is the boot CPU's idle thread: run whatever's ready, picking up any deferred work
+    // interrupts leave us, and idle when there's nothing to do
+    loop {
+        deferred::run_pending();
+        if !task::yield_now() {
+            time::tickless::idle();
         }
     }
 }
 
+/// Starts the executable `image` as the first process, `init`, which gets the IRQ control
+/// capability.
+fn start_init(image: &[u8]) {
+    let pid = task::process::create("init");
+    // before its thread starts, so that it's there from the first instruction
+    let control = cap::SlotRef::new(pid, irq::CONTROL_SLOT);
+    if let Err(err) = irq::grant_control(control) {
+        warn!("init: couldn't grant IRQ control: {:?}", err);
+    }
+
+    // the loader's logged why if it couldn't
+    if task::loader::start(pid, "init", image, &["init"], &[], task::Priority::DEFAULT).is_ok() {
+        info!("init: started as process {}", pid);
+    }
+}
+
 /// Related to stack landing pads. Don't care, do nothing.
+#[cfg(not(test))]
 #[lang = "eh_personality"]
 #[no_mangle]
 pub extern "C" fn eh_personality() {}
 
-/// Stack unwinding. Don't care, just halt.
+/// Stack unwinding. Don't care, just halt (for good).
+#[cfg(not(test))]
 #[allow(non_snake_case)]
 #[no_mangle]
 pub extern "C" fn _Unwind_Resume() -> ! {
+    x86_64::instructions::interrupts::disable();
     unsafe {
         loop {
             arch::x86_64::halt();
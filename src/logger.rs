@@ -32,8 +32,17 @@ impl Log for KernelLogger {
             #[cfg(feature = "logging-serial")]
             {
                 use core::fmt::Write;
+                let uptime = ::time::uptime();
                 interrupts::without_interrupts(|| {
-                    writeln!(COM1.write(), "[{}]: {}", record.level(), record.args()).unwrap()
+                    writeln!(
+                        COM1.write(),
+                        "[{:>5}.{:06}] [{}]: {}",
+                        uptime.as_secs(),
+                        uptime.subsec_micros(),
+                        record.level(),
+                        record.args()
+                    )
+                    .unwrap()
                 });
             }
             #[cfg(feature = "logging-console")]
@@ -0,0 +1,601 @@
+//! System calls.
+//!
+//! The architecture's entry path (see `arch::x86_64::syscall`) saves the caller's registers and
+//! calls [dispatch] with the system call number and its arguments.
+//!
+//! # ABI
+//! The call number goes in `rax`, and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8`
+//! and `r9`. The result comes back in `rax`: a call which succeeds returns a value below
+//! 2<sup>63</sup>; one which fails returns an [Error]'s code, negated. `rcx` and `r11` are
+//! clobbered; every other register is preserved, except that calls which receive a message
+//! return it in the argument registers. The numbers, codes and meanings of calls are stable: new
+//! ones get new numbers.
+//!
+//! # Messages
+//! The IPC calls pass a message (see the `ipc` module) in registers: an [info] word in `rsi`, and
+//! the message's words in `rdx`, `r10`, `r8` and `r9`. A received message comes back the same
+//! way, with the badge of the capability it was sent through in `rdi`.
+//!
+//! Pointer arguments are user addresses in the caller's address space. They're only ever
+//! accessed through `usermode::copy_from_user` and `usermode::copy_to_user`, so a bad one gets
+//! [Error::BadAddress], rather than a kernel fault.
+
+use alloc::sync::Arc;
+use core::{cmp, str};
+
+use arch::x86_64::memory::paging::VirtualAddress;
+use arch::x86_64::usermode::{self, BadAddress};
+use cap::{self, CapError, Capability, Object, Rights, SlotRef};
+use ipc::{self, Endpoint, Interrupted, IpcError, Message, Notification, Received, MSG_WORDS};
+use irq::{self, IrqHandler};
+use task::{self, process, Priority};
+use time::{timer, Duration, Instant};
+
+/// System call numbers.
+pub mod nr {
+    /// `yield()`: give up the rest of the calling thread's timeslice.
+    pub const YIELD: u64 = 0;
+    /// `exit(status)`: end the calling thread. Its process ends with its last thread.
+    pub const EXIT: u64 = 1;
+    /// `debug_write(buf, len) -> written`: write the `len` bytes of UTF-8 text at `buf` to the
+    /// kernel log. Writes at most [DEBUG_WRITE_MAX](../constant.DEBUG_WRITE_MAX.html) bytes.
+    pub const DEBUG_WRITE: u64 = 2;
+    /// `sleep(ns)`: block the calling thread for (at least) `ns` nanoseconds.
+    pub const SLEEP: u64 = 3;
+    /// `thread_create(entry, arg, priority) -> thread`: start a thread in the calling process,
+    /// running from `entry` with `arg` in `rdi`, at a priority no higher than the caller's. A
+    /// process can have at most `task::process::MAX_THREADS` threads.
+    pub const THREAD_CREATE: u64 = 4;
+    /// `cap_copy(src, dst)`: copy the capability in slot `src` to the empty slot `dst`.
+    pub const CAP_COPY: u64 = 5;
+    /// `cap_mint(src, dst, rights, badge)`: copy the capability in slot `src` to the empty slot
+    /// `dst`, keeping only `rights`, and badging it with `badge` (unless that's 0).
+    pub const CAP_MINT: u64 = 6;
+    /// `cap_move(src, dst)`: move the capability in slot `src` to the empty slot `dst`.
+    pub const CAP_MOVE: u64 = 7;
+    /// `cap_delete(slot)`: delete the capability in `slot`.
+    pub const CAP_DELETE: u64 = 8;
+    /// `cap_revoke(slot)`: delete every capability derived from the one in `slot`, in any
+    /// process.
+    pub const CAP_REVOKE: u64 = 9;
+    /// `cap_identify(slot) -> kind | rights << 8`: what kind of object the capability in `slot`
+    /// refers to, and what rights it has.
+    pub const CAP_IDENTIFY: u64 = 10;
+    /// `endpoint_create(slot)`: make an endpoint, putting a capability to it with every right in
+    /// the empty slot `slot`.
+    pub const ENDPOINT_CREATE: u64 = 11;
+    /// `send(endpoint, info, words...)`: send a message through the endpoint capability in slot
+    /// `endpoint` (which needs `WRITE`), blocking until it's received.
+    pub const SEND: u64 = 12;
+    /// `recv(endpoint, info) -> message`: wait for a message on the endpoint capability in slot
+    /// `endpoint` (which needs `READ`).
+    pub const RECV: u64 = 13;
+    /// `call(endpoint, info, words...) -> message`: send a message like `send`, and wait for the
+    /// receiver's reply.
+    pub const CALL: u64 = 14;
+    /// `reply_recv(endpoint, info, words...) -> message`: reply to the last call received, if
+    /// it's not been replied to, then `recv`.
+    pub const REPLY_RECV: u64 = 15;
+    /// `notification_create(slot)`: make a notification, putting a capability to it with every
+    /// right in the empty slot `slot`.
+    pub const NOTIFICATION_CREATE: u64 = 16;
+    /// `signal(notification, bits)`: set `bits`, and the capability's badge, in the notification
+    /// in slot `notification` (which needs `WRITE`). Never blocks.
+    pub const SIGNAL: u64 = 17;
+    /// `wait(notification) -> word`: block until the notification in slot `notification` (which
+    /// needs `READ`) has been signalled, then take its word (in `rdi`), clearing it.
+    pub const WAIT: u64 = 18;
+    /// `poll(notification) -> word`: like `wait`, but without blocking; the word's 0 if nothing's
+    /// been signalled.
+    pub const POLL: u64 = 19;
+    /// `notification_bind(notification)`: bind the notification in slot `notification` (which
+    /// needs `READ`) to the calling thread, so that its `recv`s end if it's signalled.
+    pub const NOTIFICATION_BIND: u64 = 20;
+    /// `notification_unbind()`: unbind the calling thread's notification.
+    pub const NOTIFICATION_UNBIND: u64 = 21;
+    /// `irq_claim(control, irq, slot)`: claim IRQ line `irq` with the IRQ control capability in
+    /// slot `control` (which needs `WRITE`), putting a capability to its handler with every
+    /// right in the empty slot `slot`. The line's masked until it's first acknowledged.
+    pub const IRQ_CLAIM: u64 = 22;
+    /// `irq_bind(handler, notification)`: have the IRQ whose handler capability is in slot
+    /// `handler` (which needs `WRITE`) signal the notification in slot `notification` (which
+    /// needs `WRITE`), with the notification capability's badge, or if it has none, bit `irq`.
+    pub const IRQ_BIND: u64 = 23;
+    /// `irq_ack(handler)`: acknowledge the IRQ whose handler capability is in slot `handler`
+    /// (which needs `WRITE`), unmasking its line.
+    pub const IRQ_ACK: u64 = 24;
+}
+
+/// The bits of an IPC call's `info` word.
+pub mod info {
+    /// The message carries the capability in the slot in bits 8 to 15. Sending one needs `GRANT`
+    /// on the endpoint capability.
+    pub const SEND_CAP: u64 = 1 << 0;
+    /// A capability coming with the message received (or reply) may go in the slot in bits 16
+    /// to 23. Set on a received message if one did.
+    pub const RECV_CAP: u64 = 1 << 1;
+    /// Set on a received "message" which is really the receiver's bound notification being
+    /// signalled. Its word's where a message's badge would be.
+    pub const NOTIFICATION: u64 = 1 << 2;
+    pub const SEND_SLOT_SHIFT: u64 = 8;
+    pub const RECV_SLOT_SHIFT: u64 = 16;
+    /// The message's label is in the top 32 bits.
+    pub const LABEL_SHIFT: u64 = 32;
+}
+
+/// Why a system call failed. Returned to the caller negated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// There's no system call with that number.
+    NoSuchCall = 1,
+    /// A pointer argument doesn't point at (enough) accessible user memory.
+    BadAddress = 2,
+    /// An argument's out of range, or otherwise invalid.
+    InvalidArgument = 3,
+    /// The kernel's run out of something it needs to make the call.
+    OutOfResources = 4,
+    /// The caller isn't allowed to make the call.
+    Denied = 5,
+    /// A capability slot argument is empty, or holds the wrong kind of capability.
+    InvalidCapability = 6,
+    /// A destination capability slot is already in use.
+    SlotOccupied = 7,
+    /// Whatever was asked for is already taken.
+    Busy = 8,
+    /// The caller's process was killed while it was waiting. The caller never sees this, as it
+    /// ends on its way back to user mode.
+    Interrupted = 9,
+    /// The endpoint the caller was waiting on was destroyed: its last capability was deleted.
+    Destroyed = 10,
+}
+
+impl From<BadAddress> for Error {
+    fn from(_: BadAddress) -> Error {
+        Error::BadAddress
+    }
+}
+
+impl From<Interrupted> for Error {
+    fn from(_: Interrupted) -> Error {
+        Error::Interrupted
+    }
+}
+
+impl From<IpcError> for Error {
+    fn from(err: IpcError) -> Error {
+        match err {
+            IpcError::Interrupted => Error::Interrupted,
+            IpcError::Destroyed => Error::Destroyed,
+        }
+    }
+}
+
+impl From<CapError> for Error {
+    fn from(err: CapError) -> Error {
+        match err {
+            CapError::NoSuchSpace | CapError::InsufficientRights => Error::Denied,
+            CapError::InvalidSlot | CapError::AlreadyBadged => Error::InvalidArgument,
+            CapError::Empty => Error::InvalidCapability,
+            CapError::Occupied => Error::SlotOccupied,
+        }
+    }
+}
+
+/// What a system call handler returns.
+pub type Result = ::core::result::Result<u64, Error>;
+
+/// The most `debug_write` writes at once.
+pub const DEBUG_WRITE_MAX: usize = 1024;
+
+/// A system call handler. Takes the call's six argument registers (which it can overwrite, to
+/// return more than its result), and returns its result.
+type Handler = fn(&mut [u64; 6]) -> Result;
+
+/// System call handlers, indexed by number.
+static HANDLERS: [Handler; 25] = [
+    sys_yield,
+    sys_exit,
+    sys_debug_write,
+    sys_sleep,
+    sys_thread_create,
+    sys_cap_copy,
+    sys_cap_mint,
+    sys_cap_move,
+    sys_cap_delete,
+    sys_cap_revoke,
+    sys_cap_identify,
+    sys_endpoint_create,
+    sys_send,
+    sys_recv,
+    sys_call,
+    sys_reply_recv,
+    sys_notification_create,
+    sys_signal,
+    sys_wait,
+    sys_poll,
+    sys_notification_bind,
+    sys_notification_unbind,
+    sys_irq_claim,
+    sys_irq_bind,
+    sys_irq_ack,
+];
+
+/// Runs system call `nr` with `args`, returning its result, encoded for the caller. Whatever the
+/// call leaves in `args` goes back to the caller's argument registers.
+pub fn dispatch(nr: u64, args: &mut [u64; 6]) -> u64 {
+    let result = match HANDLERS.get(nr as usize) {
+        Some(handler) => handler(args),
+        None => {
+            debug!("syscall: unknown system call {}", nr);
+            Err(Error::NoSuchCall)
+        }
+    };
+
+    match result {
+        Ok(value) => value,
+        Err(err) => (-(err as i64)) as u64,
+    }
+}
+
+fn sys_yield(_args: &mut [u64; 6]) -> Result {
+    task::yield_now();
+    Ok(0)
+}
+
+fn sys_exit(args: &mut [u64; 6]) -> Result {
+    debug!(
+        "syscall: thread {} exited with status {}",
+        task::current().expect("syscall: no current thread"),
+        args[0] as i64
+    );
+    task::exit();
+}
+
+fn sys_debug_write(args: &mut [u64; 6]) -> Result {
+    let (buf, len) = (args[0] as VirtualAddress, args[1] as usize);
+    let len = cmp::min(len, DEBUG_WRITE_MAX);
+
+    let mut text = vec![0; len];
+    usermode::copy_from_user(&mut text, buf)?;
+    let text = str::from_utf8(&text).map_err(|_| Error::InvalidArgument)?;
+
+    info!(
+        "user[{}]: {}",
+        process::current().map_or(0, |pid| pid.as_u64()),
+        text.trim_end_matches('\n')
+    );
+    Ok(len as u64)
+}
+
+fn sys_sleep(args: &mut [u64; 6]) -> Result {
+    let deadline = Instant::now()
+        .checked_add(Duration::from_nanos(args[0]))
+        .ok_or(Error::InvalidArgument)?;
+    timer::sleep_until(deadline);
+    Ok(0)
+}
+
+fn sys_thread_create(args: &mut [u64; 6]) -> Result {
+    let (entry, arg) = (args[0] as VirtualAddress, args[1] as usize);
+    if args[2] > u64::from(u8::max_value()) {
+        return Err(Error::InvalidArgument);
+    }
+    let priority = Priority::new(args[2] as u8).ok_or(Error::InvalidArgument)?;
+
+    let current = task::current().expect("syscall: no current thread");
+    let pid = process::current().ok_or(Error::Denied)?;
+    if Some(priority) > task::base_priority(current) {
+        return Err(Error::Denied);
+    }
+    if !usermode::is_user_range(entry, 1) {
+        return Err(Error::BadAddress);
+    }
+
+    process::spawn_thread(pid, "user", priority, entry, arg)
+        .map(|id| id.as_usize() as u64)
+        .ok_or(Error::OutOfResources)
+}
+
+/// Slot `index` of the calling process's capability space.
+fn cap_slot(index: u64) -> ::core::result::Result<SlotRef, Error> {
+    let pid = process::current().ok_or(Error::Denied)?;
+    Ok(SlotRef::new(pid, index as usize))
+}
+
+fn sys_cap_copy(args: &mut [u64; 6]) -> Result {
+    cap::copy(cap_slot(args[0])?, cap_slot(args[1])?)?;
+    Ok(0)
+}
+
+fn sys_cap_mint(args: &mut [u64; 6]) -> Result {
+    if args[2] > u64::from(u32::max_value()) {
+        return Err(Error::InvalidArgument);
+    }
+    let rights = Rights::from_bits(args[2] as u32).ok_or(Error::InvalidArgument)?;
+
+    cap::mint(cap_slot(args[0])?, cap_slot(args[1])?, rights, args[3])?;
+    Ok(0)
+}
+
+fn sys_cap_move(args: &mut [u64; 6]) -> Result {
+    cap::move_cap(cap_slot(args[0])?, cap_slot(args[1])?)?;
+    Ok(0)
+}
+
+fn sys_cap_delete(args: &mut [u64; 6]) -> Result {
+    cap::delete(cap_slot(args[0])?)?;
+    Ok(0)
+}
+
+fn sys_cap_revoke(args: &mut [u64; 6]) -> Result {
+    cap::revoke(cap_slot(args[0])?)?;
+    Ok(0)
+}
+
+fn sys_cap_identify(args: &mut [u64; 6]) -> Result {
+    let cap = cap::lookup(cap_slot(args[0])?)?;
+    Ok(cap.object().kind() as u64 | u64::from(cap.rights().bits()) << 8)
+}
+
+fn sys_endpoint_create(args: &mut [u64; 6]) -> Result {
+    let endpoint = Object::Endpoint(Arc::new(Endpoint::new()));
+    cap::insert(cap_slot(args[0])?, Capability::new(endpoint, Rights::all()))?;
+    Ok(0)
+}
+
+/// The capability in slot `index`, if it has `rights`.
+fn cap_with(index: u64, rights: Rights) -> ::core::result::Result<Capability, Error> {
+    let cap = cap::lookup(cap_slot(index)?)?;
+    cap.require(rights)?;
+    Ok(cap)
+}
+
+/// The endpoint capability in slot `index`, if it has `rights`: the endpoint, its badge, and all
+/// its rights.
+fn endpoint_cap(
+    index: u64,
+    rights: Rights,
+) -> ::core::result::Result<(Arc<Endpoint>, u64, Rights), Error> {
+    let cap = cap_with(index, rights)?;
+    match *cap.object() {
+        Object::Endpoint(ref endpoint) => Ok((Arc::clone(endpoint), cap.badge(), cap.rights())),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+/// The message to send in `args`, sent through a capability with `rights`.
+fn message_from(args: &[u64; 6], rights: Rights) -> ::core::result::Result<Message, Error> {
+    let info = args[1];
+    let cap = if info & info::SEND_CAP != 0 {
+        if !rights.contains(Rights::GRANT) {
+            return Err(Error::Denied);
+        }
+        let slot = cap_slot(info >> info::SEND_SLOT_SHIFT & 0xff)?;
+        cap::lookup(slot)?;
+        Some(slot)
+    } else {
+        None
+    };
+
+    let mut words = [0; MSG_WORDS];
+    words.copy_from_slice(&args[2..]);
+    Ok(Message {
+        label: (info >> info::LABEL_SHIFT) as u32,
+        words,
+        cap,
+    })
+}
+
+/// Where the `info` word in `args` says a capability coming with a message should go.
+fn recv_slot(args: &[u64; 6]) -> ::core::result::Result<Option<SlotRef>, Error> {
+    let info = args[1];
+    if info & info::RECV_CAP != 0 {
+        cap_slot(info >> info::RECV_SLOT_SHIFT & 0xff).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Puts `received` in `args`, for the caller.
+fn message_to(args: &mut [u64; 6], received: Received) -> Result {
+    let msg = received.msg;
+    let cap = msg.cap.map_or(0, |slot| {
+        info::RECV_CAP | (slot.index as u64) << info::RECV_SLOT_SHIFT
+    });
+
+    let notification = if received.notification {
+        info::NOTIFICATION
+    } else {
+        0
+    };
+
+    args[0] = received.badge;
+    args[1] = u64::from(msg.label) << info::LABEL_SHIFT | cap | notification;
+    args[2..].copy_from_slice(&msg.words);
+    Ok(0)
+}
+
+fn sys_send(args: &mut [u64; 6]) -> Result {
+    let (endpoint, badge, rights) = endpoint_cap(args[0], Rights::WRITE)?;
+    endpoint.send(badge, message_from(args, rights)?)?;
+    Ok(0)
+}
+
+fn sys_recv(args: &mut [u64; 6]) -> Result {
+    let (endpoint, _, _) = endpoint_cap(args[0], Rights::READ)?;
+    let received = endpoint.recv(recv_slot(args)?)?;
+    message_to(args, received)
+}
+
+fn sys_call(args: &mut [u64; 6]) -> Result {
+    let (endpoint, badge, rights) = endpoint_cap(args[0], Rights::WRITE)?;
+    let received = endpoint.call(badge, message_from(args, rights)?, recv_slot(args)?)?;
+    message_to(args, received)
+}
+
+fn sys_reply_recv(args: &mut [u64; 6]) -> Result {
+    let (endpoint, _, rights) = endpoint_cap(args[0], Rights::READ)?;
+    let reply = message_from(args, rights)?;
+    let received = endpoint.reply_recv(reply, recv_slot(args)?)?;
+    message_to(args, received)
+}
+
+fn sys_notification_create(args: &mut [u64; 6]) -> Result {
+    let notification = Object::Notification(Arc::new(Notification::new()));
+    cap::insert(
+        cap_slot(args[0])?,
+        Capability::new(notification, Rights::all()),
+    )?;
+    Ok(0)
+}
+
+/// The notification capability in slot `index`, if it has `rights`: the notification, and its
+/// badge.
+fn notification_cap(
+    index: u64,
+    rights: Rights,
+) -> ::core::result::Result<(Arc<Notification>, u64), Error> {
+    let cap = cap_with(index, rights)?;
+    match *cap.object() {
+        Object::Notification(ref notification) => Ok((Arc::clone(notification), cap.badge())),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+fn sys_signal(args: &mut [u64; 6]) -> Result {
+    let (notification, badge) = notification_cap(args[0], Rights::WRITE)?;
+    notification.signal(args[1] | badge);
+    Ok(0)
+}
+
+fn sys_wait(args: &mut [u64; 6]) -> Result {
+    let (notification, _) = notification_cap(args[0], Rights::READ)?;
+    args[0] = notification.wait()?;
+    Ok(0)
+}
+
+fn sys_poll(args: &mut [u64; 6]) -> Result {
+    let (notification, _) = notification_cap(args[0], Rights::READ)?;
+    args[0] = notification.poll();
+    Ok(0)
+}
+
+fn sys_notification_bind(args: &mut [u64; 6]) -> Result {
+    let (notification, _) = notification_cap(args[0], Rights::READ)?;
+    let current = task::current().expect("syscall: no current thread");
+    if !ipc::bind(&notification, current) {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(0)
+}
+
+fn sys_notification_unbind(_args: &mut [u64; 6]) -> Result {
+    let current = task::current().expect("syscall: no current thread");
+    if !ipc::unbind(current) {
+        return Err(Error::InvalidArgument);
+    }
+    Ok(0)
+}
+
+fn sys_irq_claim(args: &mut [u64; 6]) -> Result {
+    match *cap_with(args[0], Rights::WRITE)?.object() {
+        Object::IrqControl => {}
+        _ => return Err(Error::InvalidCapability),
+    }
+    if args[1] >= irq::IRQ_COUNT as u64 || irq::is_reserved(args[1] as u8) {
+        return Err(Error::InvalidArgument);
+    }
+    let slot = cap_slot(args[2])?;
+
+    // claimed first, so that the line's only ever in one slot; given up again if that fails
+    let handler = irq::claim(args[1] as u8).ok_or(Error::Busy)?;
+    cap::insert(
+        slot,
+        Capability::new(Object::Irq(Arc::new(handler)), Rights::all()),
+    )?;
+    Ok(0)
+}
+
+/// The IRQ handler capability in slot `index`, if it has `rights`.
+fn irq_cap(index: u64, rights: Rights) -> ::core::result::Result<Arc<IrqHandler>, Error> {
+    match *cap_with(index, rights)?.object() {
+        Object::Irq(ref handler) => Ok(Arc::clone(handler)),
+        _ => Err(Error::InvalidCapability),
+    }
+}
+
+fn sys_irq_bind(args: &mut [u64; 6]) -> Result {
+    let handler = irq_cap(args[0], Rights::WRITE)?;
+    let (notification, badge) = notification_cap(args[1], Rights::WRITE)?;
+
+    let bits = if badge != 0 {
+        badge
+    } else {
+        1 << handler.irq()
+    };
+    handler.bind(notification, bits);
+    Ok(0)
+}
+
+fn sys_irq_ack(args: &mut [u64; 6]) -> Result {
+    irq_cap(args[0], Rights::WRITE)?.ack();
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sending_a_capability_needs_grant() {
+        let args = [0, info::SEND_CAP | 3 << info::SEND_SLOT_SHIFT, 0, 0, 0, 0];
+        for &rights in [Rights::empty(), Rights::READ | Rights::WRITE].iter() {
+            assert_eq!(message_from(&args, rights), Err(Error::Denied));
+        }
+    }
+
+    #[test]
+    fn messages_without_capabilities() {
+        let args = [0, 7 << info::LABEL_SHIFT, 1, 2, 3, 4];
+        let expected = Message::new(7, [1, 2, 3, 4]);
+        for &rights in [Rights::empty(), Rights::WRITE, Rights::all()].iter() {
+            assert_eq!(message_from(&args, rights), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn received_messages() {
+        let mut args = [0; 6];
+        let slot = SlotRef::new(process::ProcessId::from_u64(1), 5);
+        let received = Received {
+            badge: 42,
+            msg: Message {
+                label: 7,
+                words: [1, 2, 3, 4],
+                cap: Some(slot),
+            },
+            notification: false,
+        };
+
+        assert_eq!(message_to(&mut args, received), Ok(0));
+        assert_eq!(
+            args,
+            [
+                42,
+                7 << info::LABEL_SHIFT | info::RECV_CAP | 5 << info::RECV_SLOT_SHIFT,
+                1,
+                2,
+                3,
+                4,
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Error::from(CapError::InsufficientRights), Error::Denied);
+        assert_eq!(Error::from(CapError::Empty), Error::InvalidCapability);
+        assert_eq!(Error::from(IpcError::Interrupted), Error::Interrupted);
+        assert_eq!(Error::from(IpcError::Destroyed), Error::Destroyed);
+    }
+}
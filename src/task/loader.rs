@@ -0,0 +1,275 @@
+//! Loading ELF executables into user processes.
+//!
+//! [spawn] starts a program from an ELF image (a multiboot module, say): it maps the image's
+//! loadable segments into a fresh process, builds the main thread's stack the way the System V
+//! ABI has it, and starts the thread at the image's entry point.
+//!
+//! # The initial stack
+//! At the entry point, `rsp` (16-byte aligned) points at `argc`, followed by the `argv` pointers
+//! and a null; the `envp` pointers and a null; then the auxiliary vector, as key/value pairs
+//! ending in an `AT_NULL` key. The strings they point to are at the top of the stack.
+
+use alloc::vec::Vec;
+use core::{fmt, mem};
+
+use super::process::{self, ProcessId, USER_STACK_SIZE};
+use super::Priority;
+use arch::x86_64::memory::paging::table::EntryFlags;
+use arch::x86_64::memory::paging::{Frame, VirtualAddress};
+use arch::x86_64::memory::{AddressSpace, MapError};
+use elf::{ElfError, ElfFile, PT_PHDR};
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// Why a program couldn't be loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The image isn't a valid executable.
+    Elf(ElfError),
+    /// A segment couldn't be mapped: it's outside the user range, say, or there's no memory
+    /// left.
+    Map(MapError),
+    /// There's no room for the main thread's stack.
+    NoStack,
+    /// The arguments and environment don't fit on the stack.
+    ArgsTooBig,
+    /// The process was killed while it was being loaded.
+    Killed,
+    /// The main thread couldn't be started: the process was killed, or there's no room left for
+    /// the thread's kernel stack.
+    NoThread,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> LoadError {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<MapError> for LoadError {
+    fn from(err: MapError) -> LoadError {
+        LoadError::Map(err)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Elf(err) => write!(f, "{}", err),
+            LoadError::Map(err) => write!(f, "couldn't map segment: {:?}", err),
+            LoadError::NoStack => write!(f, "no room for a stack"),
+            LoadError::ArgsTooBig => write!(f, "arguments too big"),
+            LoadError::Killed => write!(f, "killed while loading"),
+            LoadError::NoThread => write!(f, "couldn't start the main thread"),
+        }
+    }
+}
+
+/// An executable, loaded into an address space.
+#[derive(Clone, Copy, Debug)]
+pub struct Image {
+    /// Where execution starts.
+    pub entry: VirtualAddress,
+    /// Where the program header table is, if it's been loaded too.
+    pub program_headers: Option<VirtualAddress>,
+    /// How many program headers there are.
+    pub program_header_count: usize,
+    /// How big each program header is.
+    pub program_header_size: usize,
+}
+
+/// The flags for a page shared by two segments, mapped with `a` and `b`: whatever either allows.
+fn merge_flags(a: EntryFlags, b: EntryFlags) -> EntryFlags {
+    let no_execute = a & b & EntryFlags::NO_EXECUTE;
+    ((a | b) - EntryFlags::NO_EXECUTE) | no_execute
+}
+
+/// Maps the loadable segments of the executable in `data` into `space`, with the permissions
+/// their program headers ask for, and copies their contents in. Anything in a segment past its
+/// file data (its BSS) is left zeroed.
+///
+/// Segments are mapped with page granularity, so a page two segments share gets the permissions
+/// of both.
+pub fn load(space: &AddressSpace, data: &[u8]) -> Result<Image, LoadError> {
+    let file = ElfFile::parse(data)?;
+
+    // the end of the last page mapped so far, and the flags it was mapped with
+    let mut mapped_end = 0;
+    let mut last_flags = EntryFlags::empty();
+
+    for segment in file.segments() {
+        let flags = EntryFlags::from_elf_segment_flags(segment.flags);
+        let start = segment.vaddr as VirtualAddress;
+        let end = segment.vaddr_end() as VirtualAddress;
+
+        let first_page = start & !(Frame::SIZE - 1);
+        let end_page = end
+            .checked_add(Frame::SIZE - 1)
+            .ok_or(MapError::OutOfRange)?
+            & !(Frame::SIZE - 1);
+
+        let mut map_from = first_page;
+        last_flags = if first_page < mapped_end {
+            // the segment before ends on this segment's first page
+            let merged = merge_flags(last_flags, flags);
+            space.protect(first_page, Frame::SIZE, merged)?;
+            map_from = mapped_end;
+            if end_page == mapped_end {
+                merged
+            } else {
+                flags
+            }
+        } else {
+            flags
+        };
+        if map_from < end_page {
+            space.map(map_from, end_page - map_from, flags)?;
+        }
+        mapped_end = end_page;
+
+        // fresh pages are zeroed, which takes care of the BSS
+        space.write(start, file.segment_data(&segment))?;
+    }
+
+    // the program headers went wherever the segment covering them in the image did
+    let (ph_offset, ph_count, ph_size) = file.program_header_table();
+    let ph_end = (ph_offset + ph_count * ph_size) as u64;
+    let program_headers = file
+        .program_headers()
+        .find(|header| header.kind == PT_PHDR)
+        .map(|header| header.vaddr as VirtualAddress)
+        .or_else(|| {
+            file.segments()
+                .find(|s| s.offset <= ph_offset as u64 && ph_end <= s.offset + s.file_size)
+                .map(|s| (s.vaddr + (ph_offset as u64 - s.offset)) as VirtualAddress)
+        });
+
+    Ok(Image {
+        entry: file.entry() as VirtualAddress,
+        program_headers,
+        program_header_count: ph_count,
+        program_header_size: ph_size,
+    })
+}
+
+/// Builds the initial stack for a program loaded as `image`, with arguments `argv` and
+/// environment `envp`, for a stack whose top is at `top`. Returns the stack pointer, and what
+/// goes between it and the top.
+fn build_stack(
+    top: VirtualAddress,
+    image: &Image,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(VirtualAddress, Vec<u8>), LoadError> {
+    const WORD: usize = mem::size_of::<u64>();
+
+    let mut auxv = vec![
+        (AT_PAGESZ, Frame::SIZE as u64),
+        (AT_ENTRY, image.entry as u64),
+    ];
+    if let Some(phdr) = image.program_headers {
+        auxv.push((AT_PHDR, phdr as u64));
+        auxv.push((AT_PHENT, image.program_header_size as u64));
+        auxv.push((AT_PHNUM, image.program_header_count as u64));
+    }
+    auxv.push((AT_NULL, 0));
+
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * auxv.len();
+    let size = strings_size + words * WORD + 15;
+    if size > USER_STACK_SIZE {
+        return Err(LoadError::ArgsTooBig);
+    }
+
+    let strings = top - strings_size;
+    let rsp = (strings - words * WORD) & !0xf;
+    let mut stack = vec![0; top - rsp];
+
+    let mut word_at = 0;
+    let mut push_word = |stack: &mut Vec<u8>, word: u64| {
+        for (i, byte) in stack[word_at..word_at + WORD].iter_mut().enumerate() {
+            *byte = (word >> (8 * i)) as u8;
+        }
+        word_at += WORD;
+    };
+    let mut string_at = strings - rsp;
+
+    push_word(&mut stack, argv.len() as u64);
+    for list in [argv, envp].iter() {
+        for s in list.iter() {
+            push_word(&mut stack, (rsp + string_at) as u64);
+            stack[string_at..string_at + s.len()].copy_from_slice(s.as_bytes());
+            // already followed by a null
+            string_at += s.len() + 1;
+        }
+        push_word(&mut stack, 0);
+    }
+    for &(key, value) in auxv.iter() {
+        push_word(&mut stack, key);
+        push_word(&mut stack, value);
+    }
+
+    Ok((rsp, stack))
+}
+
+/// Starts the executable in `data` as a new process called `name`, whose main thread runs at
+/// `priority` with arguments `argv` and environment `envp` on its stack.
+///
+/// Nothing's left behind if the image can't be loaded (beyond the memory it was being loaded
+/// into, as that can't be freed yet).
+pub fn spawn(
+    name: &'static str,
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    priority: Priority,
+) -> Result<ProcessId, LoadError> {
+    let pid = process::create(name);
+    start(pid, name, data, argv, envp, priority).map(|_| pid)
+}
+
+/// Like [spawn], but into process `pid`, fresh from `process::create` (so that it can be given
+/// capabilities before it starts). The process is destroyed if the image can't be loaded.
+pub fn start(
+    pid: ProcessId,
+    name: &'static str,
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    priority: Priority,
+) -> Result<(), LoadError> {
+    let result = load_into(pid, name, data, argv, envp, priority);
+    if let Err(err) = result {
+        warn!("task: couldn't load {}: {}", name, err);
+        process::destroy(pid);
+    }
+
+    result
+}
+
+fn load_into(
+    pid: ProcessId,
+    name: &'static str,
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    priority: Priority,
+) -> Result<(), LoadError> {
+    let space = process::address_space(pid).ok_or(LoadError::Killed)?;
+    let image = load(&space, data)?;
+
+    let top = process::alloc_user_stack(pid, name).ok_or(LoadError::NoStack)?;
+    let (rsp, stack) = build_stack(top, &image, argv, envp)?;
+    space.write(rsp, &stack)?;
+
+    process::start_thread(pid, name, priority, image.entry, top, rsp, 0)
+        .ok_or(LoadError::NoThread)?;
+    debug!("task: loaded {}, entry point {:#x}", name, image.entry);
+
+    Ok(())
+}
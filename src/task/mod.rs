@@ -0,0 +1,653 @@
+//! Kernel threads, and a preemptive priority scheduler for them.
+//!
+//! Each CPU runs one thread at a time. Threads which are ready to run wait their turn on the run
+//! queue: one FIFO per [Priority] level, the highest nonempty level going first. A thread keeps
+//! its CPU until it [yield_now]s, [block]s, [sleep]s or [exit]s; until a higher-priority thread
+//! becomes ready; or until its time slice runs out. Each tick counts down the running thread's
+//! quantum, and once it hits zero (or something more important is ready), the thread is
+//! [preempt]ed on the way out of the interrupt, going to the back of its level's queue.
+//!
+//! How long a time slice lasts is set per priority level (see [set_timeslice]); every level
+//! starts out at [TIMESLICE].
+//!
+//! # Priority inheritance
+//! A thread which [block_on]s another (for a [Mutex] it holds, say) lends that thread its
+//! priority until it's woken, so a low-priority thread holding up a high-priority one runs at the
+//! high priority in the meantime, and can't be starved by everything in between. Lending is
+//! transitive: if the owner is itself blocked on a third thread, that one inherits too.
+//!
+//! When there's nothing else to do, a CPU runs its idle thread: whichever flow of control called
+//! [init] on it (on the boot CPU, the one which goes on to run `kernel_main`). Idle threads rank
+//! below every priority, and are preempted as soon as another thread becomes ready.
+//!
+//! Threads may also belong to a user [process], in which case they run (mostly) in ring 3, in
+//! their process's address space, and enter the kernel on their own kernel stacks.
+
+pub mod loader;
+mod mutex;
+pub mod process;
+mod runqueue;
+pub mod thread;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::{cmp, mem};
+use spin::{Mutex as SpinMutex, Once};
+
+use self::process::ProcessId;
+use self::runqueue::RunQueue;
+use arch::x86_64::context::{self, Context};
+use arch::x86_64::interrupts::without_interrupts;
+use arch::x86_64::memory::AddressSpace;
+use arch::x86_64::{self, memory, usermode, MAX_CPUS};
+use time::{self, clockevent, Duration};
+
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::thread::{Priority, State, Thread, ThreadId, PRIORITY_LEVELS};
+
+/// How big each thread's kernel stack is, in pages.
+pub const STACK_PAGES: usize = 4;
+
+/// How long a thread may run before it's preempted, if anything else of its priority is ready,
+/// unless its priority level's been given a different [timeslice](fn.set_timeslice.html).
+pub const TIMESLICE: Duration = Duration::from_millis(100);
+
+/// How far down a chain of blocked threads priority is lent. Bounds the work done when a cycle of
+/// threads have deadlocked waiting on each other.
+const MAX_INHERITANCE_DEPTH: usize = 16;
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    /// Threads waiting for a CPU.
+    run_queue: RunQueue,
+    /// The thread each CPU is running.
+    current: [Option<ThreadId>; MAX_CPUS],
+    /// Each CPU's idle thread. Idle threads are never on the run queue.
+    idle: [Option<ThreadId>; MAX_CPUS],
+    /// A dead thread each CPU has just switched away from, to be freed once it's off its stack.
+    reap: [Option<ThreadId>; MAX_CPUS],
+    /// How long a time slice lasts at each priority level.
+    timeslices: [Duration; PRIORITY_LEVELS],
+    next_id: u64,
+}
+
+impl Scheduler {
+    fn alloc_id(&mut self) -> ThreadId {
+        let id = ThreadId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn thread(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("task: no such thread")
+    }
+
+    /// Is `id` one of the idle threads?
+    fn is_idle(&self, id: ThreadId) -> bool {
+        self.idle.contains(&Some(id))
+    }
+
+    /// Puts `id` on the run queue, asking any CPU running something less important to switch.
+    fn make_ready(&mut self, id: ThreadId) {
+        let priority = {
+            let thread = self.thread(id);
+            thread.state = State::Ready;
+            thread.priority
+        };
+        self.run_queue.push_back(id, priority);
+        self.kick(priority);
+    }
+
+    /// Asks every CPU running an idle thread, or one of lower priority than `priority`, to switch
+    /// threads on its way out of the next interrupt.
+    fn kick(&self, priority: Priority) {
+        for cpu in 0..MAX_CPUS {
+            let running = match self.current[cpu] {
+                Some(running) => running,
+                None => continue,
+            };
+            let outranked = self.is_idle(running)
+                || self
+                    .threads
+                    .get(&running)
+                    .map_or(false, |t| t.priority < priority);
+            if outranked {
+                NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Starts a fresh time slice for thread `id`, about to run on `cpu`, dropping any request
+    /// for `cpu` to switch threads.
+    fn start_slice(&mut self, cpu: usize, id: ThreadId) {
+        let slice = self.timeslices[self.thread(id).priority.level()];
+        QUANTUM[cpu].store(time::duration_to_ticks(slice), Ordering::Relaxed);
+        NEED_RESCHED[cpu].store(false, Ordering::Relaxed);
+    }
+
+    /// Recomputes thread `id`'s effective priority from its base priority and its waiters', then
+    /// does the same down the chain of threads it's blocked on, for as long as that changes
+    /// anything.
+    fn update_priority(&mut self, id: ThreadId) {
+        let mut id = id;
+        for _ in 0..MAX_INHERITANCE_DEPTH {
+            let (old, new, blocked_on) = {
+                let thread = match self.threads.get(&id) {
+                    Some(thread) => thread,
+                    None => return,
+                };
+                let inherited = thread
+                    .waiters
+                    .iter()
+                    .filter_map(|waiter| self.threads.get(waiter))
+                    .map(|waiter| waiter.priority)
+                    .max();
+                let new = cmp::max(thread.base_priority, inherited.unwrap_or(Priority::MIN));
+
+                (thread.priority, new, thread.blocked_on)
+            };
+            if old == new {
+                return;
+            }
+
+            self.set_effective_priority(id, old, new);
+            match blocked_on {
+                Some(owner) => id = owner,
+                None => return,
+            }
+        }
+    }
+
+    fn set_effective_priority(&mut self, id: ThreadId, old: Priority, new: Priority) {
+        let state = {
+            let thread = self.thread(id);
+            thread.priority = new;
+            thread.state
+        };
+
+        match state {
+            State::Ready if !self.is_idle(id) => {
+                self.run_queue.remove(id, old);
+                self.run_queue.push_back(id, new);
+                self.kick(new);
+            }
+            State::Running if self.run_queue.highest().map_or(false, |ready| ready > new) => {
+                // it's been outranked by something waiting
+                for cpu in 0..MAX_CPUS {
+                    if self.current[cpu] == Some(id) {
+                        NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Stops thread `waiter` waiting on whichever thread it's blocked on, taking back the priority
+    /// it lent.
+    fn stop_waiting(&mut self, waiter: ThreadId) {
+        let owner = match self.thread(waiter).blocked_on.take() {
+            Some(owner) => owner,
+            None => return,
+        };
+        if let Some(owner_thread) = self.threads.get_mut(&owner) {
+            owner_thread.waiters.retain(|&w| w != waiter);
+        }
+        self.update_priority(owner);
+    }
+
+    /// Takes thread `id` out of the scheduler for good. It mustn't be running, or on the run
+    /// queue. Anything blocked on it is left to fend for itself, as it can't release anything
+    /// now.
+    fn remove(&mut self, id: ThreadId) -> Option<Box<Thread>> {
+        self.stop_waiting(id);
+        let thread = self.threads.remove(&id)?;
+        for waiter in thread.waiters.iter() {
+            if let Some(waiter) = self.threads.get_mut(waiter) {
+                waiter.blocked_on = None;
+            }
+        }
+
+        Some(thread)
+    }
+}
+
+lazy_static! {
+    // only ever locked with interrupts off, as threads are woken from deferred work
+    static ref SCHEDULER: SpinMutex<Scheduler> = SpinMutex::new(Scheduler {
+        threads: BTreeMap::new(),
+        run_queue: RunQueue::new(),
+        current: [None; MAX_CPUS],
+        idle: [None; MAX_CPUS],
+        reap: [None; MAX_CPUS],
+        timeslices: [TIMESLICE; PRIORITY_LEVELS],
+        next_id: 0,
+    });
+
+    /// How many more ticks the thread running on each CPU may run for.
+    static ref QUANTUM: [AtomicU64; MAX_CPUS] = Default::default();
+    /// Which CPUs should switch threads on their way out of the current interrupt.
+    static ref NEED_RESCHED: [AtomicBool; MAX_CPUS] = Default::default();
+}
+
+static TICK_HOOK: Once<()> = Once::new();
+
+/// Where new threads start, on their own stacks: finishes the switch to them, then runs `func`.
+extern "C" fn thread_entry(func: usize, arg: usize) -> ! {
+    finish_switch();
+    ::x86_64::instructions::interrupts::enable();
+
+    let func: fn(usize) = unsafe { mem::transmute(func) };
+    func(arg);
+
+    exit();
+}
+
+/// Switches the current CPU to the next thread on the run queue, leaving the current thread in
+/// `state` (and back on the run queue, if that's [State::Ready]). A thread staying ready only
+/// gives way to threads of at least its priority; if the current thread isn't staying ready and
+/// nothing else is, the CPU switches to its idle thread. Either way, whichever thread runs next
+/// starts a fresh time slice.
+///
+/// If the current thread is blocking on a thread `blocked_on`, it lends that thread its priority
+/// until it's woken.
+///
+/// Returns once something switches back, or straight away (returning `false`) if there's nothing
+/// to switch to, or the current thread is blocking but has a wakeup pending.
+///
+/// Must be called with interrupts disabled.
+fn switch_to_next(state: State, blocked_on: Option<ThreadId>) -> bool {
+    let cpu = x86_64::cpu_id();
+
+    let (old, new) = {
+        let mut sched = SCHEDULER.lock();
+        let current = match sched.current[cpu] {
+            Some(current) => current,
+            None => return false,
+        };
+        let idle = sched.is_idle(current);
+
+        if state == State::Blocked || state == State::Sleeping {
+            let thread = sched.thread(current);
+            if thread.wakeup_pending {
+                thread.wakeup_pending = false;
+                return false;
+            }
+        }
+
+        if let (Some(owner), State::Blocked) = (blocked_on, state) {
+            if owner != current && sched.threads.contains_key(&owner) {
+                sched.thread(current).blocked_on = Some(owner);
+                sched.thread(owner).waiters.push(current);
+                sched.update_priority(owner);
+            }
+        }
+
+        let min = if state == State::Ready && !idle {
+            Some(sched.thread(current).priority)
+        } else {
+            None
+        };
+        let next = match sched.run_queue.pop(min) {
+            Some(next) => next,
+            None if state == State::Ready => {
+                sched.start_slice(cpu, current);
+                return false;
+            }
+            None => sched.idle[cpu].expect("task: no idle thread"),
+        };
+
+        sched.thread(current).state = state;
+        match state {
+            State::Ready if !idle => {
+                let priority = sched.thread(current).priority;
+                sched.run_queue.push_back(current, priority);
+            }
+            State::Dead => sched.reap[cpu] = Some(current),
+            _ => {}
+        }
+        sched.thread(next).state = State::Running;
+        sched.current[cpu] = Some(next);
+        sched.start_slice(cpu, next);
+
+        // user threads come back into the kernel on their own stacks, in their own spaces
+        {
+            let next = sched.thread(next);
+            if let Some(ref space) = next.address_space {
+                let stack = next
+                    .stack
+                    .as_ref()
+                    .expect("task: user thread without a stack");
+                unsafe {
+                    space.activate();
+                    usermode::set_kernel_stack(stack.top());
+                }
+            }
+        }
+
+        // the threads are boxed, so these stay put after the lock's dropped
+        let old: *mut Context = &mut sched.thread(current).context;
+        let new: *const Context = &sched.thread(next).context;
+        (old, new)
+    };
+
+    unsafe { context::switch(old, new) };
+    finish_switch();
+
+    true
+}
+
+/// Cleans up after a switch to the current thread: frees the thread switched away from, if it
+/// was dead. Must be called with interrupts disabled.
+fn finish_switch() {
+    let dead = {
+        let mut sched = SCHEDULER.lock();
+        let dead = sched.reap[x86_64::cpu_id()].take();
+        dead.and_then(|dead| sched.remove(dead))
+    };
+
+    if let Some(mut thread) = dead {
+        // we've switched off it, so nothing's running on it
+        if let Some(stack) = thread.stack.take() {
+            memory::free_stack(stack);
+        }
+        ::ipc::thread_exited(thread.id);
+        if let Some(pid) = thread.process {
+            process::thread_exited(pid, thread.id);
+        }
+    }
+}
+
+/// Adopts the current flow of control as the current CPU's idle thread. Must be called on each
+/// CPU before it runs any other threads.
+pub fn init() {
+    without_interrupts(|| {
+        let cpu = x86_64::cpu_id();
+        let mut sched = SCHEDULER.lock();
+        assert!(
+            sched.current[cpu].is_none(),
+            "task::init() can only be called once per CPU!"
+        );
+
+        let id = sched.alloc_id();
+        let mut thread = Thread::new(id, "idle", Priority::MIN, Context::empty(), None);
+        thread.state = State::Running;
+        sched.threads.insert(id, Box::new(thread));
+        sched.current[cpu] = Some(id);
+        sched.idle[cpu] = Some(id);
+    });
+
+    TICK_HOOK.call_once(|| clockevent::add_tick_hook(tick_hook));
+}
+
+/// Counts down the running thread's quantum, asking for it to be preempted when it runs out.
+fn tick_hook(cpu: usize, _ticks: u64) {
+    let quantum = &QUANTUM[cpu];
+    let left = quantum.load(Ordering::Relaxed).saturating_sub(1);
+    quantum.store(left, Ordering::Relaxed);
+
+    if left == 0 {
+        NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switches away from the current thread if it's been asked to give up its CPU: because its time
+/// slice is up, because something more important is ready, or because it's an idle thread and
+/// anything is.
+///
+/// Called on the way out of an interrupt handler, once the interrupt's been acknowledged and its
+/// deferred work has run; the thread carries on from the interrupt when it's next switched back
+/// to. Also called from threads which may have just readied a more important thread. Must not
+/// be called from deferred context.
+pub fn preempt() {
+    without_interrupts(|| {
+        if NEED_RESCHED[x86_64::cpu_id()].load(Ordering::Relaxed) {
+            // an idle thread may have stopped the tick on its way to halting; whatever runs next
+            // needs it back
+            clockevent::restart_tick();
+            switch_to_next(State::Ready, None);
+        }
+    });
+}
+
+/// Starts a thread called `name`, which runs `func(arg)` on a stack of its own at `priority`,
+/// then exits.
+///
+/// The thread waits its turn on the run queue: if it's more important than the current thread,
+/// it runs as soon as the current thread is next [preempt]ed.
+///
+/// Returns `None` if there's no room left for the thread's stack.
+pub fn spawn(
+    name: &'static str,
+    priority: Priority,
+    func: fn(usize),
+    arg: usize,
+) -> Option<ThreadId> {
+    spawn_in(None, name, priority, func, arg)
+}
+
+/// Like [spawn], but the thread belongs to `process` (if it's given), and runs in its address
+/// space.
+fn spawn_in(
+    process: Option<(ProcessId, Arc<AddressSpace>)>,
+    name: &'static str,
+    priority: Priority,
+    func: fn(usize),
+    arg: usize,
+) -> Option<ThreadId> {
+    let stack = memory::alloc_stack(STACK_PAGES)?;
+    let context = unsafe { Context::new(stack.top(), thread_entry, func as usize, arg) };
+
+    let id = without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let id = sched.alloc_id();
+        let mut thread = Thread::new(id, name, priority, context, Some(stack));
+        if let Some((pid, space)) = process {
+            thread.process = Some(pid);
+            thread.address_space = Some(space);
+        }
+        sched.threads.insert(id, Box::new(thread));
+        sched.make_ready(id);
+
+        id
+    });
+
+    debug!(
+        "task: spawned thread {} ({}) at priority {}",
+        id, name, priority
+    );
+    Some(id)
+}
+
+/// The thread running on the current CPU, if [init] has been called on it.
+pub fn current() -> Option<ThreadId> {
+    without_interrupts(|| SCHEDULER.lock().current[x86_64::cpu_id()])
+}
+
+/// Can the current flow of control [block]? That is, is it a thread, and not an idle one?
+pub fn can_block() -> bool {
+    without_interrupts(|| {
+        let sched = SCHEDULER.lock();
+        sched.current[x86_64::cpu_id()].map_or(false, |current| !sched.is_idle(current))
+    })
+}
+
+/// Are there threads waiting for a CPU?
+pub fn has_ready() -> bool {
+    without_interrupts(|| !SCHEDULER.lock().run_queue.is_empty())
+}
+
+/// Thread `id`'s effective priority (including any it's inherited), if there's such a thread.
+pub fn priority(id: ThreadId) -> Option<Priority> {
+    without_interrupts(|| SCHEDULER.lock().threads.get(&id).map(|t| t.priority))
+}
+
+/// Thread `id`'s base priority (not counting any it's inherited), if there's such a thread.
+pub fn base_priority(id: ThreadId) -> Option<Priority> {
+    without_interrupts(|| SCHEDULER.lock().threads.get(&id).map(|t| t.base_priority))
+}
+
+/// Sets thread `id`'s base priority. Returns `false` if there's no such thread.
+///
+/// Takes effect (on the run queue, or on whether the thread's preempted) straight away; though
+/// while the thread's inherited a higher priority, it keeps running at that.
+pub fn set_priority(id: ThreadId, priority: Priority) -> bool {
+    let found = without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        match sched.threads.get_mut(&id) {
+            Some(thread) => thread.base_priority = priority,
+            None => return false,
+        }
+        sched.update_priority(id);
+
+        true
+    });
+
+    preempt();
+    found
+}
+
+/// Sets how long a time slice lasts at `priority`. Threads already running keep the slice they
+/// started with.
+pub fn set_timeslice(priority: Priority, slice: Duration) {
+    without_interrupts(|| SCHEDULER.lock().timeslices[priority.level()] = slice);
+}
+
+/// Gives the current CPU to the next ready thread of at least the current thread's priority, if
+/// there is one; the current thread goes to the back of its priority's queue. Returns whether
+/// anything else ran.
+///
+/// Must not be called from interrupt or deferred context.
+pub fn yield_now() -> bool {
+    without_interrupts(|| switch_to_next(State::Ready, None))
+}
+
+/// Blocks the current thread until it's [wake]d.
+///
+/// Wakeups aren't counted, and may come from anywhere, so callers should check whatever they're
+/// waiting for (and block again if need be) when this returns. If the thread was woken since it
+/// last blocked, this returns straight away.
+///
+/// # Panics
+/// If the current flow of control [can't block](fn.can_block.html).
+pub fn block() {
+    assert!(can_block(), "task: block() from an idle thread");
+    without_interrupts(|| switch_to_next(State::Blocked, None));
+}
+
+/// Like [block], but the current thread is waiting on thread `owner` (for a lock it holds, or a
+/// reply to a request, say): until it's woken, `owner` inherits its priority, if that's higher.
+///
+/// # Panics
+/// If the current flow of control [can't block](fn.can_block.html).
+pub fn block_on(owner: ThreadId) {
+    assert!(can_block(), "task: block_on() from an idle thread");
+    without_interrupts(|| switch_to_next(State::Blocked, Some(owner)));
+}
+
+/// Like [block], but marks the thread as [sleeping](thread/enum.State.html#variant.Sleeping),
+/// waiting for time to pass rather than for some event. Whatever's timing the sleep should [wake]
+/// the thread when it's over.
+///
+/// # Panics
+/// If the current flow of control [can't block](fn.can_block.html).
+pub fn sleep() {
+    assert!(can_block(), "task: sleep() from an idle thread");
+    without_interrupts(|| switch_to_next(State::Sleeping, None));
+}
+
+/// Makes thread `id` ready to run if it's blocked or sleeping, taking back any priority it lent
+/// while blocked; otherwise, makes its next [block] (or [sleep]) return straight away. Returns
+/// `false` if there's no such (live) thread.
+///
+/// A woken thread more important than the current one runs once the current one is next
+/// [preempt]ed. Can be called from deferred context.
+pub fn wake(id: ThreadId) -> bool {
+    without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+
+        let woken = match sched.threads.get_mut(&id) {
+            Some(thread) => match thread.state {
+                State::Blocked | State::Sleeping => true,
+                State::Dead => return false,
+                State::Ready | State::Running => {
+                    thread.wakeup_pending = true;
+                    false
+                }
+            },
+            None => return false,
+        };
+        if woken {
+            sched.stop_waiting(id);
+            sched.make_ready(id);
+        }
+
+        true
+    })
+}
+
+/// Ends the current thread.
+///
+/// # Panics
+/// If called from an idle thread.
+pub fn exit() -> ! {
+    assert!(can_block(), "task: exit() from an idle thread");
+
+    ::x86_64::instructions::interrupts::disable();
+    switch_to_next(State::Dead, None);
+    unreachable!("task: dead thread switched back to");
+}
+
+/// Has the current thread been [kill]ed? Waits a thread can spend long in on a user's behalf
+/// (for a message, say, or a timeout) should give up when it has, so that it gets back to user
+/// mode to die.
+///
+/// [kill]: process/fn.kill.html
+pub fn killed() -> bool {
+    without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        match sched.current[x86_64::cpu_id()] {
+            Some(current) => sched.thread(current).killed,
+            None => false,
+        }
+    })
+}
+
+/// Ends the current thread if its process has been killed. Called on the way back to user mode,
+/// where a thread's holding nothing that'd be left locked.
+pub fn exit_if_killed() {
+    if killed() {
+        exit();
+    }
+}
+
+/// Marks thread `id` as killed, and wakes it if it's waiting: it ends the next time it's on its
+/// way back to user mode (see [exit_if_killed]). Until then it may be anywhere in the kernel,
+/// holding locks or owing other threads something, so it can't just be removed. Returns `false`
+/// if there's no such (live) thread.
+fn kill(id: ThreadId) -> bool {
+    without_interrupts(|| {
+        let mut sched = SCHEDULER.lock();
+        let state = match sched.threads.get_mut(&id) {
+            Some(thread) => {
+                thread.killed = true;
+                thread.state
+            }
+            None => return false,
+        };
+
+        match state {
+            State::Dead => return false,
+            State::Blocked | State::Sleeping => {
+                sched.stop_waiting(id);
+                sched.make_ready(id);
+            }
+            // so that it doesn't block on its way out
+            State::Ready | State::Running => sched.thread(id).wakeup_pending = true,
+        }
+
+        true
+    })
+}
@@ -0,0 +1,137 @@
+//! A sleeping mutual-exclusion lock for threads, with priority inheritance.
+//!
+//! Unlike a spinlock, a thread which finds a [Mutex] taken blocks until it's released, lending the
+//! owner its priority in the meantime (see [block_on](../fn.block_on.html)). Releasing the lock
+//! wakes every waiter to contend for it again; the most important gets to try first.
+//!
+//! Only threads can use these: not interrupt handlers or deferred work, nor (if the lock might be
+//! contended) idle threads.
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use spin::Mutex as SpinMutex;
+
+use super::ThreadId;
+use arch::x86_64::interrupts::without_interrupts;
+
+struct LockState {
+    owner: Option<ThreadId>,
+    /// Threads blocked waiting for the lock.
+    waiters: Vec<ThreadId>,
+}
+
+/// A mutual-exclusion lock which blocks the threads waiting for it.
+pub struct Mutex<T> {
+    // only ever locked with interrupts off, so its holder can't be preempted
+    state: SpinMutex<LockState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// A new, unlocked mutex holding `data`.
+    pub fn new(data: T) -> Mutex<T> {
+        Mutex {
+            state: SpinMutex::new(LockState {
+                owner: None,
+                waiters: Vec::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Takes the lock for thread `me`, or returns its current owner.
+    fn acquire(&self, me: ThreadId) -> Result<(), ThreadId> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            match state.owner {
+                None => {
+                    state.owner = Some(me);
+                    state.waiters.retain(|&waiter| waiter != me);
+                    Ok(())
+                }
+                Some(owner) => {
+                    assert!(owner != me, "task: Mutex locked recursively");
+                    if !state.waiters.contains(&me) {
+                        state.waiters.push(me);
+                    }
+                    Err(owner)
+                }
+            }
+        })
+    }
+
+    /// Takes the lock, blocking until it's free.
+    ///
+    /// # Panics
+    /// If the current thread already holds it, or if it's held and the current flow of control
+    /// [can't block](../fn.can_block.html).
+    pub fn lock(&self) -> MutexGuard<T> {
+        let me = super::current().expect("task: Mutex used before task::init()");
+
+        while let Err(owner) = self.acquire(me) {
+            super::block_on(owner);
+        }
+
+        MutexGuard { mutex: self }
+    }
+
+    /// Takes the lock if it's free.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let me = super::current().expect("task: Mutex used before task::init()");
+
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.owner.is_some() {
+                return None;
+            }
+
+            state.owner = Some(me);
+            Some(MutexGuard { mutex: self })
+        })
+    }
+
+    fn unlock(&self) {
+        let waiters = without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.owner = None;
+            mem::replace(&mut state.waiters, Vec::new())
+        });
+
+        for waiter in waiters {
+            super::wake(waiter);
+        }
+        // we've lost whatever priority the waiters lent us, and may have woken something more
+        // important
+        super::preempt();
+    }
+}
+
+/// Proof of holding a [Mutex]'s lock; releases it when dropped.
+pub struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
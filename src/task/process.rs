@@ -0,0 +1,350 @@
+//! User processes.
+//!
+//! A process is an [AddressSpace], a capability space (see the `cap` module), and the threads
+//! running in it. Each of its threads has a user stack in the process's space, and a kernel stack
+//! of its own, which it enters the kernel on from ring 3. A process lasts until its last thread
+//! exits, or until it's [kill]ed; which is also what happens when one of its threads faults.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Arguments};
+use spin::Mutex as SpinMutex;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+use super::{Priority, ThreadId};
+use arch::x86_64::interrupts::without_interrupts;
+use arch::x86_64::memory::paging::table::EntryFlags;
+use arch::x86_64::memory::paging::{Frame, VirtualAddress};
+use arch::x86_64::memory::{AddressSpace, USER_END};
+use arch::x86_64::usermode;
+use cap;
+
+/// How big each user thread's stack is, in pages.
+pub const USER_STACK_PAGES: usize = 16;
+
+/// How big each user thread's stack is, in bytes.
+pub const USER_STACK_SIZE: usize = USER_STACK_PAGES * Frame::SIZE;
+
+/// How many threads a process can have at once.
+pub const MAX_THREADS: usize = 64;
+
+/// The top of a process's first thread's stack. Each thread's stack goes below the last one,
+/// behind an unmapped guard page.
+const USER_STACK_TOP: VirtualAddress = USER_END - Frame::SIZE;
+
+/// Identifies a process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    /// The id, as a plain number.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// The id whose [as_u64](#method.as_u64) is `id`.
+    pub fn from_u64(id: u64) -> ProcessId {
+        ProcessId(id)
+    }
+}
+
+impl fmt::Display for ProcessId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+struct Process {
+    name: &'static str,
+    address_space: Arc<AddressSpace>,
+    /// The process's threads, and the tops of their user stacks.
+    threads: BTreeMap<ThreadId, VirtualAddress>,
+    /// The tops of stacks whose threads have exited, which are still mapped, for reuse.
+    free_stacks: Vec<VirtualAddress>,
+    /// Where the next new stack goes.
+    next_stack_top: VirtualAddress,
+}
+
+struct Processes {
+    processes: BTreeMap<ProcessId, Process>,
+    next_id: u64,
+}
+
+lazy_static! {
+    // only ever locked with interrupts off, as threads are reaped with them off; taken before the
+    // scheduler's and capability table's locks, if both are needed
+    static ref PROCESSES: SpinMutex<Processes> = SpinMutex::new(Processes {
+        processes: BTreeMap::new(),
+        next_id: 1,
+    });
+}
+
+/// Where a user thread starts.
+struct UserStart {
+    rip: VirtualAddress,
+    rsp: VirtualAddress,
+    arg: usize,
+}
+
+/// Where user threads start, on their kernel stacks: drops to user mode.
+fn user_entry(start: usize) {
+    let start = unsafe { Box::from_raw(start as *mut UserStart) };
+    let (rip, rsp, arg) = (start.rip, start.rsp, start.arg);
+    drop(start);
+
+    // the process may have been killed before the thread first ran
+    super::exit_if_killed();
+    unsafe { usermode::enter(rip, rsp, arg) };
+}
+
+/// Creates a process called `name`, with an empty address space and no threads. It can be set up
+/// through its [address_space], then started with [spawn_thread].
+///
+/// # Panics
+/// If there's no memory left for the address space.
+pub fn create(name: &'static str) -> ProcessId {
+    let address_space = Arc::new(AddressSpace::new());
+
+    let pid = without_interrupts(|| {
+        let mut processes = PROCESSES.lock();
+        let pid = ProcessId(processes.next_id);
+        processes.next_id += 1;
+        cap::create_space(pid);
+        processes.processes.insert(
+            pid,
+            Process {
+                name,
+                address_space,
+                threads: BTreeMap::new(),
+                free_stacks: Vec::new(),
+                next_stack_top: USER_STACK_TOP,
+            },
+        );
+
+        pid
+    });
+
+    debug!("task: created process {} ({})", pid, name);
+    pid
+}
+
+/// Process `pid`'s address space, if there's such a process.
+pub fn address_space(pid: ProcessId) -> Option<Arc<AddressSpace>> {
+    without_interrupts(|| {
+        PROCESSES
+            .lock()
+            .processes
+            .get(&pid)
+            .map(|p| p.address_space.clone())
+    })
+}
+
+/// Starts a thread called `name` in process `pid`, at `priority`, which runs user code from
+/// `entry` with `arg` as its first argument, on a fresh stack of [USER_STACK_PAGES] pages.
+///
+/// Returns `None` if there's no such process, it has [MAX_THREADS] threads already, or there's no
+/// room for the thread's stacks.
+pub fn spawn_thread(
+    pid: ProcessId,
+    name: &'static str,
+    priority: Priority,
+    entry: VirtualAddress,
+    arg: usize,
+) -> Option<ThreadId> {
+    let stack_top = alloc_user_stack(pid, name)?;
+    let id = start_thread(pid, name, priority, entry, stack_top, stack_top, arg);
+    if id.is_none() {
+        free_user_stack(pid, stack_top);
+    }
+
+    id
+}
+
+/// Finds a [USER_STACK_PAGES]-page stack, for a thread called `name`, in process `pid`: one an
+/// exited thread left, or else a fresh one. Returns its top, or `None` if there's no such process,
+/// no room for the stack, or the process couldn't start a thread on it anyway (having
+/// [MAX_THREADS] already).
+pub(super) fn alloc_user_stack(pid: ProcessId, name: &str) -> Option<VirtualAddress> {
+    let (space, stack_top) = without_interrupts(|| {
+        let mut processes = PROCESSES.lock();
+        let process = processes.processes.get_mut(&pid)?;
+        if process.threads.len() >= MAX_THREADS {
+            return None;
+        }
+        if let Some(top) = process.free_stacks.pop() {
+            return Some((None, top));
+        }
+
+        let top = process.next_stack_top;
+        // leave a guard page below
+        process.next_stack_top = top.checked_sub(USER_STACK_SIZE + Frame::SIZE)?;
+
+        Some((Some(process.address_space.clone()), top))
+    })?;
+
+    // a reused stack's still mapped
+    if let Some(space) = space {
+        let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        if let Err(err) = space.map(stack_top - USER_STACK_SIZE, USER_STACK_SIZE, flags) {
+            warn!(
+                "task: no user stack for {} in process {}: {:?}",
+                name, pid, err
+            );
+            return None;
+        }
+    }
+
+    Some(stack_top)
+}
+
+/// Gives back the user stack topped at `stack_top`, which [alloc_user_stack] returned but no
+/// thread was started on, to process `pid`.
+fn free_user_stack(pid: ProcessId, stack_top: VirtualAddress) {
+    without_interrupts(|| {
+        if let Some(process) = PROCESSES.lock().processes.get_mut(&pid) {
+            process.free_stacks.push(stack_top);
+        }
+    });
+}
+
+/// Starts a thread called `name` in process `pid`, at `priority`, which runs user code from `rip`
+/// with its stack pointer at `rsp` (on the stack from [alloc_user_stack] topped at `stack_top`),
+/// and `arg` as its first argument. Returns `None` if there's no such process, it has
+/// [MAX_THREADS] threads already, or there's no room for the thread's kernel stack.
+pub(super) fn start_thread(
+    pid: ProcessId,
+    name: &'static str,
+    priority: Priority,
+    rip: VirtualAddress,
+    stack_top: VirtualAddress,
+    rsp: VirtualAddress,
+    arg: usize,
+) -> Option<ThreadId> {
+    // hold the lock until the thread's on the list, so it can't exit (or the process be killed)
+    // before it is
+    let id = without_interrupts(|| {
+        let mut processes = PROCESSES.lock();
+        // the process may have been killed in the meantime
+        let process = processes.processes.get_mut(&pid)?;
+        if process.threads.len() >= MAX_THREADS {
+            return None;
+        }
+
+        let start = Box::into_raw(Box::new(UserStart { rip, rsp, arg }));
+        let id = match super::spawn_in(
+            Some((pid, process.address_space.clone())),
+            name,
+            priority,
+            user_entry,
+            start as usize,
+        ) {
+            Some(id) => id,
+            None => {
+                drop(unsafe { Box::from_raw(start) });
+                return None;
+            }
+        };
+        process.threads.insert(id, stack_top);
+
+        Some(id)
+    })?;
+
+    debug!("task: thread {} ({}) joined process {}", id, name, pid);
+    Some(id)
+}
+
+/// The process the current thread belongs to, if it's a user thread.
+pub fn current() -> Option<ProcessId> {
+    without_interrupts(|| {
+        let cpu = ::arch::x86_64::cpu_id();
+        let mut sched = super::SCHEDULER.lock();
+        let current = sched.current[cpu]?;
+        sched.thread(current).process
+    })
+}
+
+/// Ends process `pid` and all its threads. Each thread finishes what it's doing in the kernel (a
+/// wait for a message or a timeout is cut short) and ends on its way back to user mode; if the
+/// current thread's one of them, it carries on until then too. Returns `false` if there's no such
+/// process.
+pub fn kill(pid: ProcessId) -> bool {
+    let process = match without_interrupts(|| PROCESSES.lock().processes.remove(&pid)) {
+        Some(process) => process,
+        None => return false,
+    };
+
+    for &thread in process.threads.keys() {
+        super::kill(thread);
+    }
+    cap::destroy_space(pid);
+
+    info!("task: killed process {} ({})", pid, process.name);
+    true
+}
+
+/// Forgets process `pid`, which never got any threads (because it couldn't be loaded, say).
+pub(super) fn destroy(pid: ProcessId) {
+    let destroyed = without_interrupts(|| {
+        let mut processes = PROCESSES.lock();
+        let idle = processes
+            .processes
+            .get(&pid)
+            .map_or(false, |p| p.threads.is_empty());
+        idle && processes.processes.remove(&pid).is_some()
+    });
+
+    if destroyed {
+        cap::destroy_space(pid);
+    }
+}
+
+/// Handles an exception user code caused: kills the current thread's process, and ends the
+/// thread. `what` describes the exception.
+///
+/// Called from the exception's handler, which is abandoned along with whatever stack it's on.
+///
+/// # Panics
+/// If the current thread isn't a user thread.
+pub fn fault(what: Arguments, stack_frame: &ExceptionStackFrame) -> ! {
+    fault_at(what, stack_frame.instruction_pointer.as_u64())
+}
+
+/// Like [fault], for something user code did at `rip` that isn't an exception, but which it
+/// can't be allowed to carry on from.
+pub fn fault_at(what: Arguments, rip: u64) -> ! {
+    let pid = current().expect("task: user fault outside a process");
+    warn!("task: process {} faulted ({}) at {:#x}", pid, what, rip);
+
+    kill(pid);
+    super::exit();
+}
+
+/// Notes that thread `id` of process `pid` has exited, ending the process if it was the last.
+pub(super) fn thread_exited(pid: ProcessId, id: ThreadId) {
+    let ended = without_interrupts(|| {
+        let mut processes = PROCESSES.lock();
+        let last = match processes.processes.get_mut(&pid) {
+            Some(process) => {
+                // its stack's free once it's gone, and it's long gone from user mode
+                if let Some(stack_top) = process.threads.remove(&id) {
+                    process.free_stacks.push(stack_top);
+                }
+                process.threads.is_empty()
+            }
+            None => false,
+        };
+
+        if last {
+            processes.processes.remove(&pid)
+        } else {
+            None
+        }
+    });
+
+    if let Some(process) = ended {
+        cap::destroy_space(pid);
+        info!("task: process {} ({}) exited", pid, process.name);
+    }
+}
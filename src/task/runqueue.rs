@@ -0,0 +1,142 @@
+//! The run queue: a FIFO of ready threads for each priority level.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::thread::{Priority, ThreadId, PRIORITY_LEVELS};
+
+pub struct RunQueue {
+    queues: Vec<VecDeque<ThreadId>>,
+    /// Bit `n` is set when `queues[n]` isn't empty.
+    occupied: u32,
+}
+
+impl RunQueue {
+    pub fn new() -> RunQueue {
+        RunQueue {
+            queues: (0..PRIORITY_LEVELS).map(|_| VecDeque::new()).collect(),
+            occupied: 0,
+        }
+    }
+
+    /// Is every queue empty?
+    pub fn is_empty(&self) -> bool {
+        self.occupied == 0
+    }
+
+    /// The highest priority with a thread ready, if any is.
+    pub fn highest(&self) -> Option<Priority> {
+        if self.occupied == 0 {
+            None
+        } else {
+            Some(Priority(31 - self.occupied.leading_zeros() as u8))
+        }
+    }
+
+    /// Adds `id` to the back of `priority`'s queue.
+    pub fn push_back(&mut self, id: ThreadId, priority: Priority) {
+        self.queues[priority.level()].push_back(id);
+        self.occupied |= 1 << priority.level();
+    }
+
+    /// Takes `id` off `priority`'s queue. Returns `false` if it wasn't there.
+    pub fn remove(&mut self, id: ThreadId, priority: Priority) -> bool {
+        let queue = &mut self.queues[priority.level()];
+        let found = match queue.iter().position(|&queued| queued == id) {
+            Some(pos) => queue.remove(pos).is_some(),
+            None => false,
+        };
+        if queue.is_empty() {
+            self.occupied &= !(1 << priority.level());
+        }
+
+        found
+    }
+
+    /// Takes the thread at the front of the highest-priority queue, so long as that priority is
+    /// at least `min` (if given).
+    pub fn pop(&mut self, min: Option<Priority>) -> Option<ThreadId> {
+        let priority = self.highest()?;
+        if min.map_or(false, |min| priority < min) {
+            return None;
+        }
+
+        let queue = &mut self.queues[priority.level()];
+        let id = queue.pop_front();
+        if queue.is_empty() {
+            self.occupied &= !(1 << priority.level());
+        }
+
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thread(id: usize) -> ThreadId {
+        ThreadId::from_usize(id)
+    }
+
+    fn priority(level: u8) -> Priority {
+        Priority::new(level).unwrap()
+    }
+
+    #[test]
+    fn highest_priority_first() {
+        let mut queue = RunQueue::new();
+        assert!(queue.is_empty());
+        assert_eq!(queue.highest(), None);
+
+        queue.push_back(thread(1), Priority::DEFAULT);
+        queue.push_back(thread(2), Priority::MIN);
+        queue.push_back(thread(3), Priority::MAX);
+        queue.push_back(thread(4), priority(5));
+        assert_eq!(queue.highest(), Some(Priority::MAX));
+
+        let order: Vec<_> = (0..4).filter_map(|_| queue.pop(None)).collect();
+        assert_eq!(order, [thread(3), thread(1), thread(4), thread(2)]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(None), None);
+    }
+
+    #[test]
+    fn fifo_within_a_priority() {
+        let mut queue = RunQueue::new();
+        for id in 0..5 {
+            queue.push_back(thread(id), Priority::DEFAULT);
+        }
+
+        for id in 0..5 {
+            assert_eq!(queue.pop(None), Some(thread(id)));
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn minimum_priority() {
+        let mut queue = RunQueue::new();
+        queue.push_back(thread(1), priority(10));
+
+        assert_eq!(queue.pop(Some(priority(11))), None);
+        assert_eq!(queue.highest(), Some(priority(10)));
+        assert_eq!(queue.pop(Some(priority(10))), Some(thread(1)));
+    }
+
+    #[test]
+    fn remove_clears_the_bitmap() {
+        let mut queue = RunQueue::new();
+        queue.push_back(thread(1), Priority::MAX);
+        queue.push_back(thread(2), Priority::MAX);
+        queue.push_back(thread(3), Priority::MIN);
+
+        assert!(!queue.remove(thread(3), Priority::MAX));
+        assert!(queue.remove(thread(1), Priority::MAX));
+        assert_eq!(queue.highest(), Some(Priority::MAX));
+        assert!(queue.remove(thread(2), Priority::MAX));
+        assert_eq!(queue.highest(), Some(Priority::MIN));
+        assert!(queue.remove(thread(3), Priority::MIN));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+//! Thread control blocks.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::process::ProcessId;
+use arch::x86_64::context::Context;
+use arch::x86_64::memory::{AddressSpace, Stack};
+
+/// Identifies a thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(pub(super) u64);
+
+impl ThreadId {
+    /// The id, as a plain number (for passing through a callback's `usize` argument, say).
+    pub fn as_usize(self) -> usize {
+        self.0 as usize
+    }
+
+    /// The id whose [as_usize](#method.as_usize) is `id`.
+    pub fn from_usize(id: usize) -> ThreadId {
+        ThreadId(id as u64)
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How many priority levels there are. At most 32, so that the run queue can keep a bitmap of
+/// which levels have threads ready.
+pub const PRIORITY_LEVELS: usize = 32;
+
+/// A thread's scheduling priority. Higher priorities run first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Priority(pub(super) u8);
+
+impl Priority {
+    /// The lowest priority, for background work.
+    pub const MIN: Priority = Priority(0);
+    /// The priority threads start at.
+    pub const DEFAULT: Priority = Priority(16);
+    /// The highest priority, for (*e.g.*) driver threads which must preempt everything else.
+    pub const MAX: Priority = Priority(PRIORITY_LEVELS as u8 - 1);
+
+    /// Priority level `level`, if there's such a level.
+    pub fn new(level: u8) -> Option<Priority> {
+        if (level as usize) < PRIORITY_LEVELS {
+            Some(Priority(level))
+        } else {
+            None
+        }
+    }
+
+    /// The priority's level, from 0 ([MIN](#associatedconstant.MIN)) up.
+    pub fn level(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What a thread's up to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// On the run queue, waiting for a CPU.
+    Ready,
+    /// Running on a CPU.
+    Running,
+    /// Waiting to be [woken](../fn.wake.html).
+    Blocked,
+    /// Waiting for a timer to [wake](../fn.wake.html) it.
+    Sleeping,
+    /// Finished; waiting to be cleaned up.
+    Dead,
+}
+
+/// A thread control block.
+pub struct Thread {
+    pub(super) id: ThreadId,
+    pub(super) name: &'static str,
+    pub(super) state: State,
+    /// The priority the thread was given.
+    pub(super) base_priority: Priority,
+    /// The priority the thread's scheduled at: its base priority, or that of the most important
+    /// thread waiting on it, whichever's higher.
+    pub(super) priority: Priority,
+    /// The thread this one's blocked waiting on (see [block_on]), if any.
+    ///
+    /// [block_on]: ../fn.block_on.html
+    pub(super) blocked_on: Option<ThreadId>,
+    /// The threads blocked waiting on this one.
+    pub(super) waiters: Vec<ThreadId>,
+    /// The thread's saved registers, while it's not running.
+    pub(super) context: Context,
+    /// The thread's kernel stack; `None` for threads adopted from the boot flow of control, whose
+    /// stacks were set up before there was a stack allocator.
+    pub(super) stack: Option<Stack>,
+    /// Was the thread woken while it wasn't blocked or sleeping? If so, its next [block] returns
+    /// straight away, so that a wakeup racing with the decision to block isn't lost.
+    ///
+    /// [block]: ../fn.block.html
+    pub(super) wakeup_pending: bool,
+    /// The process the thread belongs to; `None` for kernel threads.
+    pub(super) process: Option<ProcessId>,
+    /// The process's address space, switched to whenever the thread is. Kernel threads run in
+    /// whichever space was active before them.
+    pub(super) address_space: Option<Arc<AddressSpace>>,
+    /// Has the thread's process been killed? If so, it ends the next time it's on its way back to
+    /// user mode.
+    pub(super) killed: bool,
+}
+
+impl Thread {
+    pub(super) fn new(
+        id: ThreadId,
+        name: &'static str,
+        priority: Priority,
+        context: Context,
+        stack: Option<Stack>,
+    ) -> Thread {
+        Thread {
+            id,
+            name,
+            state: State::Ready,
+            base_priority: priority,
+            priority,
+            blocked_on: None,
+            waiters: Vec::new(),
+            context,
+            stack,
+            wakeup_pending: false,
+            process: None,
+            address_space: None,
+            killed: false,
+        }
+    }
+
+    /// The thread's id.
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// The thread's name.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The thread's state.
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The priority the thread's scheduled at, including any it's inherited.
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// The process the thread belongs to, unless it's a kernel thread.
+    pub fn process(&self) -> Option<ProcessId> {
+        self.process
+    }
+}
+
+impl fmt::Debug for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Thread")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("state", &self.state)
+            .field("priority", &self.priority)
+            .field("process", &self.process)
+            .field("stack", &self.stack)
+            .finish()
+    }
+}
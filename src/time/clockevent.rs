@@ -0,0 +1,205 @@
+//! Clock-event devices: programmable timers which can interrupt us at some point in the future.
+//!
+//! Every CPU has a *tick device*, picked from the devices registered on it (the one with the
+//! highest [rating](trait.ClockEventDevice.html#tymethod.rating) wins), which drives that CPU's
+//! periodic tick.
+
+use alloc::vec::Vec;
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::RwLock;
+
+use super::{clocksource, timer, Instant};
+use arch::x86_64::device::pit;
+use arch::x86_64::{self, MAX_CPUS};
+
+bitflags! {
+    /// What a [ClockEventDevice] is capable of.
+    pub struct Features: u32 {
+        /// The device can interrupt periodically.
+        const PERIODIC  = 1 << 0;
+        /// The device can interrupt once, after a given delay.
+        const ONESHOT   = 1 << 1;
+        /// Each CPU has its own instance of the device (*e.g.* the local APIC timer).
+        const PER_CPU   = 1 << 2;
+    }
+}
+
+/// A hardware timer which can raise interrupts.
+///
+/// Implementors' interrupt handlers are expected to call [handle_tick] on every interrupt.
+pub trait ClockEventDevice: Sync {
+    /// A short name for the device, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// What the device can do.
+    fn features(&self) -> Features;
+
+    /// How good a tick source this device is; higher is better.
+    fn rating(&self) -> u32;
+
+    /// Programs the device to interrupt `freq` times a second, until told otherwise.
+    fn set_periodic(&self, freq: u32);
+
+    /// Programs the device to interrupt once, `delta_ns` nanoseconds from now.
+    ///
+    /// Devices may clamp `delta_ns` to whatever range they're able to represent.
+    fn set_oneshot(&self, delta_ns: u64);
+
+    /// Stops the device from raising any further interrupts.
+    fn shutdown(&self);
+}
+
+/// The tick device of each CPU, indexed by CPU id.
+static TICK_DEVICES: RwLock<[Option<&'static dyn ClockEventDevice>; MAX_CPUS]> =
+    RwLock::new([None; MAX_CPUS]);
+
+/// The CPU whose ticks advance the monotonic clock: the first one to get a tick device.
+static TIMEKEEPER: AtomicUsize = AtomicUsize::new(usize::max_value());
+
+/// How often tick devices should interrupt, in _Hz_.
+static TICK_RATE: AtomicU32 = AtomicU32::new(pit::TICK_FREQ);
+
+/// A function run on every tick, with the current CPU's id and its new tick count.
+///
+/// Hooks run in interrupt context, so should do little more than [defer](::deferred::defer) work.
+pub type TickHook = fn(cpu: usize, ticks: u64);
+
+/// The longest we'll stop the tick for, in ns.
+const MAX_IDLE_NS: u64 = 10_000_000_000;
+
+lazy_static! {
+    /// How many tick interrupts each CPU has handled.
+    static ref EVENT_COUNTS: [AtomicU64; MAX_CPUS] = Default::default();
+    /// Which CPUs have their tick stopped.
+    static ref TICK_STOPPED: [AtomicBool; MAX_CPUS] = Default::default();
+    static ref TICK_HOOKS: RwLock<Vec<TickHook>> = RwLock::new(Vec::new());
+}
+
+/// Offers `device` as a tick device for the current CPU.
+///
+/// The device is only used if it's rated higher than the CPU's current tick device, in which
+/// case the current device is shut down and the new one starts ticking at [tick_rate].
+pub fn register(device: &'static dyn ClockEventDevice) {
+    assert!(
+        device.features().contains(Features::PERIODIC),
+        "clockevent: tick devices must support periodic mode"
+    );
+
+    let cpu = x86_64::cpu_id();
+    let mut devices = TICK_DEVICES.write();
+
+    if let Some(current) = devices[cpu] {
+        if current.rating() >= device.rating() {
+            debug!(
+                "clockevent: cpu {}: keeping {} over {}",
+                cpu,
+                current.name(),
+                device.name()
+            );
+            return;
+        }
+
+        current.shutdown();
+    }
+
+    let rate = tick_rate();
+    device.set_periodic(rate);
+    devices[cpu] = Some(device);
+    TIMEKEEPER.compare_and_swap(usize::max_value(), cpu, Ordering::Relaxed);
+
+    info!(
+        "clockevent: cpu {}: tick device is {} ({} Hz)",
+        cpu,
+        device.name(),
+        rate
+    );
+}
+
+/// Returns the current CPU's tick device, if it has one.
+pub fn tick_device() -> Option<&'static dyn ClockEventDevice> {
+    TICK_DEVICES.read()[x86_64::cpu_id()]
+}
+
+/// The rate tick devices interrupt at, in _Hz_.
+pub fn tick_rate() -> u32 {
+    TICK_RATE.load(Ordering::Relaxed)
+}
+
+/// Changes the tick rate, reprogramming the current CPU's tick device.
+///
+/// Tick devices belonging to other CPUs pick the new rate up the next time they're programmed.
+pub fn set_tick_rate(freq: u32) {
+    assert!(freq > 0, "clockevent: tick rate must be nonzero");
+
+    TICK_RATE.store(freq, Ordering::Relaxed);
+    if let Some(device) = tick_device() {
+        device.set_periodic(freq);
+    }
+}
+
+/// How many ticks `cpu` has handled since boot.
+pub fn event_count(cpu: usize) -> u64 {
+    EVENT_COUNTS[cpu].load(Ordering::Relaxed)
+}
+
+/// Stops the current CPU's periodic tick ahead of idling, instead programming its tick device to
+/// interrupt once, when the next timer expires. Returns whether the tick was stopped; it isn't if
+/// the tick device can't do one-shot interrupts, or the next timer's within a tick anyway.
+///
+/// The monotonic clock can only keep time without ticks if there's a clock source, so there
+/// must be one.
+///
+/// Must be called with interrupts disabled, and followed by [restart_tick] once the CPU's busy.
+pub fn stop_tick() -> bool {
+    let device = match tick_device() {
+        Some(device) if device.features().contains(Features::ONESHOT) => device,
+        _ => return false,
+    };
+    let source = match clocksource::current() {
+        Some(source) => source,
+        None => return false,
+    };
+
+    // don't let the clock source wrap (twice) while the clock isn't being updated
+    let max_ns = cmp::min(source.cycles_to_ns(source.mask() / 2), MAX_IDLE_NS);
+    let delta_ns = timer::next_expiry().map_or(max_ns, |deadline| {
+        cmp::min(deadline.duration_since(Instant::now()).as_nanos() as u64, max_ns)
+    });
+    if delta_ns <= 1_000_000_000 / u64::from(tick_rate()) {
+        return false;
+    }
+
+    device.set_oneshot(delta_ns);
+    TICK_STOPPED[x86_64::cpu_id()].store(true, Ordering::Relaxed);
+    true
+}
+
+/// Restarts the current CPU's periodic tick, if [stop_tick] stopped it.
+///
+/// Must be called with interrupts disabled.
+pub fn restart_tick() {
+    if TICK_STOPPED[x86_64::cpu_id()].swap(false, Ordering::Relaxed) {
+        if let Some(device) = tick_device() {
+            device.set_periodic(tick_rate());
+        }
+    }
+}
+
+/// Adds `hook` to the functions run on every tick, on every CPU.
+pub fn add_tick_hook(hook: TickHook) {
+    ::x86_64::instructions::interrupts::without_interrupts(|| TICK_HOOKS.write().push(hook));
+}
+
+/// Handles a tick on the current CPU. Called from tick devices' interrupt handlers.
+pub fn handle_tick() {
+    let cpu = x86_64::cpu_id();
+    let ticks = EVENT_COUNTS[cpu].fetch_add(1, Ordering::Relaxed) + 1;
+    if cpu == TIMEKEEPER.load(Ordering::Relaxed) {
+        super::tick();
+    }
+
+    for hook in TICK_HOOKS.read().iter() {
+        hook(cpu, ticks);
+    }
+}
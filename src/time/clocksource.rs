@@ -0,0 +1,78 @@
+//! Clock sources: free-running hardware counters we can read the time from.
+//!
+//! Of the registered sources, the one with the highest
+//! [rating](trait.ClockSource.html#tymethod.rating) is used.
+
+use spin::RwLock;
+
+/// A free-running counter, counting up at a fixed frequency.
+pub trait ClockSource: Sync {
+    /// A short name for the clock source, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// How good this clock source is; higher is better.
+    fn rating(&self) -> u32;
+
+    /// Reads the counter.
+    fn read(&self) -> u64;
+
+    /// Which bits of [read](#tymethod.read)'s value are significant. The counter wraps around
+    /// to zero after reaching this value.
+    fn mask(&self) -> u64 {
+        u64::max_value()
+    }
+
+    /// The rate the counter counts at, in _Hz_.
+    fn frequency(&self) -> u64;
+
+    /// Converts a difference between two counter values into nanoseconds.
+    fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        (u128::from(cycles) * 1_000_000_000 / u128::from(self.frequency())) as u64
+    }
+
+    /// Busy-waits for at least `us` microseconds.
+    fn delay_us(&self, us: u64) {
+        let start = self.read();
+        let cycles = (u128::from(us) * u128::from(self.frequency()) / 1_000_000) as u64;
+
+        while (self.read().wrapping_sub(start) & self.mask()) < cycles {}
+    }
+}
+
+static CURRENT: RwLock<Option<&'static dyn ClockSource>> = RwLock::new(None);
+
+/// Offers `source` as the system's clock source. It's used if it's rated higher than the current
+/// one.
+///
+/// The monotonic clock switches over to it on the next tick.
+pub fn register(source: &'static dyn ClockSource) {
+    // ticks read this, so mustn't interrupt us holding it
+    ::x86_64::instructions::interrupts::without_interrupts(|| replace_if_better(source));
+}
+
+fn replace_if_better(source: &'static dyn ClockSource) {
+    let mut current = CURRENT.write();
+
+    if let Some(existing) = *current {
+        if existing.rating() >= source.rating() {
+            debug!(
+                "clocksource: keeping {} over {}",
+                existing.name(),
+                source.name()
+            );
+            return;
+        }
+    }
+
+    *current = Some(source);
+    info!(
+        "clocksource: using {} ({} Hz)",
+        source.name(),
+        source.frequency()
+    );
+}
+
+/// The current clock source, if one has been registered.
+pub fn current() -> Option<&'static dyn ClockSource> {
+    *CURRENT.read()
+}
@@ -0,0 +1,218 @@
+//! Timekeeping.
+//!
+//! [Instant]s read the system's monotonic clock, which starts at zero at boot and only ever moves
+//! forwards. It's read from the current [clock source](clocksource), at whatever resolution that
+//! has; without one, it has the resolution of a tick. The [wall] clock is kept as an offset from
+//! it.
+//!
+//! One CPU, the *timekeeper* (whichever first gets a tick device), updates the clock on each of
+//! its ticks: it moves the clock's base up to the present (so clock sources never get the chance to
+//! wrap), and picks up any change of clock source.
+
+pub mod clockevent;
+pub mod clocksource;
+pub mod tickless;
+pub mod timer;
+pub mod wall;
+
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicU64, Ordering};
+pub use core::time::Duration;
+use spin::RwLock;
+
+use self::clocksource::ClockSource;
+pub use self::wall::{wall_time, DateTime};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// How many ticks the timekeeper has handled since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds of ticks that haven't yet been added to the clock (without a clock source).
+static PENDING_NS: AtomicU64 = AtomicU64::new(0);
+
+/// The monotonic clock: some point in time, and how far the clock source has counted since.
+struct Clock {
+    source: Option<&'static dyn ClockSource>,
+    /// The clock source's reading at `base_ns`.
+    base_cycles: u64,
+    /// Nanoseconds since boot.
+    base_ns: u64,
+}
+
+impl Clock {
+    fn now_ns(&self) -> u64 {
+        match self.source {
+            Some(source) => {
+                let cycles = source.read().wrapping_sub(self.base_cycles) & source.mask();
+                self.base_ns + source.cycles_to_ns(cycles)
+            }
+            None => self.base_ns,
+        }
+    }
+}
+
+// written only by the timekeeper's ticks
+static CLOCK: RwLock<Clock> = RwLock::new(Clock {
+    source: None,
+    base_cycles: 0,
+    base_ns: 0,
+});
+
+/// Brings the clock up to date. Called by [clockevent] on the timekeeper's ticks.
+fn tick() {
+    let tick_ns = NANOS_PER_SEC / u64::from(clockevent::tick_rate());
+
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    PENDING_NS.fetch_add(tick_ns, Ordering::Relaxed);
+
+    // this tick may have interrupted a reader on this CPU; if so, catch up next time
+    if let Some(mut clock) = CLOCK.try_write() {
+        let now = match clock.source {
+            Some(_) => clock.now_ns(),
+            None => clock.base_ns + PENDING_NS.load(Ordering::Relaxed),
+        };
+        PENDING_NS.store(0, Ordering::Relaxed);
+
+        let source = clocksource::current();
+        clock.source = source;
+        clock.base_cycles = source.map_or(0, |source| source.read());
+        clock.base_ns = now;
+    }
+}
+
+/// How many ticks have passed since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// How long it's been since boot.
+pub fn uptime() -> Duration {
+    Instant::now().since_boot()
+}
+
+/// Converts a number of ticks (at the current tick rate) into a [Duration].
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let rate = u64::from(clockevent::tick_rate());
+    Duration::new(ticks / rate, ((ticks % rate) * NANOS_PER_SEC / rate) as u32)
+}
+
+/// Converts a [Duration] into a number of ticks (at the current tick rate), rounding up.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let rate = u128::from(clockevent::tick_rate());
+    let ns = duration.as_nanos();
+
+    ((ns * rate + u128::from(NANOS_PER_SEC) - 1) / u128::from(NANOS_PER_SEC)) as u64
+}
+
+/// A point on the monotonic clock.
+///
+/// Like `std::time::Instant`, these are opaque and only useful compared to each other.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    ns: u64,
+}
+
+impl Instant {
+    /// The current time.
+    pub fn now() -> Instant {
+        Instant {
+            ns: CLOCK.read().now_ns(),
+        }
+    }
+
+    /// The time since boot at this instant.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.ns)
+    }
+
+    /// How long it's been since `earlier`, or zero if `earlier` is later than this.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.ns.saturating_sub(earlier.ns))
+    }
+
+    /// How long it's been since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// This instant plus `duration`, if that's representable.
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        duration_as_ns(duration)
+            .and_then(|ns| self.ns.checked_add(ns))
+            .map(|ns| Instant { ns })
+    }
+
+    /// This instant plus `duration`, or (if that's not representable) the last representable
+    /// instant, which never comes.
+    pub fn saturating_add(&self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap_or(Instant {
+            ns: u64::max_value(),
+        })
+    }
+
+    /// This instant minus `duration`, if that's not before boot.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        duration_as_ns(duration)
+            .and_then(|ns| self.ns.checked_sub(ns))
+            .map(|ns| Instant { ns })
+    }
+}
+
+fn duration_as_ns(duration: Duration) -> Option<u64> {
+    let ns = duration.as_nanos();
+    if ns > u128::from(u64::max_value()) {
+        None
+    } else {
+        Some(ns as u64)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("time: overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("time: overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, other: Instant) -> Duration {
+        self.duration_since(other)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Instant({}.{:09}s)",
+            self.ns / NANOS_PER_SEC,
+            self.ns % NANOS_PER_SEC
+        )
+    }
+}
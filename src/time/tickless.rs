@@ -0,0 +1,29 @@
+//! Tickless idle: an idle CPU doesn't need waking 20 times a second to find it has nothing to do.
+//!
+//! When a CPU goes [idle], its periodic tick is swapped for a one-shot interrupt at the next timer
+//! deadline (see [clockevent::stop_tick]); the tick comes back as soon as the CPU wakes up.
+
+use super::clockevent;
+use arch::x86_64;
+use deferred;
+use task;
+
+/// Halts the current CPU until an interrupt arrives, with its tick stopped where possible.
+///
+/// Returns straight away if there's deferred work to run, or a thread ready to. Must be called
+/// with interrupts enabled, and not from interrupt or deferred context.
+pub fn idle() {
+    ::x86_64::instructions::interrupts::disable();
+
+    if deferred::has_pending() || task::has_ready() {
+        ::x86_64::instructions::interrupts::enable();
+        return;
+    }
+
+    let stopped = clockevent::stop_tick();
+    unsafe { x86_64::enable_interrupts_and_halt() };
+
+    if stopped {
+        ::x86_64::instructions::interrupts::without_interrupts(clockevent::restart_tick);
+    }
+}
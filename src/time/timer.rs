@@ -0,0 +1,262 @@
+//! Kernel timers: calling a function at some point in the future, once or periodically.
+//!
+//! Pending timers are kept ordered by expiry time. Each tick checks whether the earliest has
+//! expired (without taking any locks), and if so, defers running the expired timers' callbacks;
+//! so callbacks run on whichever CPU noticed, with interrupts enabled, up to a tick late.
+//!
+//! Also here are [sleep_until]/[sleep_for], which block the calling thread, and [wait_until] for
+//! polling a condition with a timeout.
+
+use alloc::collections::BTreeMap;
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::{clockevent, Duration, Instant};
+use arch::x86_64::{self, interrupts::without_interrupts};
+use deferred;
+use task::{self, ThreadId};
+
+/// Identifies a pending timer, for [cancel]ling it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+/// A function called when a timer expires, with the argument it was set up with.
+pub type TimerCallback = fn(usize);
+
+/// Returned by [wait_until] if the deadline passed first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedOut;
+
+struct Timer {
+    callback: TimerCallback,
+    arg: usize,
+    /// How often the timer repeats, if it does.
+    period: Option<Duration>,
+}
+
+struct Timers {
+    /// Pending timers, by expiry time (in ns) then id.
+    queue: BTreeMap<(u64, TimerId), Timer>,
+    /// The expiry time (in ns) of each pending timer.
+    expiries: BTreeMap<TimerId, u64>,
+    next_id: u64,
+}
+
+impl Timers {
+    fn insert(&mut self, id: TimerId, expires: u64, timer: Timer) {
+        self.queue.insert((expires, id), timer);
+        self.expiries.insert(id, expires);
+        self.update_next_expiry();
+    }
+
+    fn remove(&mut self, id: TimerId) -> Option<Timer> {
+        let expires = self.expiries.remove(&id)?;
+        let timer = self.queue.remove(&(expires, id));
+        self.update_next_expiry();
+
+        timer
+    }
+
+    /// Removes the earliest timer, if it's expired by `now`.
+    fn pop_expired(&mut self, now: u64) -> Option<(TimerId, u64, Timer)> {
+        let (expires, id) = *self.queue.keys().next()?;
+        if expires > now {
+            return None;
+        }
+
+        self.remove(id).map(|timer| (id, expires, timer))
+    }
+
+    fn update_next_expiry(&self) {
+        let next = self
+            .queue
+            .keys()
+            .next()
+            .map_or(u64::max_value(), |&(expires, _)| expires);
+        NEXT_EXPIRY.store(next, Ordering::Relaxed);
+    }
+}
+
+lazy_static! {
+    // only ever locked with interrupts off, as expired timers run from interrupt exit
+    static ref TIMERS: Mutex<Timers> = Mutex::new(Timers {
+        queue: BTreeMap::new(),
+        expiries: BTreeMap::new(),
+        next_id: 0,
+    });
+}
+
+/// When the earliest pending timer expires, in ns (`u64::max_value()` if there isn't one).
+/// Lets ticks check for expired timers without locking.
+static NEXT_EXPIRY: AtomicU64 = AtomicU64::new(u64::max_value());
+/// Is a run of expired timers already queued?
+static RUN_PENDING: AtomicBool = AtomicBool::new(false);
+
+fn add(
+    expires: Instant,
+    period: Option<Duration>,
+    callback: TimerCallback,
+    arg: usize,
+) -> TimerId {
+    without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let id = TimerId(timers.next_id);
+        timers.next_id += 1;
+        timers.insert(
+            id,
+            expires.ns,
+            Timer {
+                callback,
+                arg,
+                period,
+            },
+        );
+
+        id
+    })
+}
+
+/// Calls `callback(arg)` once, at `deadline` (or as soon as possible, if that's passed).
+pub fn at(deadline: Instant, callback: TimerCallback, arg: usize) -> TimerId {
+    add(deadline, None, callback, arg)
+}
+
+/// Calls `callback(arg)` once, `delay` from now. A delay too long to represent never ends.
+pub fn oneshot(delay: Duration, callback: TimerCallback, arg: usize) -> TimerId {
+    add(Instant::now().saturating_add(delay), None, callback, arg)
+}
+
+/// Calls `callback(arg)` every `period`, starting `period` from now, until [cancel]led.
+///
+/// If a period is missed entirely (say, because interrupts were off), the timer skips it rather
+/// than firing repeatedly to catch up. A period too long to represent never ends.
+///
+/// # Panics
+/// If `period` is zero.
+pub fn periodic(period: Duration, callback: TimerCallback, arg: usize) -> TimerId {
+    assert!(period > Duration::from_secs(0), "timer: period must be nonzero");
+    let first = Instant::now().saturating_add(period);
+    add(first, Some(period), callback, arg)
+}
+
+/// Stops timer `id` from firing (again). Returns `false` if it had already fired (or been
+/// cancelled).
+///
+/// A periodic timer's callback may still be running on another CPU when this returns.
+pub fn cancel(id: TimerId) -> bool {
+    without_interrupts(|| TIMERS.lock().remove(id).is_some())
+}
+
+/// When the earliest pending timer expires, if there is one.
+pub fn next_expiry() -> Option<Instant> {
+    match NEXT_EXPIRY.load(Ordering::Relaxed) {
+        ns if ns == u64::max_value() => None,
+        ns => Some(Instant { ns }),
+    }
+}
+
+/// Runs the callbacks of every expired timer, rescheduling periodic ones.
+fn run_expired(_: usize) {
+    RUN_PENDING.store(false, Ordering::Relaxed);
+
+    loop {
+        let now = Instant::now().ns;
+        let expired = without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            let (id, expires, timer) = timers.pop_expired(now)?;
+            let (callback, arg) = (timer.callback, timer.arg);
+
+            if let Some(period) = timer.period {
+                // one that'd overflow never comes round again
+                let period = cmp::min(period.as_nanos(), u128::from(u64::max_value())) as u64;
+                let mut next = expires.saturating_add(period);
+                if next <= now {
+                    next = now.saturating_add(period);
+                }
+                timers.insert(id, next, timer);
+            }
+
+            Some((callback, arg))
+        });
+
+        match expired {
+            Some((callback, arg)) => callback(arg),
+            None => break,
+        }
+    }
+}
+
+/// Checks for expired timers on every tick.
+fn tick_hook(_cpu: usize, _ticks: u64) {
+    if NEXT_EXPIRY.load(Ordering::Relaxed) <= Instant::now().ns
+        && !RUN_PENDING.swap(true, Ordering::Relaxed)
+        && deferred::defer(run_expired, 0).is_err()
+    {
+        // try again next tick
+        RUN_PENDING.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Sets timers up. Must be called before any are added.
+pub fn init() {
+    assert_first_call!("timer::init() can only be called once!");
+
+    lazy_static::initialize(&TIMERS);
+    clockevent::add_tick_hook(tick_hook);
+}
+
+/// Waits until `condition()` returns `true`, or `deadline` passes (if given), letting other
+/// threads run (or, if there aren't any, halting) between checks.
+///
+/// `condition` is checked after every interrupt, so it should be cheap. Must be called with
+/// interrupts enabled, and not from interrupt or deferred context.
+pub fn wait_until<F>(mut condition: F, deadline: Option<Instant>) -> Result<(), TimedOut>
+where
+    F: FnMut() -> bool,
+{
+    loop {
+        if condition() {
+            return Ok(());
+        }
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            return Err(TimedOut);
+        }
+
+        deferred::run_pending();
+        if !task::yield_now() {
+            // nothing else to do; wait for something to happen
+            unsafe { x86_64::halt() };
+        }
+    }
+}
+
+fn wake_sleeper(thread: usize) {
+    task::wake(ThreadId::from_usize(thread));
+}
+
+/// Waits until `deadline` has passed, blocking the current thread (if it [can
+/// block](../../task/fn.can_block.html); otherwise, halting until then).
+///
+/// Must be called with interrupts enabled, and not from interrupt or deferred context.
+pub fn sleep_until(deadline: Instant) {
+    if !task::can_block() {
+        let _ = wait_until(|| false, Some(deadline));
+        return;
+    }
+
+    let thread = task::current().expect("timer: sleeping with no current thread");
+    let timer = at(deadline, wake_sleeper, thread.as_usize());
+    // a killed thread gives up early, so that it can die
+    while Instant::now() < deadline && !task::killed() {
+        task::sleep();
+    }
+    cancel(timer);
+}
+
+/// Waits for (at least) `duration`.
+///
+/// Must be called with interrupts enabled, and not from interrupt or deferred context.
+pub fn sleep_for(duration: Duration) {
+    sleep_until(Instant::now().saturating_add(duration));
+}
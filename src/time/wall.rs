@@ -0,0 +1,229 @@
+//! Wall-clock (calendar) time.
+//!
+//! Wall time is kept as an offset from the monotonic clock: whatever reads the real-time clock at
+//! boot tells us what the time was at some [Instant], and from then on the monotonic clock does
+//! the counting. So wall time never jumps backwards, but drifts as much as the tick does.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use super::{Duration, Instant, NANOS_PER_SEC};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// The wall time at boot (monotonic time zero), in nanoseconds since the Unix epoch.
+static BOOT_TIME_NS: AtomicU64 = AtomicU64::new(0);
+static BOOT_TIME_SET: AtomicBool = AtomicBool::new(false);
+
+/// A calendar date and time, in UTC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// How many days `month` (1 to 12) of `year` has.
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// Is this a real date and time, no earlier than the Unix epoch? Leap seconds don't count.
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Seconds since the Unix epoch (1970-01-01 00:00:00 UTC); `None` if this isn't
+    /// [valid](#method.is_valid).
+    pub fn to_unix(&self) -> Option<u64> {
+        if !self.is_valid() {
+            return None;
+        }
+
+        // after Howard Hinnant's `days_from_civil`, with years starting in March
+        let (year, month) = if self.month <= 2 {
+            (i64::from(self.year) - 1, i64::from(self.month) + 9)
+        } else {
+            (i64::from(self.year), i64::from(self.month) - 3)
+        };
+        // (years are never negative here, so this rounds the right way)
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        // (and valid dates are never before the epoch, nor far enough after it to overflow)
+        Some(
+            days as u64 * SECS_PER_DAY
+                + u64::from(self.hour) * 3600
+                + u64::from(self.minute) * 60
+                + u64::from(self.second),
+        )
+    }
+
+    /// The date and time `secs` seconds after the Unix epoch.
+    pub fn from_unix(secs: u64) -> DateTime {
+        // Hinnant's `civil_from_days`
+        let days = (secs / SECS_PER_DAY) as i64 + 719_468;
+        let secs_of_day = secs % SECS_PER_DAY;
+
+        let era = days / 146_097;
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Sets the wall clock: at `instant`, it was `time`. Returns `false` (leaving the clock alone) if
+/// `time` isn't [valid](struct.DateTime.html#method.is_valid), or is too far off to count in
+/// nanoseconds.
+pub fn set_wall_time(time: DateTime, instant: Instant) -> bool {
+    let unix_secs = time.to_unix();
+    let unix_ns = match unix_secs.and_then(|secs| secs.checked_mul(NANOS_PER_SEC)) {
+        Some(unix_ns) => unix_ns,
+        None => return false,
+    };
+
+    BOOT_TIME_NS.store(unix_ns.saturating_sub(instant.ns), Ordering::Relaxed);
+    BOOT_TIME_SET.store(true, Ordering::Release);
+    true
+}
+
+/// Has the wall clock been set?
+pub fn is_set() -> bool {
+    BOOT_TIME_SET.load(Ordering::Acquire)
+}
+
+/// The current wall time, as the time since the Unix epoch; or `None` if the wall clock hasn't
+/// been set.
+pub fn wall_time() -> Option<Duration> {
+    if !is_set() {
+        return None;
+    }
+
+    let boot = BOOT_TIME_NS.load(Ordering::Relaxed);
+    Some(Duration::from_nanos(boot.saturating_add(Instant::now().ns)))
+}
+
+/// The current wall time as a calendar date, if the wall clock has been set.
+pub fn now() -> Option<DateTime> {
+    wall_time().map(|time| DateTime::from_unix(time.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn known_dates() {
+        let known = [
+            (date(1970, 1, 1, 0, 0, 0), 0),
+            (date(1999, 12, 31, 23, 59, 59), 946_684_799),
+            (date(2000, 3, 1, 0, 0, 0), 951_868_800),
+            (date(2024, 2, 29, 12, 34, 56), 1_709_210_096),
+            (date(2038, 1, 19, 3, 14, 8), 2_147_483_648),
+        ];
+        for &(time, secs) in known.iter() {
+            assert_eq!(time.to_unix(), Some(secs), "{}", time);
+            assert_eq!(DateTime::from_unix(secs), time);
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        // a little over a day at a time, so the time of day moves too
+        let mut secs = 0;
+        while secs < 20_000_000_000 {
+            let time = DateTime::from_unix(secs);
+            assert!(time.is_valid(), "{}", time);
+            assert_eq!(time.to_unix(), Some(secs), "{}", time);
+            secs += SECS_PER_DAY + 3_607;
+        }
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let garbage = [
+            date(1969, 12, 31, 23, 59, 59),
+            date(2019, 0, 1, 0, 0, 0),
+            date(2019, 13, 1, 0, 0, 0),
+            date(2019, 1, 0, 0, 0, 0),
+            date(2019, 4, 31, 0, 0, 0),
+            date(2019, 2, 29, 0, 0, 0),
+            date(2000, 2, 30, 0, 0, 0),
+            date(2100, 2, 29, 0, 0, 0),
+            date(2019, 1, 1, 24, 0, 0),
+            date(2019, 1, 1, 0, 60, 0),
+            date(2019, 1, 1, 0, 0, 60),
+            // what an RTC with its BCD misread might say
+            date(2019, 0x12, 0x31, 0, 0, 0),
+        ];
+        for time in garbage.iter() {
+            assert!(!time.is_valid(), "{}", time);
+            assert_eq!(time.to_unix(), None, "{}", time);
+            assert!(!set_wall_time(*time, Instant { ns: 0 }));
+        }
+    }
+
+    #[test]
+    fn too_late_for_nanoseconds() {
+        // u64 nanoseconds run out in 2554
+        let time = date(2600, 1, 1, 0, 0, 0);
+        assert!(time.to_unix().is_some());
+        assert!(!set_wall_time(time, Instant { ns: 0 }));
+    }
+}